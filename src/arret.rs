@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use log::{error, info, warn};
+use millegrilles_common_rust::tokio;
+use millegrilles_common_rust::tokio::signal::unix::{signal, SignalKind};
+
+/// Intervalle de verification des taches en cours durant l'arret.
+const INTERVALLE_VERIFICATION_ARRET_MS: u64 = 100;
+
+/// Suivi des taches en cours pour un arret sans interrompre un traitement (ex. un message
+/// sauvegarde pour une partie des destinataires seulement).
+#[derive(Default)]
+pub struct EtatArret {
+    arret: AtomicBool,
+    compteur: AtomicU64,
+    taches: Mutex<HashMap<u64, String>>,
+}
+
+/// Garde d'une tache en cours, la tache est retiree lorsque la garde est dropped.
+pub struct GardeTache<'a> {
+    etat: &'a EtatArret,
+    id: u64,
+}
+
+impl Drop for GardeTache<'_> {
+    fn drop(&mut self) {
+        self.etat.taches.lock().expect("lock taches").remove(&self.id);
+    }
+}
+
+impl EtatArret {
+    /// Enregistre une tache. Retourne None si l'arret est en cours, la tache ne doit pas debuter.
+    pub fn debuter_tache<S>(&self, nom: S) -> Option<GardeTache>
+        where S: ToString
+    {
+        let mut taches = self.taches.lock().expect("lock taches");
+        // Verifier sous le lock pour ne pas ajouter de tache apres le debut du drain
+        if self.arret.load(Ordering::SeqCst) {
+            return None
+        }
+        let id = self.compteur.fetch_add(1, Ordering::SeqCst);
+        taches.insert(id, nom.to_string());
+        Some(GardeTache { etat: self, id })
+    }
+
+    /// Enregistre une tache qui doit completer meme durant l'arret (ex. traitement d'une
+    /// transaction deja sauvegardee). Le drain attend sa fin.
+    pub fn suivre_tache<S>(&self, nom: S) -> GardeTache
+        where S: ToString
+    {
+        let mut taches = self.taches.lock().expect("lock taches");
+        let id = self.compteur.fetch_add(1, Ordering::SeqCst);
+        taches.insert(id, nom.to_string());
+        GardeTache { etat: self, id }
+    }
+
+    /// Refuse les nouvelles taches. Les consommateurs (MQ, threads) cessent de debuter du travail.
+    pub fn arreter(&self) {
+        let _taches = self.taches.lock().expect("lock taches");
+        self.arret.store(true, Ordering::SeqCst);
+    }
+
+    pub fn est_en_arret(&self) -> bool {
+        self.arret.load(Ordering::SeqCst)
+    }
+
+    fn taches_en_cours(&self) -> Vec<String> {
+        self.taches.lock().expect("lock taches").values().cloned().collect()
+    }
+
+    /// Refuse les nouvelles taches et attend la fin des taches en cours jusqu'au delai.
+    /// Retourne les taches qui n'ont pas termine.
+    pub async fn drainer(&self, delai: tokio::time::Duration) -> Vec<String> {
+        self.arreter();
+
+        let fin = tokio::time::Instant::now() + delai;
+        loop {
+            let taches = self.taches_en_cours();
+            if taches.is_empty() {
+                info!("EtatArret.drainer Toutes les taches sont terminees");
+                return taches
+            }
+            if tokio::time::Instant::now() >= fin {
+                warn!("EtatArret.drainer Delai expire, {} taches en cours", taches.len());
+                return taches
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(INTERVALLE_VERIFICATION_ARRET_MS)).await;
+        }
+    }
+}
+
+/// Attend SIGTERM ou SIGINT. Retourne le nom du signal recu. Si les signaux ne peuvent pas
+/// etre enregistres, l'application arrete seulement lorsqu'une task termine.
+pub async fn attendre_signal_arret() -> &'static str {
+    let (mut sigterm, mut sigint) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+        (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("attendre_signal_arret Erreur enregistrement SIGTERM/SIGINT : {:?}", e);
+            return std::future::pending().await
+        }
+    };
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    }
+}
@@ -1,10 +1,11 @@
 use std::collections::{HashMap, HashSet};
 use std::str::from_utf8;
+use std::time::Instant;
 
-use log::{debug, error, warn};
-use millegrilles_common_rust::{constantes as CommonConstantes, serde_json};
+use log::{debug, error, info, warn};
+use millegrilles_common_rust::{chrono, constantes as CommonConstantes, serde_json};
 use millegrilles_common_rust::base64::{Engine as _, engine::general_purpose::STANDARD_NO_PAD as base64_nopad};
-use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson::{doc, Document};
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
 use millegrilles_common_rust::chiffrage_cle::CommandeAjouterCleDomaine;
 use millegrilles_common_rust::chrono::{DateTime, Utc};
@@ -22,19 +23,27 @@ use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::Mess
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::optionepochseconds;
 use millegrilles_common_rust::millegrilles_cryptographie::x25519::CleSecreteX25519;
 use millegrilles_common_rust::millegrilles_cryptographie::x509::EnveloppeCertificat;
-use millegrilles_common_rust::mongo_dao::MongoDao;
-use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument, UpdateOptions};
 use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
 use millegrilles_common_rust::recepteur_messages::{MessageValide, TypeMessage};
 use millegrilles_common_rust::serde_json::json;
 use millegrilles_common_rust::tokio_stream::StreamExt;
-use millegrilles_common_rust::tokio::time as tokio_time;
 use serde::{Deserialize, Serialize};
 
 use crate::constantes;
-use crate::constantes::{COLLECTION_FICHIERS_NOM, COLLECTION_RECEPTION_NOM, COLLECTION_USAGERS_NOM, DOMAINE_NOM};
+use crate::constantes::{COLLECTION_CONFIGURATION_NOM, COLLECTION_FICHIERS_NOM, COLLECTION_LIVRAISONS_NOM, COLLECTION_RECEPTION_NOM, COLLECTION_USAGERS_NOM, CONFIG_SYNC_FICHIERS, DOMAINE_NOM};
+use crate::courriel::{commande_configurer_transfert_courriel, commande_confirmer_transfert_courriel, planifier_transferts_courriel, TransfertCourriel};
+use crate::diffusions::commande_diffuser_message;
 use crate::domaine_messages::GestionnaireDomaineMessages;
-use crate::transactions::{TransactionMarquerLu, TransactionRecevoirMessage, TransactionSupprimerMessage};
+use crate::erreurs::{ErreurMessages, reponse_erreur};
+use crate::livraisons::{creer_livraisons, livrer, terminer_livraison, SourceLivraison};
+use crate::metriques::metriques;
+use crate::push::{commande_ajouter_abonnement_push, commande_retirer_abonnement_push};
+use crate::regeneration::commande_verifier_regeneration;
+use crate::structures_messages::filtre_bucket;
+use crate::transactions::{FiltreMessages, MetadataMessageV2, TransactionAssignerCleUsager, TransactionIndexerMessage, TransactionMarquerLu, TransactionRecevoirMessage, TransactionSupprimerMessage};
+use crate::validation::valider_message_post;
 
 pub async fn consommer_commande<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -43,20 +52,46 @@ pub async fn consommer_commande<M>(gestionnaire: &GestionnaireDomaineMessages, m
     debug!("consommer_commande : {:?}", &message.type_message);
     let (_user_id, _role_prive) = verifier_autorisation(&message)?;
 
-    let action = match &message.type_message {
-        TypeMessageOut::Commande(r) => r.action.clone(),
+    let (action, avec_reply_to) = match &message.type_message {
+        TypeMessageOut::Commande(r) => (r.action.clone(), r.reply_to.is_some()),
         _ => Err(Error::Str("grosfichiers.consommer_commande Mauvais type message, doit etre Commande"))?
     };
 
-    match action.as_str() {
+    // Les commandes en cours completent durant l'arret, les nouvelles sont refusees
+    let _garde = match gestionnaire.arret.debuter_tache(format!("commande {}", action)) {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::ArretEnCours, None)?))
+    };
+
+    let resultat = match action.as_str() {
         // Commandes standard
         constantes::COMMANDE_POSTER_V1 => commande_poster_v1(gestionnaire, middleware, message).await,
         constantes::COMMANDE_MARQUER_LU => commande_marquer_lu(gestionnaire, middleware, message).await,
         constantes::COMMANDE_SUPPRIMER_MESSAGE => commande_supprimer_message(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_MARQUER_LU_FILTRE => commande_marquer_lu_filtre(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_SUPPRIMER_MESSAGES_FILTRE => commande_supprimer_messages_filtre(gestionnaire, middleware, message).await,
         constantes::COMMANDE_RECLAMER_FUUIDS => commande_reclamer_fuuids(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_DIFFUSER_MESSAGE => commande_diffuser_message(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_INDEXER_MESSAGE => commande_indexer_message(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_VERIFIER_REGENERATION => commande_verifier_regeneration(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_AJOUTER_ABONNEMENT_PUSH => commande_ajouter_abonnement_push(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_RETIRER_ABONNEMENT_PUSH => commande_retirer_abonnement_push(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_CONFIGURER_TRANSFERT_COURRIEL => commande_configurer_transfert_courriel(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_CONFIRMER_TRANSFERT_COURRIEL => commande_confirmer_transfert_courriel(gestionnaire, middleware, message).await,
         // Commande inconnue
         _ => Err(Error::String(format!("consommer_commande: Commande {} inconnue, **DROPPED**\n{}",
                                        action, from_utf8(message.message.buffer.as_slice())?)))?,
+    };
+
+    metriques().action("commande", action.as_str(), resultat.is_err());
+    match resultat {
+        Ok(inner) => Ok(inner),
+        Err(e) if avec_reply_to => {
+            // Repondre a l'emetteur plutot que de laisser la requete expirer
+            error!("consommer_commande Erreur traitement commande {} : {:?}", action, e);
+            Ok(Some(reponse_erreur(middleware, ErreurMessages::ErreurInterne, None)?))
+        },
+        Err(e) => Err(e)
     }
 }
 
@@ -93,24 +128,134 @@ fn verifier_autorisation(message: &MessageValide) -> Result<(Option<String>, boo
 #[derive(Serialize)]
 struct ReponseCommandePoster {
     ok: bool,
-    code: Option<usize>,
-    err: Option<String>,
+    /// Destinataires sans profil local, le message est livre aux autres destinataires.
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    destinataires_inconnus: Vec<String>,
+    /// Destinataires dont la livraison a ete reportee, elle sera reessayee en arriere-plan.
+    #[serde(skip_serializing_if="Option::is_none")]
+    livraisons_en_attente: Option<usize>,
 }
 
 async fn commande_poster_v1<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
 {
-    let message_ref = message.message.parse()?;
+    metriques().posts_recus.inc();
+
+    // Dechiffrer le contenu du message
+    let mut resultat = match dechiffrer_message_post(gestionnaire, middleware, &message.message).await? {
+        Some(inner) => inner,
+        None => {
+            return Ok(Some(reponse_erreur(middleware, ErreurMessages::TimeoutServeur, Some("Timeout serveur"))?))
+        }
+    };
+
+    // Verifier les limites et nettoyer le contenu avant le rechiffrage pour les destinataires
+    if let Err(e) = valider_message_post(&gestionnaire.config.limites, &mut resultat) {
+        debug!("commande_poster_v1 Message refuse : {}", e);
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::ContenuRefuse, Some(e.as_str()))?))
+    }
+
+    debug!("commande_poster_v1 Message dechiffre recu :\n{:?}", resultat);
+
+    let expediteur = message.certificat.get_user_id()?;
+    let expediteur = expediteur.as_ref().map(|e| e.as_str());
+
+    let resultat_poster = match poster_message(gestionnaire, middleware, SourceLivraison::Message(&message.message), &resultat, expediteur).await {
+        Ok(inner) => inner,
+        Err(e) => {
+            error!("commande_poster_v1 Erreur poster_message : {:?}", e);
+            return Ok(Some(reponse_erreur(middleware, ErreurMessages::ErreurInterne, Some("Erreur traitement destinataires"))?))
+        }
+    };
+
+    if resultat_poster.nombre_destinataires == 0 {
+        debug!("commande_poster_v1 Message recu n'a aucun destinataire correspondant");
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::DestinatairesInconnus, Some("Destinataires inconnus"))?))
+    }
+
+    let livraisons_en_attente = match resultat_poster.livraisons_en_attente {
+        0 => None,
+        n => Some(n)
+    };
+    let reponse = ReponseCommandePoster {
+        ok: true,
+        destinataires_inconnus: resultat_poster.destinataires_inconnus,
+        livraisons_en_attente,
+    };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+pub struct ResultatPosterMessage {
+    pub nombre_destinataires: usize,
+    pub destinataires_inconnus: Vec<String>,
+    /// Livraisons reportees, reessayees par thread_livraisons.
+    pub livraisons_en_attente: usize,
+}
+
+/// Livre un message dechiffre et valide a ses destinataires. Chemin commun de posterV1 et de la
+/// reception de courriels, la source permet a thread_livraisons de reprendre les livraisons.
+pub async fn poster_message<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, source: SourceLivraison<'_>,
+                               message: &MessagePostV1, expediteur: Option<&str>)
+    -> Result<ResultatPosterMessage, Error>
+    where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
+{
+    // Recuperer profil de l'usager. Generer au besoin.
+    let (profils, mut cles_chiffrage, destinataires_inconnus) = get_profils_usagers(gestionnaire, middleware, &message.destinataires).await?;
+
+    let nombre_destinataires = profils.len();
+    metriques().destinataires.with_label_values(&["resolu"]).inc_by(nombre_destinataires as u64);
+    metriques().destinataires.with_label_values(&["inconnu"]).inc_by(destinataires_inconnus.len() as u64);
+    if nombre_destinataires == 0 {
+        return Ok(ResultatPosterMessage { nombre_destinataires, destinataires_inconnus, livraisons_en_attente: 0 })
+    }
+
+    // Conserver une livraison par destinataire avant de sauvegarder les messages. Une livraison
+    // en erreur est reessayee par thread_livraisons plutot que d'interrompre les autres.
+    let user_ids: Vec<&str> = profils.iter().map(|p| p.user_id.as_str()).collect();
+    let post_id = creer_livraisons(middleware, source, expediteur, &user_ids).await?;
+
+    // Transferts par courriel envoyes par thread_courriels_sortants
+    planifier_transferts_courriel(gestionnaire, middleware, post_id.as_str(), source, &profils).await?;
+
+    for profil in &profils {
+        let cle = profil.cle_id.as_ref()
+            .and_then(|cle_id| cles_chiffrage.remove(cle_id).map(|cle| (cle_id, cle)));
+        match cle {
+            Some((cle_id, cle_secrete)) => {
+                livrer(gestionnaire, middleware, post_id.as_str(), profil.user_id.as_str(), cle_id.as_str(),
+                       cle_secrete, message, expediteur).await?;
+            },
+            None => {
+                let err = Error::Str("poster_message Cle de chiffrage du profil non disponible");
+                terminer_livraison(gestionnaire, middleware, post_id.as_str(), profil.user_id.as_str(), Err(err)).await?;
+            }
+        }
+    }
+
+    // Les livraisons completees sont retirees, compter celles qui restent
+    let collection_livraisons = middleware.get_collection(COLLECTION_LIVRAISONS_NOM)?;
+    let livraisons_en_attente = collection_livraisons.count_documents(doc! {"post_id": &post_id}, None).await? as usize;
+
+    Ok(ResultatPosterMessage { nombre_destinataires, destinataires_inconnus, livraisons_en_attente })
+}
+
+/// Dechiffre le contenu d'un message MessagePostV1. La cle est obtenue aupres du maitre des cles.
+/// Retourne None si le maitre des cles n'a pas repondu.
+pub async fn dechiffrer_message_post<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: &MessageMilleGrillesBufferDefault)
+    -> Result<Option<MessagePostV1>, Error>
+    where M: GenerateurMessages + CleChiffrageHandler
+{
+    let message_ref = message.parse()?;
 
     let dechiffrage = match message_ref.dechiffrage.as_ref() {
         Some(inner) => inner,
-        None => Err(Error::Str("commande_poster_v1 Message sans chiffrage - **REJETE**"))?
+        None => Err(Error::Str("dechiffrer_message_post Message sans chiffrage - **REJETE**"))?
     };
 
     let cles = match dechiffrage.cles.as_ref() {
         Some(inner) => inner,
-        None => Err(Error::Str("commande_poster_v1 Message sans cles chiffrees - **REJETE**"))?
+        None => Err(Error::Str("dechiffrer_message_post Message sans cles chiffrees - **REJETE**"))?
     };
 
     let enveloppe_signature = middleware.get_enveloppe_signature();
@@ -118,42 +263,42 @@ async fn commande_poster_v1<M>(gestionnaire: &GestionnaireDomaineMessages, middl
 
     let cle_dechiffrage = match cles.get(fingerprint.as_str()) {
         Some(_inner) => {
-            todo!("Dechiffrer cle");
+            // Appele par les threads de diffusion et de livraison, ne pas paniquer
+            Err(Error::Str("dechiffrer_message_post Dechiffrage avec la cle locale non supporte"))?
             //let mut cle_buffer = [0u8;32];
             // cle_buffer.copy_from_slice(&cle_secrete[0..32]);
             //CleSecreteX25519 {0: cle_buffer}
         },
         None => {
-            debug!("commande_poster_v1 Cle locale non presente dans dechiffrage, faire une requete de dechiffrage aupres du maitre des cles");
+            debug!("dechiffrer_message_post Cle locale non presente dans dechiffrage, faire une requete de dechiffrage aupres du maitre des cles");
             let routage = RoutageMessageAction::builder(
                 DOMAINE_NOM_MAITREDESCLES, MAITREDESCLES_REQUETE_DECHIFFRAGE_MESSAGE,
                 vec![Securite::L3Protege]
             )
-                .timeout_blocking(5000)
+                .timeout_blocking(gestionnaire.config.timeout_requete_longue_ms)
                 .build();
             let reponse_dechiffrage = match middleware.transmettre_requete(routage, dechiffrage).await {
                 Ok(inner) => {
                     match inner {
                         Some(inner) => match inner {
                             TypeMessage::Valide(inner) => inner,
-                            _ => Err(Error::Str("commande_poster_v1 Mauvais type de reponse sur requete dechiffrage cles"))?
+                            _ => Err(Error::Str("dechiffrer_message_post Mauvais type de reponse sur requete dechiffrage cles"))?
                         },
-                        None => Err(Error::Str("commande_poster_v1 Aucune reponse sur requete dechiffrage cles"))?
+                        None => Err(Error::Str("dechiffrer_message_post Aucune reponse sur requete dechiffrage cles"))?
                     }
                 },
                 Err(e) => {
-                    error!("commande_poster_v1 Erreur requete dechiffrage message : {:?}", e);
-                    let reponse = ReponseCommandePoster { ok: false, code: Some(3), err: Some("Timeout serveur".to_string()) };
-                    return Ok(Some(middleware.build_reponse(&reponse)?.0))
+                    error!("dechiffrer_message_post Erreur requete dechiffrage message : {:?}", e);
+                    return Ok(None)
                 }
             };
 
             let reponse_dechiffrage_ref = reponse_dechiffrage.message.parse()?;
             let contenu: ReponseDechiffrageMessage = reponse_dechiffrage_ref.dechiffrer(enveloppe_signature.as_ref())?;
-            debug!("commande_poster_v1 Reponse dechiffrage ref : {:?}", contenu);
+            debug!("dechiffrer_message_post Reponse dechiffrage ref : {:?}", contenu);
             let cle_secrete = match contenu.cle_secrete_base64 {
                 Some(inner) => base64_nopad.decode(inner)?,
-                None => Err(Error::Str("commande_poster_v1 Aucune cle secrete inclue"))?
+                None => Err(Error::Str("dechiffrer_message_post Aucune cle secrete inclue"))?
             };
             let mut cle_buffer = [0u8;32];
             cle_buffer.copy_from_slice(&cle_secrete[0..32]);
@@ -161,71 +306,36 @@ async fn commande_poster_v1<M>(gestionnaire: &GestionnaireDomaineMessages, middl
         }
     };
 
-    // Dechiffrer le contenu du message
-    let resultat: MessagePostV1 = message_ref.dechiffrer_avec_secret(cle_dechiffrage)?;
-
-    debug!("commande_poster_v1 Message dechiffre recu :\n{:?}", resultat);
-
-    // Recuperer profil de l'usager. Generer au besoin.
-    let (profils, mut cles_chiffrage, destinataire_manquants) = match get_profils_usagers(middleware, &resultat.destinataires).await {
-        Ok(inner) => inner,
-        Err(e) => {
-            error!("commande_poster_v1 Erreur get_profils_usagers : {:?}", e);
-            let reponse = ReponseCommandePoster { ok: true, code: Some(500), err: Some("Erreur traitement destinataires".to_string()) };
-            return Ok(Some(middleware.build_reponse(&reponse)?.0))
-        }
-    };
-
-    let nombre_profils = profils.len();
-    if nombre_profils == 0 {
-        debug!("Message recu n'a aucun destinataire correspondant");
-        let reponse = ReponseCommandePoster { ok: true, code: Some(1), err: Some("Destinataires inconnus".to_string()) };
-        return Ok(Some(middleware.build_reponse(&reponse)?.0))
-    }
-
-    // Generer la transaction pour chaque profil usager
-    for profil in profils {
-        let cle_id = match profil.cle_id {
-            Some(inner) => inner,
-            None => {
-                error!("commande_poster_v1 Cle_id de chiffrage manquante pour profil {}, skip destinataire", profil.user_id);
-                continue
-            }
-        };
-        let cle_secrete = match cles_chiffrage.remove(&cle_id) {
-            Some(inner) => inner,
-            None => {
-                error!("commande_poster_v1 Cle de chiffrage manquante pour profil {}, skip destinataire", profil.user_id);
-                continue
-            }
-        };
-        sauvegarder_message(gestionnaire, middleware, profil.user_id.as_str(), cle_id, cle_secrete, &resultat).await?;
-    }
-
-    let (code, err) = if destinataire_manquants.len() > 0 {
-        (200, Some(format!("Message traite pour {} destinataires. {} destinataires sont inconnus",
-                           nombre_profils, destinataire_manquants.len())))
-    } else {
-        (201, None)
-    };
-
-    let reponse = ReponseCommandePoster { ok: true, code: Some(code), err };
-    Ok(Some(middleware.build_reponse(&reponse)?.0))
+    Ok(Some(message_ref.dechiffrer_avec_secret(cle_dechiffrage)?))
 }
 
-#[derive(Serialize)]
-struct EvenementNouveauMessage {
-    message_id: String,
-    user_id: String,
+/// Message_id deterministe d'une copie de message livree a un usager a partir d'une source
+/// (diffusion, post). Permet de reprendre une livraison sans creer de doublon.
+pub fn message_id_livraison(source_id: &str, user_id: &str) -> String {
+    format!("{}:{}", source_id, user_id)
 }
 
-async fn sauvegarder_message<M,S,K>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M,
-                                    user_id: S, cle_id: K, cle_secrete: CleSecreteX25519,
-                                    message: &MessagePostV1
+/// Chiffre et sauvegarde le message pour un usager. Lorsque message_id est fourni, la sauvegarde
+/// est ignoree si le message existe deja pour l'usager.
+pub async fn sauvegarder_message<M,S,K>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M,
+                                    message_id: Option<&str>, user_id: S, cle_id: K, cle_secrete: CleSecreteX25519,
+                                    message: &MessagePostV1, expediteur: Option<&str>
 )
     -> Result<(), Error>
     where M: GenerateurMessages + ValidateurX509 + MongoDao, S: ToString, K: ToString
 {
+    let user_id = user_id.to_string();
+
+    if let Some(message_id) = message_id {
+        let filtre = doc! {constantes::CHAMP_USER_ID: &user_id, constantes::CHAMP_MESSAGE_ID: message_id};
+        let collection = middleware.get_collection(COLLECTION_RECEPTION_NOM)?;
+        if collection.find_one(filtre, None).await?.is_some() {
+            debug!("sauvegarder_message Message {} deja sauvegarde, skip", message_id);
+            return Ok(())
+        }
+    }
+
+    let debut_chiffrage = Instant::now();
     let mut cipher = CipherMgs4::with_secret(CleSecreteCipher::CleSecrete(cle_secrete))?;
     let message_bytes = serde_json::to_string(&message)?;
     let taille_chiffrage = (message_bytes.len() as f64 * 1.05 + 17f64) as usize;
@@ -233,6 +343,7 @@ async fn sauvegarder_message<M,S,K>(gestionnaire: &GestionnaireDomaineMessages,
     buffer.resize(taille_chiffrage, 0u8);
     let taille_contenu = cipher.update(message_bytes.as_bytes(), buffer.as_mut_slice())?;
     let resultat_chiffrage = cipher.finalize(&mut buffer[taille_contenu..])?;
+    metriques().duree_chiffrage.observe(debut_chiffrage.elapsed().as_secs_f64());
 
     // Tronquer le buffer pour garder la taille exacte
     buffer.truncate(taille_contenu + resultat_chiffrage.len);
@@ -250,8 +361,6 @@ async fn sauvegarder_message<M,S,K>(gestionnaire: &GestionnaireDomaineMessages,
         header: None, ref_hachage_bytes: None, hachage_bytes: None,
     };
 
-    let user_id = user_id.to_string();
-
     let fichiers = match &message.fichiers {
         Some(inner) => {
             Some(inner.iter().map(|f| f.into()).collect())
@@ -259,32 +368,38 @@ async fn sauvegarder_message<M,S,K>(gestionnaire: &GestionnaireDomaineMessages,
         None => None
     };
 
-    let transaction_message = TransactionRecevoirMessage::new(&user_id, message_chiffre, fichiers);
-    let (_, message_id) = sauvegarder_traiter_transaction_serializable_v2(middleware, &transaction_message, gestionnaire,
-        DOMAINE_NOM, constantes::COMMANDE_POSTER_V1).await?;
+    let metadata = MetadataMessageV2 {
+        expediteur: expediteur.map(|e| e.to_string()),
+        conversation_id: message.conversation_id.clone(),
+        expiration: message.expiration.clone(),
+        taille: message_bytes.len() as i64,
+        nombre_fichiers: message.fichiers.as_ref().map(|f| f.len()).unwrap_or(0) as i64,
+    };
 
-    // Emettre evenement de nouveau message
-    let routage = RoutageMessageAction::builder(
-        DOMAINE_NOM, constantes::EVENEMENT_NOUVEAU_MESSAGE, vec![Securite::L2Prive])
-        .partition(&user_id)
-        .build();
-    let evenement = EvenementNouveauMessage {message_id, user_id};
-    middleware.emettre_evenement(routage, evenement).await?;
+    let transaction_message = TransactionRecevoirMessage::new(
+        message_id.map(|m| m.to_string()), &user_id, message_chiffre, fichiers, metadata);
+    // L'evenement nouveauMessage est conserve lors du traitement de la transaction
+    sauvegarder_traiter_transaction_serializable_v2(middleware, &transaction_message, gestionnaire,
+        DOMAINE_NOM, constantes::COMMANDE_POSTER_V1).await?;
 
     Ok(())
 }
 
 #[derive(Debug, Deserialize)]
-struct ProfilUsagerMessages {
+pub struct ProfilUsagerMessages {
     /// User_id interne defini par le maitre des comptes.
-    user_id: String,
+    pub user_id: String,
 
     /// Nom de l'usager. Reset regulierement pour reverifier aupres du maitre des comptes.
-    nom_usager: Option<String>,
+    pub nom_usager: Option<String>,
 
     /// Cle_id courant pour le chiffrage des messages de cet usager.
     /// Reset automatiquement regulierement pour generer une nouvelle cle.
-    cle_id: Option<String>,
+    pub cle_id: Option<String>,
+
+    /// Transfert des messages recus vers une adresse courriel externe.
+    #[serde(default)]
+    pub transfert_courriel: Option<TransfertCourriel>,
 
     // /// Date du dernier reset de nom_usager et cle_id
     //#[serde(default, skip_serializing_if="Option::is_none", with="opt_chrono_datetime_as_bson_datetime")]
@@ -301,18 +416,16 @@ struct ReponseUsersMaitredescomptes {
     usagers: HashMap<String, Option<String>>
 }
 
-async fn get_profils_usagers<M,S>(middleware: &M, noms_usagers: &Vec<S>)
+async fn get_profils_usagers<M,S>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, noms_usagers: &Vec<S>)
     -> Result<(Vec<ProfilUsagerMessages>, HashMap<String, CleSecreteX25519>, Vec<String>), Error>
     where
-        M: MongoDao + GenerateurMessages + CleChiffrageHandler,
+        M: MongoDao + GenerateurMessages + CleChiffrageHandler + ValidateurX509,
         S: AsRef<str>
 {
     let noms_usagers: Vec<&str> = noms_usagers.iter().map(|s| s.as_ref()).collect();
     let mut manquants: HashSet<&str> = HashSet::with_capacity(noms_usagers.len());
     manquants.extend(noms_usagers.iter());
 
-    let enveloppe_signature = middleware.get_enveloppe_signature();
-
     // Charger profils usagers connus
     let mut profils= find_usagers_messages(middleware, FiltreUsagerChamp::NomUsager(noms_usagers)).await?;
     let mut cles_chiffrage: HashMap<String, CleSecreteX25519> = HashMap::new();
@@ -321,36 +434,11 @@ async fn get_profils_usagers<M,S>(middleware: &M, noms_usagers: &Vec<S>)
         if let Some(nom_usager) = p.nom_usager.as_ref() {
             manquants.remove(nom_usager.as_str());
 
-            // Charger la cle de chiffrage si presente
+            // Charger la cle de chiffrage si presente. Sans cle, la livraison est reessayee.
             if let Some(cle_id) = &p.cle_id {
-                let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCLES, MAITREDESCLES_REQUETE_DECHIFFRAGE_V2, vec![Securite::L3Protege])
-                    .timeout_blocking(3000)
-                    .build();
-                let requete = RequeteDechiffrage {
-                    domaine: DOMAINE_NOM.to_string(),
-                    liste_hachage_bytes: None,
-                    cle_ids: Some(vec![cle_id.clone()]),
-                    certificat_rechiffrage: None,
-                };
-                if let Ok(Some(TypeMessage::Valide(reponse))) = middleware.transmettre_requete(routage, requete).await {
-                    let reponse_ref = reponse.message.parse()?;
-                    let reponse_dechiffree: ReponseRequeteDechiffrageV2 = reponse_ref.dechiffrer(enveloppe_signature.as_ref())?;
-                    if reponse_dechiffree.ok {
-                        match reponse_dechiffree.cles {
-                            Some(mut inner) => {
-                                if inner.len() != 1 {
-                                    Err(Error::Str("get_profils_usagers Mauvais nombre de cles dechiffrees recues"))?
-                                }
-                                let cle = inner.remove(0);
-                                cles_chiffrage.insert(cle_id.to_owned(), cle.cle_secrete()?)
-                            },
-                            None => Err(Error::Str("get_profils_usagers Aucunes cles dechiffrees recues"))?
-                        };
-                    } else {
-                        Err(Error::String(format!("get_profils_usagers Erreur requete cle dechiffrage : {:?}", reponse_dechiffree.err)))?
-                    }
-                } else {
-                    Err(Error::Str("get_profils_usagers Erreur requete cle dechiffrage"))?
+                match charger_cle_profil(gestionnaire, middleware, cle_id).await {
+                    Ok(cle) => { cles_chiffrage.insert(cle_id.to_owned(), cle); },
+                    Err(e) => error!("get_profils_usagers Erreur chargement cle profil {} : {:?}", p.user_id, e)
                 }
             }
         }
@@ -360,7 +448,7 @@ async fn get_profils_usagers<M,S>(middleware: &M, noms_usagers: &Vec<S>)
     if manquants.len() > 0 {
         debug!("get_profils_usagers {} profils manquants, requete vers maitre des comptes", manquants.len());
         let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCOMPTES, "getUserIdParNomUsager", vec![Securite::L3Protege])
-            .timeout_blocking(3000)
+            .timeout_blocking(gestionnaire.config.timeout_requete_ms)
             .build();
         let requete_contenu = RequeteUsersMaitredescomptes {noms_usagers: manquants.iter().map(|s| s.to_string()).collect()};
         let reponse = middleware.transmettre_requete(routage, requete_contenu).await.unwrap_or_else(|e| {
@@ -406,7 +494,120 @@ async fn get_profils_usagers<M,S>(middleware: &M, noms_usagers: &Vec<S>)
         }
     }
 
-    // Generer les cles pour profils
+    // Generer les cles pour profils. Les profils sans cle sont livres par thread_livraisons.
+    if let Err(e) = generer_cles_profils(gestionnaire, middleware, &mut profils, &mut cles_chiffrage).await {
+        error!("get_profils_usagers Erreur generation cles profils : {:?}", e);
+    }
+
+    let cles_manquantes = profils.iter()
+        .filter(|p| p.cle_id.as_ref().map(|c| !cles_chiffrage.contains_key(c)).unwrap_or(true))
+        .count();
+    if cles_manquantes > 0 {
+        warn!("get_profils_usagers Mismatch nombre de cles de chiffrage et profils : {} profils sans cle sur {}, livraison reportee",
+            cles_manquantes, profils.len());
+        metriques().destinataires.with_label_values(&["cle_manquante"]).inc_by(cles_manquantes as u64);
+    }
+
+    debug!("Generer les transactions de nouveau message pour {} destinataire(s)", profils.len());
+
+    Ok((profils, cles_chiffrage, manquants.iter().map(|s| s.to_string()).collect()))
+}
+
+/// Recupere la cle de chiffrage d'un profil aupres du maitre des cles.
+pub async fn charger_cle_profil<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, cle_id: &String) -> Result<CleSecreteX25519, Error>
+    where M: GenerateurMessages
+{
+    let enveloppe_signature = middleware.get_enveloppe_signature();
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCLES, MAITREDESCLES_REQUETE_DECHIFFRAGE_V2, vec![Securite::L3Protege])
+        .timeout_blocking(gestionnaire.config.timeout_requete_ms)
+        .build();
+    let requete = RequeteDechiffrage {
+        domaine: DOMAINE_NOM.to_string(),
+        liste_hachage_bytes: None,
+        cle_ids: Some(vec![cle_id.clone()]),
+        certificat_rechiffrage: None,
+    };
+    let debut_requete = Instant::now();
+    let reponse = middleware.transmettre_requete(routage, requete).await;
+    metriques().latence_maitredescles.observe(debut_requete.elapsed().as_secs_f64());
+    if let Ok(Some(TypeMessage::Valide(reponse))) = reponse {
+        let reponse_ref = reponse.message.parse()?;
+        let reponse_dechiffree: ReponseRequeteDechiffrageV2 = reponse_ref.dechiffrer(enveloppe_signature.as_ref())?;
+        if reponse_dechiffree.ok {
+            match reponse_dechiffree.cles {
+                Some(mut inner) => {
+                    if inner.len() != 1 {
+                        Err(Error::Str("charger_cle_profil Mauvais nombre de cles dechiffrees recues"))?
+                    }
+                    let cle = inner.remove(0);
+                    Ok(cle.cle_secrete()?)
+                },
+                None => Err(Error::Str("charger_cle_profil Aucunes cles dechiffrees recues"))?
+            }
+        } else {
+            Err(Error::String(format!("charger_cle_profil Erreur requete cle dechiffrage : {:?}", reponse_dechiffree.err)))?
+        }
+    } else {
+        Err(Error::Str("charger_cle_profil Erreur requete cle dechiffrage"))?
+    }
+}
+
+/// Remplace les cles de chiffrage des profils qui ont expire. Les nouveaux messages sont chiffres
+/// avec la nouvelle cle, les messages existants conservent leur cle_id.
+pub async fn rotation_cles_profils<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M) -> Result<(), Error>
+    where M: MongoDao + GenerateurMessages + CleChiffrageHandler + ValidateurX509
+{
+    let maintenant = Utc::now();
+
+    // Les cles assignees avant l'ajout de date_cle debutent leur periode de validite maintenant
+    let filtre_sans_date = doc! {"cle_id": {"$exists": true}, "date_cle": {"$exists": false}};
+    let ops = doc! {
+        "$set": {"date_cle": maintenant},
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true},
+    };
+    let resultat = middleware.get_collection(COLLECTION_USAGERS_NOM)?.update_many(filtre_sans_date, ops, None).await?;
+    if resultat.modified_count > 0 {
+        info!("rotation_cles_profils date_cle initialisee pour {} profils usagers", resultat.modified_count);
+    }
+
+    let date_expiration = maintenant - chrono::Duration::days(gestionnaire.config.duree_cle_usager_jours);
+    let filtre = doc! {"cle_id": {"$exists": true}, "date_cle": {"$lt": date_expiration}};
+    let collection = middleware.get_collection_typed::<ProfilUsagerMessages>(COLLECTION_USAGERS_NOM)?;
+    let mut total = 0;
+    loop {
+        let options = FindOptions::builder().limit(constantes::LIMITE_ROTATION_CLES_BATCH).build();
+        let mut curseur = collection.find(filtre.clone(), options).await?;
+        let mut profils = Vec::new();
+        while curseur.advance().await? {
+            let mut profil: ProfilUsagerMessages = curseur.deserialize_current()?;
+            profil.cle_id = None;
+            profils.push(profil);
+        }
+
+        if profils.is_empty() {
+            break
+        }
+
+        // Chaque nouvelle cle est conservee avec une transaction assignerCleUsager
+        total += profils.len();
+        let mut cles_chiffrage = HashMap::new();
+        generer_cles_profils(gestionnaire, middleware, &mut profils, &mut cles_chiffrage).await?;
+    }
+
+    if total > 0 {
+        info!("rotation_cles_profils Rotation de {} cles de profils usagers", total);
+    }
+
+    Ok(())
+}
+
+/// Genere une nouvelle cle de chiffrage pour chaque profil qui n'en a pas.
+pub async fn generer_cles_profils<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M,
+                                    profils: &mut Vec<ProfilUsagerMessages>, cles_chiffrage: &mut HashMap<String, CleSecreteX25519>)
+    -> Result<(), Error>
+    where M: MongoDao + GenerateurMessages + CleChiffrageHandler + ValidateurX509
+{
+    let enveloppe_signature = middleware.get_enveloppe_signature();
     let enveloppe_ca = enveloppe_signature.enveloppe_ca.as_ref();
     let enveloppes_chiffrage = middleware.get_publickeys_chiffrage();
     if enveloppes_chiffrage.len() == 0 {
@@ -414,14 +615,14 @@ async fn get_profils_usagers<M,S>(middleware: &M, noms_usagers: &Vec<S>)
     }
     let enveloppes_chiffrage_ref: Vec<&EnveloppeCertificat> = enveloppes_chiffrage.iter().map(|e| e.as_ref()).collect();
     let domaines = vec![DOMAINE_NOM];
-    for p in &mut profils {
+    for p in profils {
         if p.cle_id.is_none() {
             // Generer cle pour usager
             let (dechiffrage, cle) = generer_cle_avec_ca(domaines.clone(), enveloppe_ca, enveloppes_chiffrage_ref.clone())?;
 
             // Conserver cle aupres du maitre des cles
             let routage_cle = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCLES, COMMANDE_AJOUTER_CLE_DOMAINES, vec![Securite::L1Public])
-                .timeout_blocking(5000)
+                .timeout_blocking(gestionnaire.config.timeout_requete_longue_ms)
                 .build();
             let mut cles = HashMap::new();
             match dechiffrage.cles {
@@ -430,52 +631,47 @@ async fn get_profils_usagers<M,S>(middleware: &M, noms_usagers: &Vec<S>)
                         cles.insert(fingerprint, val);
                     }
                 },
-                None => Err(Error::Str("get_profils_usagers Aucunes cles generees par generer_cle_avec_ca"))?
+                None => Err(Error::Str("generer_cles_profils Aucunes cles generees par generer_cle_avec_ca"))?
             }
 
             let signature_domaines = match dechiffrage.signature {
                 Some(inner) => inner,
-                None => Err(Error::Str("get_profils_usagers Aucune signature generee par generer_cle_avec_ca"))?
+                None => Err(Error::Str("generer_cles_profils Aucune signature generee par generer_cle_avec_ca"))?
             };
 
             let commande = CommandeAjouterCleDomaine {
                 cles,
                 signature: signature_domaines.clone(),
             };
-            if let Ok(Some(TypeMessage::Valide(message))) = middleware.transmettre_commande(routage_cle, commande).await {
+            let debut_requete = Instant::now();
+            let reponse = middleware.transmettre_commande(routage_cle, commande).await;
+            metriques().latence_maitredescles.observe(debut_requete.elapsed().as_secs_f64());
+            if let Ok(Some(TypeMessage::Valide(message))) = reponse {
                 let message_ref = message.message.parse()?;
                 let message_contenu = message_ref.contenu()?;
                 let reponse_etat: ReponseCommande = message_contenu.deserialize()?;
                 if let Some(true) = reponse_etat.ok {
-                    // Ok, sauvegarder cle_id dans profil
+                    // Ok, sauvegarder cle_id dans profil avec une transaction (permet la regeneration)
                     let cle_id = signature_domaines.get_cle_ref()?.to_string();
-                    let filtre = doc!{"user_id": &p.user_id};
-                    let ops = doc! {
-                        "$set": {"cle_id": &cle_id},
-                        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+                    let transaction = TransactionAssignerCleUsager {
+                        user_id: p.user_id.clone(),
+                        nom_usager: p.nom_usager.clone(),
+                        cle_id: cle_id.clone(),
                     };
-                    let collection = middleware.get_collection(COLLECTION_USAGERS_NOM)?;
-                    if collection.update_one(filtre, ops, None).await?.modified_count != 1 {
-                        Err(Error::String(format!("get_profils_usagers Erreur sauvegarde cle_id pour profil {} - SKIP", p.user_id)))?
-                    }
+                    sauvegarder_traiter_transaction_serializable_v2(middleware, &transaction, gestionnaire,
+                        DOMAINE_NOM, constantes::TRANSACTION_ASSIGNER_CLE_USAGER).await?;
                     p.cle_id = Some(cle_id.clone());
                     cles_chiffrage.insert(cle_id, cle.secret);
                 } else {
-                    Err(Error::String(format!("get_profils_usagers Erreur sauvegarde cle aupres du maitre des cles : {:?}", reponse_etat)))?
+                    Err(Error::String(format!("generer_cles_profils Erreur sauvegarde cle aupres du maitre des cles : {:?}", reponse_etat)))?
                 }
             } else {
-                Err(Error::Str("get_profils_usagers Erreur sauvegarde nouvelle cle profil aupres du maitre des cles"))?
+                Err(Error::Str("generer_cles_profils Erreur sauvegarde nouvelle cle profil aupres du maitre des cles"))?
             }
         }
     }
 
-    if cles_chiffrage.len() != profils.len() {
-        Err(Error::Str("get_profils_usagers Mismatch nombre de cles de chiffrage et profils"))?
-    }
-
-    debug!("Generer les transactions de nouveau message pour {} destinataire(s)", profils.len());
-
-    Ok((profils, cles_chiffrage, manquants.iter().map(|s| s.to_string()).collect()))
+    Ok(())
 }
 
 enum FiltreUsagerChamp<S> where S: ToString {
@@ -535,23 +731,88 @@ pub struct MessageFichierV1 {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct MessagePostV1 {
+pub struct MessagePostV1 {
     /// Contenu HTML du message
-    contenu: String,
+    pub contenu: String,
     /// Liste de destinataires (noms usagers)
-    destinataires: Vec<String>,
+    pub destinataires: Vec<String>,
     /// Information pour repondre au message.
     #[serde(skip_serializing_if="Option::is_none")]
-    reply_to: Option<String>,
+    pub reply_to: Option<String>,
     /// Date de creation du message du point de vue de l'origine
     #[serde(default, skip_serializing_if="Option::is_none", with="optionepochseconds")]
-    date_post: Option<DateTime<Utc>>,
+    pub date_post: Option<DateTime<Utc>>,
     /// Information non structuree sur l'origine du message.
-    origine: Option<String>,
+    pub origine: Option<String>,
     /// Nom de l'auteur (non authoritative).
-    auteur: Option<String>,
+    pub auteur: Option<String>,
     /// Fichiers attaches au message
-    fichiers: Option<Vec<MessageFichierV1>>
+    pub fichiers: Option<Vec<MessageFichierV1>>,
+    /// Identificateur de la conversation (fil de messages).
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub conversation_id: Option<String>,
+    /// Date apres laquelle le message peut etre supprime.
+    #[serde(default, skip_serializing_if="Option::is_none", with="optionepochseconds")]
+    pub expiration: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all="snake_case")]
+enum StatutMessage {
+    Ok,
+    /// Message inconnu ou qui n'appartient pas a l'usager.
+    Inconnu,
+    DejaLu,
+}
+
+#[derive(Serialize)]
+struct ResultatMessage {
+    message_id: String,
+    statut: StatutMessage,
+}
+
+#[derive(Serialize)]
+struct ReponseResultatsMessages {
+    ok: bool,
+    #[serde(skip_serializing_if="Option::is_none")]
+    code: Option<usize>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    raison: Option<&'static str>,
+    resultats: Vec<ResultatMessage>,
+}
+
+impl ReponseResultatsMessages {
+    fn new(resultats: Vec<ResultatMessage>) -> Self {
+        if resultats.iter().all(|r| r.statut == StatutMessage::Inconnu) {
+            let erreur = ErreurMessages::MessageInconnu;
+            Self { ok: false, code: Some(erreur.code()), raison: Some(erreur.raison()), resultats }
+        } else {
+            Self { ok: true, code: None, raison: None, resultats }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MessageEtatLu {
+    message_id: String,
+    lu: Option<bool>,
+}
+
+/// Charge l'etat lu des messages de l'usager. Les message_ids absents n'appartiennent pas a l'usager.
+async fn charger_etat_messages<M>(middleware: &M, user_id: &String, message_ids: &Vec<String>)
+    -> Result<HashMap<String, bool>, Error>
+    where M: MongoDao
+{
+    let filtre = doc!{constantes::CHAMP_USER_ID: user_id, constantes::CHAMP_MESSAGE_ID: {"$in": message_ids}};
+    let options = FindOptions::builder().projection(doc!{"message_id": 1, "lu": 1}).build();
+    let collection = middleware.get_collection_typed::<MessageEtatLu>(COLLECTION_RECEPTION_NOM)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut etats = HashMap::new();
+    while curseur.advance().await? {
+        let row = curseur.deserialize_current()?;
+        etats.insert(row.message_id, row.lu.unwrap_or(false));
+    }
+    Ok(etats)
 }
 
 async fn commande_marquer_lu<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
@@ -566,46 +827,180 @@ async fn commande_marquer_lu<M>(gestionnaire: &GestionnaireDomaineMessages, midd
 
     let user_id = match message.certificat.get_user_id()? {
         Some(inner) => inner,
-        None => Err(Error::Str("commande_marquer_lu Certificat sans user_id"))?
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
     };
 
-    let message_ids = commande.message_ids;
+    // Conserver uniquement les messages de l'usager qui n'ont pas deja lu==true
+    let etats = charger_etat_messages(middleware, &user_id, &commande.message_ids).await?;
+    let mut resultats = Vec::with_capacity(commande.message_ids.len());
+    let mut message_ids = Vec::new();
+    for message_id in commande.message_ids {
+        let statut = match etats.get(&message_id) {
+            Some(true) => StatutMessage::DejaLu,
+            Some(false) => {
+                message_ids.push(message_id.clone());
+                StatutMessage::Ok
+            },
+            None => StatutMessage::Inconnu
+        };
+        resultats.push(ResultatMessage { message_id, statut });
+    }
 
-    // Verifier que l'usager a acces au message et qu'il n'a pas deja lu==true
-    let filtre = doc!{constantes::CHAMP_USER_ID: &user_id, constantes::CHAMP_MESSAGE_ID: {"$in": &message_ids}};
-    let collection = middleware.get_collection(COLLECTION_RECEPTION_NOM)?;
-    let doc_existant = collection.find_one(filtre, None).await?;
-    if doc_existant.is_some() {
+    if message_ids.len() > 0 {
         // Ok, creer la transaction
-        sauvegarder_traiter_transaction_v2(middleware, message, gestionnaire).await?;
+        let transaction = TransactionMarquerLu { user_id: Some(user_id.clone()), message_ids, filtre: None };
+        sauvegarder_traiter_transaction_serializable_v2(middleware, &transaction, gestionnaire,
+            DOMAINE_NOM, constantes::COMMANDE_MARQUER_LU).await?;
+    }
 
-        // Emettre evenement de messages suppriems
-        let routage = RoutageMessageAction::builder(
-            DOMAINE_NOM, constantes::EVENEMENT_MESSAGE_LU, vec![Securite::L2Prive])
-            .partition(&user_id)
-            .build();
-        let evenement = EvenementMessagesLu {message_ids, user_id};
-        middleware.emettre_evenement(routage, evenement).await?;
+    Ok(Some(middleware.build_reponse(ReponseResultatsMessages::new(resultats))?.0))
+}
 
-        Ok(Some(middleware.reponse_ok(200, None)?))
-    } else {
-        Ok(Some(middleware.reponse_err(404, None, Some("Message id inconnu ou n'appartient pas a l'usager"))?))
+async fn commande_supprimer_message<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
+{
+    let commande: TransactionSupprimerMessage = {
+        let message_ref = message.message.parse()?;
+
+        message_ref.contenu()?.deserialize()?
+    };
+
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+
+    // Conserver uniquement les messages de l'usager
+    let etats = charger_etat_messages(middleware, &user_id, &commande.message_ids).await?;
+    let mut resultats = Vec::with_capacity(commande.message_ids.len());
+    let mut message_ids = Vec::new();
+    for message_id in commande.message_ids {
+        let statut = match etats.contains_key(&message_id) {
+            true => {
+                message_ids.push(message_id.clone());
+                StatutMessage::Ok
+            },
+            false => StatutMessage::Inconnu
+        };
+        resultats.push(ResultatMessage { message_id, statut });
+    }
+
+    if message_ids.len() > 0 {
+        // Ok, creer la transaction
+        let transaction = TransactionSupprimerMessage { user_id: Some(user_id.clone()), message_ids, filtre: None };
+        sauvegarder_traiter_transaction_serializable_v2(middleware, &transaction, gestionnaire,
+            DOMAINE_NOM, constantes::COMMANDE_SUPPRIMER_MESSAGE).await?;
     }
+
+    Ok(Some(middleware.build_reponse(ReponseResultatsMessages::new(resultats))?.0))
 }
 
 #[derive(Serialize)]
-struct EvenementMessagesSupprimes {
-    message_ids: Vec<String>,
-    user_id: String,
+struct ReponseOperationFiltre {
+    ok: bool,
+    /// Nombre de messages traites.
+    nombre: usize,
+    /// True si la limite de messages a ete atteinte, la commande doit etre transmise a nouveau.
+    incomplet: bool,
 }
 
-type EvenementMessagesLu = EvenementMessagesSupprimes;
+/// Resout les message_ids de l'usager qui correspondent au filtre.
+async fn resoudre_filtre_messages<M>(middleware: &M, user_id: &String, filtre_messages: &FiltreMessages, non_lus: bool)
+    -> Result<(Vec<String>, bool), Error>
+    where M: MongoDao
+{
+    let mut filtre = doc!{constantes::CHAMP_USER_ID: user_id};
+    if let Some(bucket) = filtre_messages.bucket.as_ref() {
+        filtre.insert("bucket", filtre_bucket(bucket.as_str()));
+    }
+    if let Some(date_avant) = filtre_messages.date_avant {
+        filtre.insert("date_traitement", doc!{"$lt": date_avant});
+    }
+    if non_lus {
+        filtre.insert("lu", doc!{"$ne": true});
+    }
 
-async fn commande_supprimer_message<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    let options = FindOptions::builder()
+        .projection(doc!{"message_id": 1, "lu": 1})
+        .limit(constantes::LIMITE_MESSAGES_FILTRE)
+        .build();
+    let collection = middleware.get_collection_typed::<MessageEtatLu>(COLLECTION_RECEPTION_NOM)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut message_ids = Vec::new();
+    while curseur.advance().await? {
+        let row = curseur.deserialize_current()?;
+        message_ids.push(row.message_id);
+    }
+
+    let incomplet = message_ids.len() as i64 >= constantes::LIMITE_MESSAGES_FILTRE;
+    Ok((message_ids, incomplet))
+}
+
+async fn commande_marquer_lu_filtre<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
 {
-    let commande: TransactionSupprimerMessage = {
+    let filtre: FiltreMessages = {
+        let message_ref = message.message.parse()?;
+        message_ref.contenu()?.deserialize()?
+    };
+
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+
+    let (message_ids, incomplet) = resoudre_filtre_messages(middleware, &user_id, &filtre, true).await?;
+    let nombre = message_ids.len();
+
+    if nombre > 0 {
+        let transaction = TransactionMarquerLu { user_id: Some(user_id.clone()), message_ids, filtre: Some(filtre) };
+        sauvegarder_traiter_transaction_serializable_v2(middleware, &transaction, gestionnaire,
+            DOMAINE_NOM, constantes::COMMANDE_MARQUER_LU).await?;
+    }
+
+    let reponse = ReponseOperationFiltre { ok: true, nombre, incomplet };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+async fn commande_supprimer_messages_filtre<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
+{
+    let filtre: FiltreMessages = {
+        let message_ref = message.message.parse()?;
+        message_ref.contenu()?.deserialize()?
+    };
+
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+
+    // Eviter de supprimer tous les messages de l'usager avec un filtre vide
+    if filtre.bucket.is_none() && filtre.date_avant.is_none() {
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::RequeteInvalide, Some("Filtre bucket ou date_avant requis"))?))
+    }
+
+    let (message_ids, incomplet) = resoudre_filtre_messages(middleware, &user_id, &filtre, false).await?;
+    let nombre = message_ids.len();
+
+    if nombre > 0 {
+        let transaction = TransactionSupprimerMessage { user_id: Some(user_id.clone()), message_ids, filtre: Some(filtre) };
+        sauvegarder_traiter_transaction_serializable_v2(middleware, &transaction, gestionnaire,
+            DOMAINE_NOM, constantes::COMMANDE_SUPPRIMER_MESSAGE).await?;
+    }
+
+    let reponse = ReponseOperationFiltre { ok: true, nombre, incomplet };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+async fn commande_indexer_message<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
+{
+    let commande: TransactionIndexerMessage = {
         let message_ref = message.message.parse()?;
 
         message_ref.contenu()?.deserialize()?
@@ -613,30 +1008,22 @@ async fn commande_supprimer_message<M>(gestionnaire: &GestionnaireDomaineMessage
 
     let user_id = match message.certificat.get_user_id()? {
         Some(inner) => inner,
-        None => Err(Error::Str("commande_supprimer_message Certificat sans user_id"))?
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
     };
 
-    let message_ids = commande.message_ids;
+    if commande.tokens.len() > constantes::LIMITE_TOKENS_INDEX_MESSAGE {
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::LimiteDepassee, Some("Trop de tokens de recherche pour le message"))?))
+    }
 
-    // Verifier que l'usager a acces au message et qu'il n'a pas deja lu==true
-    let filtre = doc!{constantes::CHAMP_USER_ID: &user_id, constantes::CHAMP_MESSAGE_ID: {"$in": &message_ids}};
+    // Verifier que l'usager a acces au message
+    let filtre = doc!{constantes::CHAMP_USER_ID: &user_id, constantes::CHAMP_MESSAGE_ID: &commande.message_id};
     let collection = middleware.get_collection(COLLECTION_RECEPTION_NOM)?;
     let doc_existant = collection.find_one(filtre, None).await?;
     if doc_existant.is_some() {
-        // Ok, creer la transaction
         sauvegarder_traiter_transaction_v2(middleware, message, gestionnaire).await?;
-
-        // Emettre evenement de messages suppriems
-        let routage = RoutageMessageAction::builder(
-            DOMAINE_NOM, constantes::EVENEMENT_MESSAGE_SUPPRIME, vec![Securite::L2Prive])
-            .partition(&user_id)
-            .build();
-        let evenement = EvenementMessagesSupprimes {message_ids, user_id};
-        middleware.emettre_evenement(routage, evenement).await?;
-
         Ok(Some(middleware.reponse_ok(200, None)?))
     } else {
-        Ok(Some(middleware.reponse_err(404, None, Some("Message id inconnu ou n'appartient pas a l'usager"))?))
+        Ok(Some(reponse_erreur(middleware, ErreurMessages::MessageInconnu, Some("Message id inconnu ou n'appartient pas a l'usager"))?))
     }
 }
 
@@ -645,21 +1032,39 @@ pub struct FichierMessageReclamation {
     fuuid: String,
 }
 
-pub const LIMITE_FUUIDS_BATCH: usize = 10000;
+#[derive(Deserialize)]
+struct CommandeReclamerFuuids {
+    /// Si true, transmet uniquement les fuuids modifies depuis la derniere sync confirmee.
+    incremental: Option<bool>,
+    /// Par defaut, le domaine Fichiers confirme chaque batch avant la suivante. Avec false, les
+    /// batch sont transmises sans attente et l'etat de la sync n'est pas conserve.
+    confirmation: Option<bool>,
+}
 
-pub async fn commande_reclamer_fuuids<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+#[derive(Deserialize)]
+struct EtatSyncFichiers {
+    #[serde(with="millegrilles_common_rust::bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    derniere_sync: DateTime<Utc>,
+}
+
+pub async fn commande_reclamer_fuuids<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao
 {
     if !message.certificat.verifier_exchanges(vec![Securite::L2Prive])? {
-        error!("evenement_fichiers_syncpret Acces refuse, certificat n'est pas d'un exchange L2");
+        error!("commande_reclamer_fuuids Acces refuse, certificat n'est pas d'un exchange L2");
         return Ok(None)
     }
     if !message.certificat.verifier_roles(vec![RolesCertificats::Fichiers])? {
-        error!("evenement_fichiers_syncpret Acces refuse, certificat n'est pas de role fichiers");
+        error!("commande_reclamer_fuuids Acces refuse, certificat n'est pas de role fichiers");
         return Ok(None)
     }
 
+    let commande: CommandeReclamerFuuids = {
+        let message_ref = message.message.parse()?;
+        message_ref.contenu()?.deserialize()?
+    };
+
     // Repondre immediatement pour declencher sync
     {
         match message.type_message {
@@ -673,61 +1078,151 @@ pub async fn commande_reclamer_fuuids<M>(_gestionnaire: &GestionnaireDomaineMess
                     }
                 }
             },
-            _ => error!("evenement_fichiers_syncpret Mauvais type message, devrait etre commande/evenement")
+            _ => error!("commande_reclamer_fuuids Mauvais type message, devrait etre commande/evenement")
         }
     }
 
+    // Conserver la date avant le debut du traitement, les modifications concurrentes seront
+    // transmises a la prochaine sync incrementale.
+    let debut_sync = Utc::now();
+
+    let collection_configuration = middleware.get_collection(COLLECTION_CONFIGURATION_NOM)?;
+    let filtre_etat = doc! {"config_key": CONFIG_SYNC_FICHIERS};
+    let derniere_sync = match commande.incremental {
+        Some(true) => match collection_configuration.find_one(filtre_etat.clone(), None).await? {
+            Some(inner) => {
+                let etat: EtatSyncFichiers = convertir_bson_deserializable(inner)?;
+                Some(etat.derniere_sync)
+            },
+            None => None  // Aucune sync confirmee, faire une sync complete
+        },
+        _ => None
+    };
+    let incremental = derniere_sync.is_some();
+    let confirmation = commande.confirmation.unwrap_or(true);
+
+    // Les fichiers de messages supprimes sont transmis avec le flag archive
+    let mut total = 0 as i64;
+    for archive in [false, true] {
+        let mut filtre = match archive {
+            true => doc! {"supprime": true},
+            false => doc! {"supprime": {"$ne": true}},
+        };
+        if let Some(derniere_sync) = derniere_sync.as_ref() {
+            filtre.insert(CommonConstantes::CHAMP_MODIFICATION, doc! {"$gte": *derniere_sync});
+        }
+        total += transmettre_fuuids_filtre(gestionnaire, middleware, filtre, archive, incremental, confirmation).await?;
+    }
+
+    // Transmettre message avec flag termine dans tous les cas
+    transmettre_fuuids_fichiers(gestionnaire, middleware, &vec![], false, true, Some(total), incremental, confirmation).await?;
+
+    if confirmation {
+        // Le domaine Fichiers a confirme toutes les batch, conserver la date de sync
+        let ops = doc! {
+            "$set": {"derniere_sync": debut_sync},
+            "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true},
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection_configuration.update_one(filtre_etat, ops, options).await?;
+
+        // Les fichiers supprimes ont ete archives, retirer les references
+        let filtre_purge = doc! {"supprime": true, CommonConstantes::CHAMP_MODIFICATION: {"$lt": debut_sync}};
+        let resultat = middleware.get_collection(COLLECTION_FICHIERS_NOM)?.delete_many(filtre_purge, None).await?;
+        debug!("commande_reclamer_fuuids {} references de fichiers supprimes retirees", resultat.deleted_count);
+    }
+
+    debug!("commande_reclamer_fuuids Transmis {} confirmations de fichiers durant sync (incremental: {})", total, incremental);
+
+    Ok(None)
+}
+
+/// Transmet les fuuids correspondant au filtre par batch. Retourne le nombre de fichiers transmis.
+async fn transmettre_fuuids_filtre<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, filtre: Document, archive: bool, incremental: bool, confirmation: bool)
+    -> Result<i64, Error>
+    where M: GenerateurMessages + MongoDao
+{
     let collection = middleware.get_collection_typed::<FichierMessageReclamation>(
         COLLECTION_FICHIERS_NOM)?;
 
-    let mut fichiers_actifs: HashSet<String> = HashSet::with_capacity(10000);
+    let limite_batch = gestionnaire.config.limite_fuuids_batch;
+    let mut fichiers_actifs: HashSet<String> = HashSet::with_capacity(limite_batch);
 
     let projection = doc!{"fuuid": 1};
     let options = FindOptions::builder().projection(projection).build();
-    let filtre = doc! {};
     let mut curseur = collection.find(filtre, Some(options)).await?;
     let mut total = 0 as i64;
     while curseur.advance().await? {
         let info_fichier = curseur.deserialize_current()?;
-        // let info_fichier: RowFichiersSyncpret = convertir_bson_deserializable(f?)?;
-        total += 1;
-        // fichiers_actifs.extend(info_fichier.fuuids_reclames.into_iter().map(|s| s.to_owned()));
         fichiers_actifs.insert(info_fichier.fuuid);
 
-        if fichiers_actifs.len() >= LIMITE_FUUIDS_BATCH {
-            let fichiers_actifs_vec: Vec<String> = fichiers_actifs.drain().collect();
-            transmettre_fuuids_fichiers(middleware, &fichiers_actifs_vec, false, false, None).await?;
-            fichiers_actifs.clear();
-            tokio_time::sleep(tokio_time::Duration::from_millis(500)).await;
+        if fichiers_actifs.len() >= limite_batch {
+            total += transmettre_batch_fuuids(gestionnaire, middleware, &mut fichiers_actifs, archive, incremental, confirmation).await?;
         }
     }
 
     if ! fichiers_actifs.is_empty() {
-        let fichiers_actifs_vec: Vec<String> = fichiers_actifs.drain().collect();
-        transmettre_fuuids_fichiers(middleware, &fichiers_actifs_vec, false, false, Some(total.clone())).await?;
+        total += transmettre_batch_fuuids(gestionnaire, middleware, &mut fichiers_actifs, archive, incremental, confirmation).await?;
     }
 
-    // Transmettre message avec flag termine dans tous les cas
-    transmettre_fuuids_fichiers(middleware, &vec![], false, true, Some(total)).await?;
+    Ok(total)
+}
 
-    debug!("evenement_fichiers_syncpret Transmis {} confirmations de fichiers durant sync", total);
+/// Transmet une batch de fuuids et la vide. Un fuuid est archive uniquement s'il n'est plus
+/// reference par aucun message non supprime. Retourne le nombre de fuuids transmis.
+async fn transmettre_batch_fuuids<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, fuuids: &mut HashSet<String>, archive: bool, incremental: bool, confirmation: bool)
+    -> Result<i64, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    if archive {
+        let fuuids_batch: Vec<&String> = fuuids.iter().collect();
+        let filtre = doc! {"fuuid": {"$in": fuuids_batch}, "supprime": {"$ne": true}};
+        let collection = middleware.get_collection_typed::<FichierMessageReclamation>(COLLECTION_FICHIERS_NOM)?;
+        let options = FindOptions::builder().projection(doc!{"fuuid": 1}).build();
+        let mut curseur = collection.find(filtre, Some(options)).await?;
+        while curseur.advance().await? {
+            let fichier_actif = curseur.deserialize_current()?;
+            fuuids.remove(&fichier_actif.fuuid);
+        }
+    }
 
-    Ok(None)
+    let fuuids_vec: Vec<String> = fuuids.drain().collect();
+    if fuuids_vec.is_empty() {
+        return Ok(0)
+    }
+    transmettre_fuuids_fichiers(gestionnaire, middleware, &fuuids_vec, archive, false, None, incremental, confirmation).await?;
+    Ok(fuuids_vec.len() as i64)
 }
 
-async fn transmettre_fuuids_fichiers<M>(middleware: &M, fuuids: &Vec<String>, archive: bool, termine: bool, total: Option<i64>)
+/// Transmet une batch de fuuids au domaine Fichiers. Avec confirmation, attend la reponse du
+/// domaine Fichiers avant de continuer.
+async fn transmettre_fuuids_fichiers<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, fuuids: &Vec<String>, archive: bool, termine: bool, total: Option<i64>, incremental: bool, confirmation: bool)
                                         -> Result<(), Error>
     where M: GenerateurMessages + MongoDao,
 {
-    let confirmation = doc! {
+    if !termine {
+        metriques().taille_batch_fuuids.observe(fuuids.len() as f64);
+    }
+    let contenu = doc! {
         "fuuids": fuuids,
         "archive": archive,
         "termine": termine,
         "total": total,
+        "incremental": incremental,
     };
+    if !confirmation {
+        let routage = RoutageMessageAction::builder(DOMAINE_FICHIERS, COMMANDE_ACTIVITE_FUUIDS, vec![Securite::L2Prive])
+            .blocking(false)
+            .build();
+        middleware.transmettre_commande(routage, &contenu).await?;
+        return Ok(())
+    }
+
     let routage = RoutageMessageAction::builder(DOMAINE_FICHIERS, COMMANDE_ACTIVITE_FUUIDS, vec![Securite::L2Prive])
-        .blocking(false)
+        .timeout_blocking(gestionnaire.config.timeout_fichiers_ms)
         .build();
-    middleware.transmettre_commande(routage, &confirmation).await?;
-    Ok(())
+    match middleware.transmettre_commande(routage, &contenu).await? {
+        Some(TypeMessage::Valide(_)) => Ok(()),
+        _ => Err(Error::Str("transmettre_fuuids_fichiers Aucune confirmation du domaine Fichiers, sync interrompue"))
+    }
 }
@@ -0,0 +1,78 @@
+use log::debug;
+use millegrilles_common_rust::bson::{doc, Bson};
+use millegrilles_common_rust::constantes::Securite;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use serde::{Deserialize, Serialize};
+
+use crate::constantes;
+use crate::constantes::{COLLECTION_RECEPTION_NOM, DOMAINE_NOM};
+use crate::structures_messages::BUCKET_RECEPTION;
+
+#[derive(Deserialize)]
+struct CompteurBucketDb {
+    /// Valeur du champ bucket, absent pour la reception.
+    #[serde(rename="_id")]
+    bucket: Option<String>,
+    total: i64,
+    non_lus: i64,
+}
+
+#[derive(Serialize)]
+pub struct CompteurBucket {
+    pub bucket: String,
+    pub total: i64,
+    pub non_lus: i64,
+}
+
+#[derive(Serialize)]
+struct EvenementCompteursMaj<'a> {
+    user_id: &'a str,
+    compteurs: Vec<CompteurBucket>,
+}
+
+/// Calcule le nombre de messages et de messages non lus par bucket pour un usager.
+pub async fn calculer_compteurs<M>(middleware: &M, user_id: &str) -> Result<Vec<CompteurBucket>, Error>
+    where M: MongoDao
+{
+    let pipeline = vec![
+        doc! {"$match": {constantes::CHAMP_USER_ID: user_id, "supprime": {"$ne": true}}},
+        doc! {"$group": {
+            "_id": "$bucket",
+            "total": {"$sum": 1i64},
+            "non_lus": {"$sum": {"$cond": [{"$eq": ["$lu", true]}, 0i64, 1i64]}},
+        }},
+    ];
+    let collection = middleware.get_collection(COLLECTION_RECEPTION_NOM)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    let mut compteurs = Vec::new();
+    while curseur.advance().await? {
+        let mut row = curseur.deserialize_current()?;
+        if let Some(Bson::Null) = row.get("_id") {
+            row.remove("_id");
+        }
+        let compteur: CompteurBucketDb = convertir_bson_deserializable(row)?;
+        compteurs.push(CompteurBucket {
+            bucket: compteur.bucket.unwrap_or_else(|| BUCKET_RECEPTION.to_string()),
+            total: compteur.total,
+            non_lus: compteur.non_lus,
+        });
+    }
+    Ok(compteurs)
+}
+
+/// Emet les compteurs a jour sur la partition de l'usager.
+pub async fn emettre_compteurs<M>(middleware: &M, user_id: &str) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let compteurs = calculer_compteurs(middleware, user_id).await?;
+    debug!("emettre_compteurs Compteurs usager {} : {} buckets", user_id, compteurs.len());
+    let routage = RoutageMessageAction::builder(
+        DOMAINE_NOM, constantes::EVENEMENT_COMPTEURS_MAJ, vec![Securite::L2Prive])
+        .partition(user_id)
+        .build();
+    let evenement = EvenementCompteursMaj { user_id, compteurs };
+    middleware.emettre_evenement(routage, evenement).await?;
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use std::net::IpAddr;
+
+use log::{debug, info};
+use millegrilles_common_rust::error::Error;
+use serde::Deserialize;
+
+use crate::constantes;
+use crate::validation::LimitesMessages;
+
+/// Variable d'environnement avec le chemin optionnel du fichier de configuration TOML.
+const ENV_FICHIER_CONFIG: &str = "MG_MESSAGES_CONFIG";
+/// Duree maximale (jours) d'une cle de profil usager, au-dela la rotation n'a plus d'effet utile.
+const DUREE_CLE_USAGER_JOURS_MAX: i64 = 3650;
+
+/// Configuration du domaine Messages. Les valeurs par defaut sont remplacees par le fichier
+/// TOML (MG_MESSAGES_CONFIG) puis par les variables d'environnement MG_MESSAGES_*.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ConfigMessagesDomaine {
+    /// Nombre de messages par defaut et maximal pour une page de syncMessages.
+    pub limite_sync_messages: i64,
+    /// Nombre de fuuids par batch transmise a Fichiers lors d'un reclamer.
+    pub limite_fuuids_batch: usize,
+    /// Timeout (ms) des requetes vers le maitre des comptes et le maitre des cles.
+    pub timeout_requete_ms: u64,
+    /// Timeout (ms) des requetes plus longues (dechiffrage de message, sauvegarde de cles).
+    pub timeout_requete_longue_ms: u64,
+    /// Timeout (ms) de confirmation d'une batch de fuuids par Fichiers.
+    pub timeout_fichiers_ms: u64,
+    /// Intervalle (secondes) de rechargement des certificats du maitre des cles.
+    pub intervalle_certificats_secs: u64,
+    /// Intervalle (secondes) entre deux passes de thread_entretien.
+    pub intervalle_entretien_secs: u64,
+    /// Delai (secondes) apres le demarrage avant la premiere rotation des cles de profils.
+    pub delai_rotation_cles_secs: u64,
+    /// Intervalle (secondes) entre deux rotations des cles de profils.
+    pub intervalle_rotation_cles_secs: u64,
+    /// Age (jours) d'une cle de chiffrage de profil usager avant sa rotation.
+    pub duree_cle_usager_jours: i64,
+    /// Taille maximale (bytes) du contenu chiffre inclus dans l'evenement nouveauMessage.
+    /// 0 desactive l'inclusion du contenu.
+    pub taille_max_message_evenement: usize,
+    /// Nombre de tentatives de livraison d'un message a un destinataire avant l'abandon.
+    pub livraison_tentatives_max: i64,
+    /// Delai maximal (secondes) d'attente des taches en cours lors de l'arret.
+    pub delai_arret_secs: u64,
+    /// Adresse d'ecoute du serveur HTTP de sante (/health, /ready, /metrics). Locale par defaut.
+    pub adresse_sante: String,
+    /// Fichier PEM de la cle privee VAPID. Les notifications Web Push sont desactivees si absent.
+    pub fichier_cle_vapid: Option<String>,
+    /// Sujet VAPID (mailto: ou https:) transmis aux services push.
+    pub sujet_vapid: String,
+    /// Relais SMTP pour le transfert des messages par courriel. Desactive si absent.
+    pub smtp_hote: Option<String>,
+    pub smtp_port: u16,
+    /// Utiliser STARTTLS avec le relais SMTP.
+    pub smtp_starttls: bool,
+    pub smtp_usager: Option<String>,
+    pub smtp_mot_passe: Option<String>,
+    /// Adresse expediteur des courriels transferes.
+    pub smtp_expediteur: String,
+    /// Adresse d'ecoute SMTP pour la reception de courriels (ex. 127.0.0.1:2525). Desactive si absent.
+    pub adresse_reception_courriel: Option<String>,
+    /// Domaine des adresses courriel des usagers (nom_usager@domaine).
+    pub domaine_courriel: Option<String>,
+    /// Sources (IP ou reseau CIDR) autorisees a livrer des courriels, normalement le MTA local.
+    /// Requis lorsque la reception de courriels est activee.
+    pub sources_reception_courriel: Vec<String>,
+    pub limites: LimitesMessages,
+}
+
+impl Default for ConfigMessagesDomaine {
+    fn default() -> Self {
+        Self {
+            limite_sync_messages: constantes::LIMITE_SYNC_MESSAGES,
+            limite_fuuids_batch: constantes::LIMITE_FUUIDS_BATCH,
+            timeout_requete_ms: 3000,
+            timeout_requete_longue_ms: 5000,
+            timeout_fichiers_ms: 20000,
+            intervalle_certificats_secs: 300,
+            intervalle_entretien_secs: 30,
+            delai_rotation_cles_secs: 120,
+            intervalle_rotation_cles_secs: 24 * 3600,
+            duree_cle_usager_jours: 90,
+            taille_max_message_evenement: 64 * 1024,
+            livraison_tentatives_max: 20,
+            delai_arret_secs: 30,
+            adresse_sante: "127.0.0.1:2080".to_string(),
+            fichier_cle_vapid: None,
+            sujet_vapid: "mailto:admin@localhost".to_string(),
+            smtp_hote: None,
+            smtp_port: 587,
+            smtp_starttls: true,
+            smtp_usager: None,
+            smtp_mot_passe: None,
+            smtp_expediteur: "messages@localhost".to_string(),
+            adresse_reception_courriel: None,
+            domaine_courriel: None,
+            sources_reception_courriel: Vec::new(),
+            limites: LimitesMessages::default(),
+        }
+    }
+}
+
+impl ConfigMessagesDomaine {
+    /// Charge et valide la configuration.
+    pub fn charger() -> Result<Self, Error> {
+        let mut config = match std::env::var(ENV_FICHIER_CONFIG) {
+            Ok(chemin) => {
+                info!("ConfigMessagesDomaine.charger Chargement configuration {}", chemin);
+                let contenu = std::fs::read_to_string(chemin.as_str())
+                    .map_err(|e| Error::String(format!("ConfigMessagesDomaine.charger Erreur lecture {} : {:?}", chemin, e)))?;
+                toml::from_str(contenu.as_str())
+                    .map_err(|e| Error::String(format!("ConfigMessagesDomaine.charger Erreur format {} : {:?}", chemin, e)))?
+            },
+            Err(_) => Self::default()
+        };
+
+        config.appliquer_env()?;
+        config.valider()?;
+        debug!("ConfigMessagesDomaine.charger Configuration : {:?}", config);
+
+        Ok(config)
+    }
+
+    fn appliquer_env(&mut self) -> Result<(), Error> {
+        lire_env("MG_MESSAGES_LIMITE_SYNC", &mut self.limite_sync_messages)?;
+        lire_env("MG_MESSAGES_LIMITE_FUUIDS_BATCH", &mut self.limite_fuuids_batch)?;
+        lire_env("MG_MESSAGES_TIMEOUT_REQUETE_MS", &mut self.timeout_requete_ms)?;
+        lire_env("MG_MESSAGES_TIMEOUT_REQUETE_LONGUE_MS", &mut self.timeout_requete_longue_ms)?;
+        lire_env("MG_MESSAGES_TIMEOUT_FICHIERS_MS", &mut self.timeout_fichiers_ms)?;
+        lire_env("MG_MESSAGES_INTERVALLE_CERTIFICATS_SECS", &mut self.intervalle_certificats_secs)?;
+        lire_env("MG_MESSAGES_INTERVALLE_ENTRETIEN_SECS", &mut self.intervalle_entretien_secs)?;
+        lire_env("MG_MESSAGES_DELAI_ROTATION_CLES_SECS", &mut self.delai_rotation_cles_secs)?;
+        lire_env("MG_MESSAGES_INTERVALLE_ROTATION_CLES_SECS", &mut self.intervalle_rotation_cles_secs)?;
+        lire_env("MG_MESSAGES_DUREE_CLE_USAGER_JOURS", &mut self.duree_cle_usager_jours)?;
+        lire_env("MG_MESSAGES_TAILLE_MAX_MESSAGE_EVENEMENT", &mut self.taille_max_message_evenement)?;
+        lire_env("MG_MESSAGES_LIVRAISON_TENTATIVES_MAX", &mut self.livraison_tentatives_max)?;
+        lire_env("MG_MESSAGES_DELAI_ARRET_SECS", &mut self.delai_arret_secs)?;
+        lire_env("MG_MESSAGES_ADRESSE_SANTE", &mut self.adresse_sante)?;
+        lire_env_option("MG_MESSAGES_VAPID_PEM", &mut self.fichier_cle_vapid);
+        lire_env("MG_MESSAGES_SUJET_VAPID", &mut self.sujet_vapid)?;
+        lire_env_option("MG_MESSAGES_SMTP_HOTE", &mut self.smtp_hote);
+        lire_env("MG_MESSAGES_SMTP_PORT", &mut self.smtp_port)?;
+        lire_env("MG_MESSAGES_SMTP_STARTTLS", &mut self.smtp_starttls)?;
+        lire_env_option("MG_MESSAGES_SMTP_USAGER", &mut self.smtp_usager);
+        lire_env_option("MG_MESSAGES_SMTP_MOT_PASSE", &mut self.smtp_mot_passe);
+        lire_env("MG_MESSAGES_SMTP_EXPEDITEUR", &mut self.smtp_expediteur)?;
+        lire_env_option("MG_MESSAGES_ADRESSE_RECEPTION_COURRIEL", &mut self.adresse_reception_courriel);
+        lire_env_option("MG_MESSAGES_DOMAINE_COURRIEL", &mut self.domaine_courriel);
+        lire_env_liste("MG_MESSAGES_SOURCES_RECEPTION_COURRIEL", &mut self.sources_reception_courriel);
+        lire_env("MG_MESSAGES_TAILLE_MAX_CONTENU", &mut self.limites.taille_max_contenu)?;
+        lire_env("MG_MESSAGES_NOMBRE_MAX_FICHIERS", &mut self.limites.nombre_max_fichiers)?;
+        lire_env("MG_MESSAGES_TAILLE_MAX_FICHIERS", &mut self.limites.taille_max_fichiers)?;
+        Ok(())
+    }
+
+    fn valider(&self) -> Result<(), Error> {
+        if self.limite_sync_messages < 1 {
+            Err(Error::Str("ConfigMessagesDomaine.valider limite_sync_messages doit etre >= 1"))?
+        }
+        if self.limite_fuuids_batch < 1 {
+            Err(Error::Str("ConfigMessagesDomaine.valider limite_fuuids_batch doit etre >= 1"))?
+        }
+        if self.timeout_requete_ms == 0 || self.timeout_requete_longue_ms == 0 || self.timeout_fichiers_ms == 0 {
+            Err(Error::Str("ConfigMessagesDomaine.valider Les timeouts doivent etre > 0"))?
+        }
+        if self.livraison_tentatives_max < 1 {
+            Err(Error::Str("ConfigMessagesDomaine.valider livraison_tentatives_max doit etre >= 1"))?
+        }
+        if self.duree_cle_usager_jours < 1 || self.duree_cle_usager_jours > DUREE_CLE_USAGER_JOURS_MAX {
+            Err(Error::String(format!("ConfigMessagesDomaine.valider duree_cle_usager_jours doit etre entre 1 et {}", DUREE_CLE_USAGER_JOURS_MAX)))?
+        }
+        if self.intervalle_certificats_secs == 0 || self.intervalle_entretien_secs == 0 || self.intervalle_rotation_cles_secs == 0 {
+            Err(Error::Str("ConfigMessagesDomaine.valider Les intervalles doivent etre > 0"))?
+        }
+        if self.limites.taille_max_contenu == 0 || self.limites.taille_max_fichiers < 0 {
+            Err(Error::Str("ConfigMessagesDomaine.valider Limites de taille des messages invalides"))?
+        }
+        if self.adresse_reception_courriel.is_some() && self.domaine_courriel.is_none() {
+            Err(Error::Str("ConfigMessagesDomaine.valider domaine_courriel requis pour la reception de courriels"))?
+        }
+        if self.adresse_reception_courriel.is_some() && self.sources_reception_courriel.is_empty() {
+            Err(Error::Str("ConfigMessagesDomaine.valider sources_reception_courriel requis pour la reception de courriels"))?
+        }
+        if let Some(source) = self.sources_reception_courriel.iter().find(|s| parser_reseau(s.as_str()).is_none()) {
+            Err(Error::String(format!("ConfigMessagesDomaine.valider Source de reception invalide : {}", source)))?
+        }
+        if !(self.sujet_vapid.starts_with("mailto:") || self.sujet_vapid.starts_with("https:")) {
+            Err(Error::Str("ConfigMessagesDomaine.valider sujet_vapid doit debuter par mailto: ou https:"))?
+        }
+        Ok(())
+    }
+}
+
+/// Remplace la valeur si la variable d'environnement est presente. Une valeur invalide est
+/// une erreur de configuration.
+fn lire_env<T: std::str::FromStr>(nom: &str, valeur: &mut T) -> Result<(), Error> {
+    if let Ok(valeur_env) = std::env::var(nom) {
+        match valeur_env.parse::<T>() {
+            Ok(inner) => *valeur = inner,
+            Err(_) => Err(Error::String(format!("lire_env Valeur invalide pour {} : {}", nom, valeur_env)))?
+        }
+    }
+    Ok(())
+}
+
+/// Remplace la valeur optionnelle si la variable d'environnement est presente.
+fn lire_env_option(nom: &str, valeur: &mut Option<String>) {
+    if let Ok(valeur_env) = std::env::var(nom) {
+        *valeur = Some(valeur_env);
+    }
+}
+
+/// Remplace la liste si la variable d'environnement est presente (valeurs separees par des virgules).
+fn lire_env_liste(nom: &str, valeur: &mut Vec<String>) {
+    if let Ok(valeur_env) = std::env::var(nom) {
+        *valeur = valeur_env.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()).map(|v| v.to_string()).collect();
+    }
+}
+
+/// Parse une adresse IP ou un reseau CIDR (ex. 127.0.0.1, 10.0.0.0/8, ::1/128). Une adresse
+/// seule correspond a un reseau de longueur maximale.
+pub fn parser_reseau(valeur: &str) -> Option<(IpAddr, u8)> {
+    let (adresse, longueur) = match valeur.trim().split_once('/') {
+        Some((adresse, longueur)) => (adresse, Some(longueur)),
+        None => (valeur.trim(), None)
+    };
+    let adresse: IpAddr = adresse.parse().ok()?;
+    let longueur_max = match adresse {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128
+    };
+    let longueur = match longueur {
+        Some(longueur) => longueur.parse::<u8>().ok()?,
+        None => longueur_max
+    };
+    if longueur > longueur_max {
+        return None
+    }
+    Some((adresse, longueur))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valider_defaut() {
+        assert!(ConfigMessagesDomaine::default().valider().is_ok());
+    }
+
+    #[test]
+    fn valider_limites_invalides() {
+        let mut config = ConfigMessagesDomaine::default();
+        config.limite_sync_messages = 0;
+        assert!(config.valider().is_err());
+
+        let mut config = ConfigMessagesDomaine::default();
+        config.timeout_fichiers_ms = 0;
+        assert!(config.valider().is_err());
+
+        let mut config = ConfigMessagesDomaine::default();
+        config.limites.taille_max_fichiers = -1;
+        assert!(config.valider().is_err());
+    }
+
+    #[test]
+    fn valider_duree_cle_usager() {
+        let mut config = ConfigMessagesDomaine::default();
+        config.duree_cle_usager_jours = 0;
+        assert!(config.valider().is_err());
+        config.duree_cle_usager_jours = DUREE_CLE_USAGER_JOURS_MAX + 1;
+        assert!(config.valider().is_err());
+        config.duree_cle_usager_jours = 30;
+        assert!(config.valider().is_ok());
+    }
+
+    #[test]
+    fn valider_reception_courriel_sans_domaine() {
+        let mut config = ConfigMessagesDomaine::default();
+        config.adresse_reception_courriel = Some("127.0.0.1:2525".to_string());
+        config.sources_reception_courriel = vec!["127.0.0.1".to_string()];
+        assert!(config.valider().is_err());
+        config.domaine_courriel = Some("exemple.com".to_string());
+        assert!(config.valider().is_ok());
+    }
+
+    #[test]
+    fn valider_reception_courriel_sources() {
+        let mut config = ConfigMessagesDomaine::default();
+        config.adresse_reception_courriel = Some("127.0.0.1:2525".to_string());
+        config.domaine_courriel = Some("exemple.com".to_string());
+        assert!(config.valider().is_err());
+        config.sources_reception_courriel = vec!["127.0.0.1".to_string(), "10.0.0.0/33".to_string()];
+        assert!(config.valider().is_err());
+        config.sources_reception_courriel = vec!["127.0.0.1".to_string(), "10.0.0.0/8".to_string(), "::1".to_string()];
+        assert!(config.valider().is_ok());
+    }
+
+    #[test]
+    fn parser_reseau_cidr() {
+        assert_eq!(Some(("127.0.0.1".parse().unwrap(), 32)), parser_reseau("127.0.0.1"));
+        assert_eq!(Some(("10.0.0.0".parse().unwrap(), 8)), parser_reseau(" 10.0.0.0/8 "));
+        assert_eq!(Some(("fd00::".parse().unwrap(), 8)), parser_reseau("fd00::/8"));
+        assert_eq!(None, parser_reseau("10.0.0.0/33"));
+        assert_eq!(None, parser_reseau("mta.exemple.com"));
+        assert_eq!(None, parser_reseau("10.0.0.0/"));
+    }
+
+    #[test]
+    fn valider_sujet_vapid() {
+        let mut config = ConfigMessagesDomaine::default();
+        config.sujet_vapid = "admin@localhost".to_string();
+        assert!(config.valider().is_err());
+    }
+
+    #[test]
+    fn charger_toml_partiel() {
+        let config: ConfigMessagesDomaine = toml::from_str("limite_sync_messages = 50\n[limites]\nnombre_max_fichiers = 5\n").unwrap();
+        assert_eq!(50, config.limite_sync_messages);
+        assert_eq!(5, config.limites.nombre_max_fichiers);
+        assert_eq!(ConfigMessagesDomaine::default().timeout_requete_ms, config.timeout_requete_ms);
+    }
+}
@@ -1,10 +1,10 @@
 use millegrilles_common_rust::configuration::ConfigMessages;
-use millegrilles_common_rust::constantes::{DEFAULT_Q_TTL, Securite};
+use millegrilles_common_rust::constantes::{CHAMP_CREATION, CHAMP_MODIFICATION, DEFAULT_Q_TTL, Securite};
 use millegrilles_common_rust::error::Error;
 use millegrilles_common_rust::mongo_dao::{ChampIndex, IndexOptions, MongoDao};
 use millegrilles_common_rust::rabbitmq_dao::{ConfigQueue, ConfigRoutingExchange, QueueType};
 
-use crate::constantes::{COMMANDE_ASSOCIER_IMAGES, COMMANDE_ASSOCIER_VIDEOS, COMMANDE_MARQUER_LU, COMMANDE_POSTER_V1, COMMANDE_SUPPRIMER_MESSAGE, DOMAINE_NOM, QUEUE_VOLATILS_NOM, REQUETE_DECHIFFRER_CLES, REQUETE_MESSAGES_PAR_IDS, REQUETE_RECLAMATIONS, REQUETE_SYNC_MESSAGES, COMMANDE_RECLAMER_FUUIDS, COLLECTION_USAGERS_NOM, COLLECTION_FICHIERS_NOM, COLLECTION_RECEPTION_NOM};
+use crate::constantes::{COMMANDE_ASSOCIER_IMAGES, COMMANDE_ASSOCIER_VIDEOS, COMMANDE_MARQUER_LU, COMMANDE_POSTER_V1, COMMANDE_SUPPRIMER_MESSAGE, DOMAINE_NOM, QUEUE_VOLATILS_NOM, REQUETE_DECHIFFRER_CLES, REQUETE_MESSAGES_PAR_IDS, REQUETE_RECLAMATIONS, REQUETE_SYNC_MESSAGES, COMMANDE_RECLAMER_FUUIDS, COLLECTION_USAGERS_NOM, COLLECTION_FICHIERS_NOM, COLLECTION_RECEPTION_NOM, COMMANDE_DIFFUSER_MESSAGE, COLLECTION_DIFFUSIONS_NOM, COMMANDE_INDEXER_MESSAGE, REQUETE_RECHERCHER_MESSAGES, CHAMP_INDEX_RECHERCHE, REQUETE_ACCES_FICHIERS, COMMANDE_VERIFIER_REGENERATION, COMMANDE_MARQUER_LU_FILTRE, COMMANDE_SUPPRIMER_MESSAGES_FILTRE, REQUETE_COMPTEURS, COLLECTION_LIVRAISONS_NOM, COLLECTION_EVENEMENTS_NOM, COMMANDE_AJOUTER_ABONNEMENT_PUSH, COMMANDE_RETIRER_ABONNEMENT_PUSH, COLLECTION_ABONNEMENTS_PUSH_NOM, COMMANDE_CONFIGURER_TRANSFERT_COURRIEL, COMMANDE_CONFIRMER_TRANSFERT_COURRIEL, COLLECTION_COURRIELS_SORTANTS_NOM, COLLECTION_TRANSFERTS_EN_ATTENTE_NOM};
 
 use crate::domaine_messages::GestionnaireDomaineMessages;
 
@@ -15,13 +15,25 @@ pub fn preparer_queues() -> Vec<QueueType> {
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, REQUETE_DECHIFFRER_CLES), exchange: Securite::L2Prive});
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, REQUETE_SYNC_MESSAGES), exchange: Securite::L2Prive});
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, REQUETE_MESSAGES_PAR_IDS), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, REQUETE_RECHERCHER_MESSAGES), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, REQUETE_ACCES_FICHIERS), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, REQUETE_COMPTEURS), exchange: Securite::L2Prive});
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, REQUETE_RECLAMATIONS), exchange: Securite::L4Secure});
 
     // Commandes
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_POSTER_V1), exchange: Securite::L1Public});
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_MARQUER_LU), exchange: Securite::L2Prive});
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_SUPPRIMER_MESSAGE), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_MARQUER_LU_FILTRE), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_SUPPRIMER_MESSAGES_FILTRE), exchange: Securite::L2Prive});
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_RECLAMER_FUUIDS), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_DIFFUSER_MESSAGE), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_INDEXER_MESSAGE), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_AJOUTER_ABONNEMENT_PUSH), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_RETIRER_ABONNEMENT_PUSH), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_CONFIGURER_TRANSFERT_COURRIEL), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_CONFIRMER_TRANSFERT_COURRIEL), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_VERIFIER_REGENERATION), exchange: Securite::L3Protege});
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_ASSOCIER_IMAGES), exchange: Securite::L3Protege});
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, COMMANDE_ASSOCIER_VIDEOS), exchange: Securite::L3Protege});
 
@@ -79,6 +91,20 @@ pub async fn preparer_index_mongodb_messages<M>(middleware: &M, _gestionnaire: &
         Some(options_fichiers)
     ).await?;
 
+    let options_fichiers_modification = IndexOptions {
+        nom_index: Some(String::from("fichiers_modification")),
+        unique: false,
+    };
+    let champs_index_fichiers_modification = vec!(
+        ChampIndex {nom_champ: String::from(CHAMP_MODIFICATION), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_FICHIERS_NOM,
+        champs_index_fichiers_modification,
+        Some(options_fichiers_modification)
+    ).await?;
+
     let options_reception_message_id = IndexOptions {
         nom_index: Some(String::from("message_id")),
         unique: true,
@@ -107,5 +133,180 @@ pub async fn preparer_index_mongodb_messages<M>(middleware: &M, _gestionnaire: &
         Some(options_reception_user_id)
     ).await?;
 
+    let options_reception_compteurs = IndexOptions {
+        nom_index: Some(String::from("user_id_bucket_lu")),
+        unique: false,
+    };
+    let champs_index_reception_compteurs = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("bucket"), direction: 1},
+        ChampIndex {nom_champ: String::from("lu"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_RECEPTION_NOM,
+        champs_index_reception_compteurs,
+        Some(options_reception_compteurs)
+    ).await?;
+
+    let options_reception_recherche = IndexOptions {
+        nom_index: Some(String::from("user_id_index_recherche")),
+        unique: false,
+    };
+    let champs_index_reception_recherche = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_INDEX_RECHERCHE), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_RECEPTION_NOM,
+        champs_index_reception_recherche,
+        Some(options_reception_recherche)
+    ).await?;
+
+    let options_diffusions = IndexOptions {
+        nom_index: Some(String::from("diffusion_id")),
+        unique: true,
+    };
+    let champs_index_diffusions = vec!(
+        ChampIndex {nom_champ: String::from("diffusion_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_DIFFUSIONS_NOM,
+        champs_index_diffusions,
+        Some(options_diffusions)
+    ).await?;
+
+    let options_livraisons = IndexOptions {
+        nom_index: Some(String::from("post_id_user_id")),
+        unique: true,
+    };
+    let champs_index_livraisons = vec!(
+        ChampIndex {nom_champ: String::from("post_id"), direction: 1},
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_LIVRAISONS_NOM,
+        champs_index_livraisons,
+        Some(options_livraisons)
+    ).await?;
+
+    let options_livraisons_essai = IndexOptions {
+        nom_index: Some(String::from("echec_prochain_essai")),
+        unique: false,
+    };
+    let champs_index_livraisons_essai = vec!(
+        ChampIndex {nom_champ: String::from("echec"), direction: 1},
+        ChampIndex {nom_champ: String::from("prochain_essai"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_LIVRAISONS_NOM,
+        champs_index_livraisons_essai,
+        Some(options_livraisons_essai)
+    ).await?;
+
+    let options_evenements = IndexOptions {
+        nom_index: Some(String::from("evenement_id")),
+        unique: true,
+    };
+    let champs_index_evenements = vec!(
+        ChampIndex {nom_champ: String::from("evenement_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_EVENEMENTS_NOM,
+        champs_index_evenements,
+        Some(options_evenements)
+    ).await?;
+
+    let options_evenements_emis = IndexOptions {
+        nom_index: Some(String::from("emis_creation")),
+        unique: false,
+    };
+    let champs_index_evenements_emis = vec!(
+        ChampIndex {nom_champ: String::from("emis"), direction: 1},
+        ChampIndex {nom_champ: String::from(CHAMP_CREATION), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_EVENEMENTS_NOM,
+        champs_index_evenements_emis,
+        Some(options_evenements_emis)
+    ).await?;
+
+    let options_abonnements_push = IndexOptions {
+        nom_index: Some(String::from("endpoint")),
+        unique: true,
+    };
+    let champs_index_abonnements_push = vec!(
+        ChampIndex {nom_champ: String::from("endpoint"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_ABONNEMENTS_PUSH_NOM,
+        champs_index_abonnements_push,
+        Some(options_abonnements_push)
+    ).await?;
+
+    let options_abonnements_push_usager = IndexOptions {
+        nom_index: Some(String::from("user_id")),
+        unique: false,
+    };
+    let champs_index_abonnements_push_usager = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_ABONNEMENTS_PUSH_NOM,
+        champs_index_abonnements_push_usager,
+        Some(options_abonnements_push_usager)
+    ).await?;
+
+    let options_courriels_sortants = IndexOptions {
+        nom_index: Some(String::from("courriel_id")),
+        unique: true,
+    };
+    let champs_index_courriels_sortants = vec!(
+        ChampIndex {nom_champ: String::from("courriel_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_COURRIELS_SORTANTS_NOM,
+        champs_index_courriels_sortants,
+        Some(options_courriels_sortants)
+    ).await?;
+
+    let options_courriels_sortants_essai = IndexOptions {
+        nom_index: Some(String::from("echec_prochain_essai")),
+        unique: false,
+    };
+    let champs_index_courriels_sortants_essai = vec!(
+        ChampIndex {nom_champ: String::from("echec"), direction: 1},
+        ChampIndex {nom_champ: String::from("prochain_essai"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_COURRIELS_SORTANTS_NOM,
+        champs_index_courriels_sortants_essai,
+        Some(options_courriels_sortants_essai)
+    ).await?;
+
+    let options_transferts_en_attente = IndexOptions {
+        nom_index: Some(String::from("user_id")),
+        unique: true,
+    };
+    let champs_index_transferts_en_attente = vec!(
+        ChampIndex {nom_champ: String::from("user_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        COLLECTION_TRANSFERTS_EN_ATTENTE_NOM,
+        champs_index_transferts_en_attente,
+        Some(options_transferts_en_attente)
+    ).await?;
+
     Ok(())
 }
@@ -4,6 +4,15 @@ pub const COLLECTION_NOM: &str = DOMAINE_NOM;
 pub const COLLECTION_RECEPTION_NOM: &str = "Messages/reception";
 pub const COLLECTION_FICHIERS_NOM: &str = "Messages/fichiers";
 pub const COLLECTION_USAGERS_NOM: &str = "Messages/usagers";
+pub const COLLECTION_DIFFUSIONS_NOM: &str = "Messages/diffusions";
+pub const COLLECTION_CONFIGURATION_NOM: &str = "Messages/configuration";
+pub const COLLECTION_LIVRAISONS_NOM: &str = "Messages/livraisons";
+pub const COLLECTION_EVENEMENTS_NOM: &str = "Messages/evenements";
+pub const COLLECTION_ABONNEMENTS_PUSH_NOM: &str = "Messages/abonnementsPush";
+pub const COLLECTION_COURRIELS_SORTANTS_NOM: &str = "Messages/courrielsSortants";
+pub const COLLECTION_TRANSFERTS_EN_ATTENTE_NOM: &str = "Messages/transfertsCourrielEnAttente";
+
+pub const CONFIG_SYNC_FICHIERS: &str = "syncFichiers";
 
 pub const QUEUE_VOLATILS_NOM: &str = "Messages/volatils";
 pub const QUEUE_TRIGGERS_NOM: &str = "Messages/triggers";
@@ -11,22 +20,83 @@ pub const REQUETE_SYNC_MESSAGES: &str = "syncMessages";
 pub const REQUETE_DECHIFFRER_CLES: &str = "dechiffrerCles";
 pub const REQUETE_MESSAGES_PAR_IDS: &str = "getMessagesParIds";
 pub const REQUETE_RECLAMATIONS: &str = "reclamations";
+pub const REQUETE_RECHERCHER_MESSAGES: &str = "rechercherMessages";
+pub const REQUETE_ACCES_FICHIERS: &str = "getAccesFichiers";
+pub const REQUETE_COMPTEURS: &str = "getCompteurs";
 
 pub const COMMANDE_POSTER_V1: &str = "posterV1";
 pub const COMMANDE_MARQUER_LU: &str = "marquerLu";
 pub const COMMANDE_SUPPRIMER_MESSAGE: &str = "supprimerMessage";
+pub const COMMANDE_MARQUER_LU_FILTRE: &str = "marquerLuFiltre";
+pub const COMMANDE_SUPPRIMER_MESSAGES_FILTRE: &str = "supprimerMessagesFiltre";
 pub const COMMANDE_ASSOCIER_IMAGES: &str = "associerImages";
 pub const COMMANDE_ASSOCIER_VIDEOS: &str = "associerVideos";
 pub const COMMANDE_RECLAMER_FUUIDS: &str = "reclamerFuuids";
+pub const COMMANDE_DIFFUSER_MESSAGE: &str = "diffuserMessage";
+pub const COMMANDE_INDEXER_MESSAGE: &str = "indexerMessage";
+pub const COMMANDE_VERIFIER_REGENERATION: &str = "verifierRegeneration";
+pub const COMMANDE_AJOUTER_ABONNEMENT_PUSH: &str = "ajouterAbonnementPush";
+pub const COMMANDE_RETIRER_ABONNEMENT_PUSH: &str = "retirerAbonnementPush";
+pub const COMMANDE_CONFIGURER_TRANSFERT_COURRIEL: &str = "configurerTransfertCourriel";
+pub const COMMANDE_CONFIRMER_TRANSFERT_COURRIEL: &str = "confirmerTransfertCourriel";
+
+pub const TRANSACTION_ASSIGNER_CLE_USAGER: &str = "assignerCleUsager";
 
 pub const EVENEMENT_NOUVEAU_MESSAGE: &str = "nouveauMessage";
 pub const EVENEMENT_MESSAGE_LU: &str = "messageLu";
 pub const EVENEMENT_MESSAGE_SUPPRIME: &str = "messageSupprime";
+pub const EVENEMENT_DIFFUSION_PROGRES: &str = "diffusionProgres";
+pub const EVENEMENT_COMPTEURS_MAJ: &str = "compteursMaj";
+pub const EVENEMENT_LIVRAISON_ECHEC: &str = "livraisonEchec";
 // pub const EVENEMENT_FICHIERS_SYNCPRET: &str = "syncPret";
 
 
 pub const VERSION_TRANSACTION_MESSAGE_1: u16 = 1;
+pub const VERSION_TRANSACTION_MESSAGE_2: u16 = 2;
 
 
 pub const CHAMP_USER_ID: &str = "user_id";
 pub const CHAMP_MESSAGE_ID: &str = "message_id";
+pub const CHAMP_INDEX_RECHERCHE: &str = "index_recherche";
+
+/// Nombre maximal de tokens de recherche conserves pour un message.
+pub const LIMITE_TOKENS_INDEX_MESSAGE: usize = 1000;
+/// Nombre maximal de tokens dans une requete de recherche.
+pub const LIMITE_TOKENS_RECHERCHE: usize = 50;
+/// Nombre maximal de resultats retournes par une recherche.
+pub const LIMITE_RESULTATS_RECHERCHE: i64 = 100;
+
+/// Nombre de profils traites par batch lors de la rotation des cles.
+pub const LIMITE_ROTATION_CLES_BATCH: i64 = 100;
+
+/// Nombre maximal de messages traites par une transaction d'operation par filtre.
+pub const LIMITE_MESSAGES_FILTRE: i64 = 10000;
+
+/// Valeur par defaut de la taille maximale d'une page de syncMessages.
+pub const LIMITE_SYNC_MESSAGES: i64 = 1000;
+/// Nombre de fuuids par defaut dans une batch transmise au domaine Fichiers.
+pub const LIMITE_FUUIDS_BATCH: usize = 10000;
+/// Nombre maximal de message_ids dans une requete getMessagesParIds.
+pub const LIMITE_MESSAGES_PAR_IDS: usize = 100;
+/// Nombre maximal de message_ids dans une requete getMessagesParIds en mode stream.
+pub const LIMITE_MESSAGES_PAR_IDS_STREAM: usize = 1000;
+/// Taille approximative maximale (bytes) du contenu d'une reponse. Garde une marge sous la
+/// taille maximale d'un message sur le bus.
+pub const TAILLE_MAX_REPONSE: usize = 4 * 1024 * 1024;
+
+/// Taille maximale par defaut du contenu HTML d'un message (bytes).
+pub const TAILLE_MAX_CONTENU_MESSAGE: usize = 1024 * 1024;
+/// Nombre maximal par defaut de fichiers attaches a un message.
+pub const NOMBRE_MAX_FICHIERS_MESSAGE: usize = 100;
+/// Taille chiffree totale maximale par defaut des fichiers attaches a un message (bytes).
+pub const TAILLE_MAX_FICHIERS_MESSAGE: i64 = 5 * 1024 * 1024 * 1024;
+
+/// Nombre maximal de cle_ids dans une requete dechiffrerCles.
+pub const LIMITE_CLE_IDS_DECHIFFRAGE: usize = 100;
+
+/// Duree de validite (secondes) d'une autorisation de telechargement de fichiers.
+pub const DUREE_AUTORISATION_FICHIERS: i64 = 300;
+/// Nombre maximal de fuuids dans une requete accesFichiers.
+pub const LIMITE_FUUIDS_ACCES: usize = 100;
+/// Type d'une autorisation de telechargement de fichiers, verifie par le service de fichiers.
+pub const TYPE_AUTORISATION_FICHIERS: &str = "messages.autorisationFichiers";
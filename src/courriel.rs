@@ -0,0 +1,642 @@
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
+use millegrilles_common_rust::async_trait::async_trait;
+use millegrilles_common_rust::base64::{Engine as _, engine::general_purpose::STANDARD as base64_std};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson::oid::ObjectId;
+use millegrilles_common_rust::certificats::ValidateurX509;
+use millegrilles_common_rust::chrono::{Duration, Utc};
+use millegrilles_common_rust::constantes::{CHAMP_CREATION, CHAMP_MODIFICATION};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::{sauvegarder_traiter_transaction_serializable_v2, Middleware};
+use millegrilles_common_rust::millegrilles_cryptographie::chiffrage_cles::CleChiffrageHandler;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions};
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::tokio;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use lettre::message::header::{ContentType, Header, HeaderName, HeaderValue};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::commandes::{message_id_livraison, MessagePostV1, ProfilUsagerMessages};
+use crate::config::ConfigMessagesDomaine;
+use crate::constantes;
+use crate::constantes::{COLLECTION_COURRIELS_SORTANTS_NOM, COLLECTION_TRANSFERTS_EN_ATTENTE_NOM, DOMAINE_NOM};
+use crate::domaine_messages::GestionnaireDomaineMessages;
+use crate::erreurs::{ErreurMessages, reponse_erreur};
+use crate::livraisons::{charger_message_source, prochain_essai, SourceConservee, SourceLivraison};
+use crate::transactions::TransactionConfigurerTransfertCourriel;
+
+/// Delai maximal (secondes) d'un envoi au relais SMTP.
+const TIMEOUT_SMTP_SECS: u64 = 10;
+/// Nombre de courriels envoyes par passe de thread_courriels_sortants.
+const LIMITE_COURRIELS_BATCH: i64 = 50;
+/// Duree (secondes) du bail d'un courriel reserve par thread_courriels_sortants. Doit couvrir le
+/// dechiffrage du message et l'envoi SMTP.
+const DUREE_BAIL_COURRIEL_SECS: i64 = 120;
+/// Duree de validite (heures) d'un code de confirmation de transfert.
+const DUREE_CONFIRMATION_TRANSFERT_HEURES: i64 = 24;
+/// Nombre de codes invalides avant le retrait de la demande de transfert.
+const ESSAIS_MAX_CONFIRMATION: i64 = 5;
+const LONGUEUR_CODE_CONFIRMATION: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum ModeTransfertCourriel {
+    /// Courriel d'avis sans le contenu du message.
+    Notification,
+    /// Courriel avec le contenu dechiffre du message.
+    Complet,
+}
+
+/// Transfert des messages d'un usager vers une adresse externe.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransfertCourriel {
+    pub adresse: String,
+    pub mode: ModeTransfertCourriel,
+}
+
+/// Transmet un courriel. Permet de remplacer le relais SMTP (ex. par un serveur local de test).
+#[async_trait]
+pub trait EnvoyeurCourriel: Send + Sync {
+    async fn envoyer(&self, destinataire: &str, sujet: &str, contenu_html: String) -> Result<(), Error>;
+}
+
+/// Entete X-Loop ajoute aux courriels envoyes. Un courriel recu avec cette valeur est un
+/// courriel transfere qui revient (boucle de transfert).
+#[derive(Clone)]
+struct XLoop(String);
+
+impl Header for XLoop {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("X-Loop")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self(s.trim().to_string()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), self.0.clone())
+    }
+}
+
+/// Valeur de l'entete X-Loop, l'adresse de l'expediteur des courriels transferes.
+pub fn valeur_x_loop(config: &ConfigMessagesDomaine) -> Option<String> {
+    config.smtp_expediteur.parse::<Mailbox>().ok().map(|m| m.email.to_string())
+}
+
+/// Envoyeur par relais SMTP.
+pub struct EnvoyeurSmtp {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    expediteur: Mailbox,
+}
+
+impl EnvoyeurSmtp {
+    /// Prepare le transport SMTP. Retourne None si le relais n'est pas configure.
+    pub fn charger(config: &ConfigMessagesDomaine) -> Result<Option<Arc<dyn EnvoyeurCourriel>>, Error> {
+        let hote = match config.smtp_hote.as_ref() {
+            Some(inner) => inner,
+            None => {
+                info!("EnvoyeurSmtp.charger Aucun relais SMTP, transfert par courriel desactive");
+                return Ok(None)
+            }
+        };
+
+        let builder = if config.smtp_starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(hote.as_str())
+                .map_err(|e| Error::String(format!("EnvoyeurSmtp.charger Relais {} invalide : {:?}", hote, e)))?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(hote.as_str())
+        };
+        let mut builder = builder
+            .port(config.smtp_port)
+            .timeout(Some(std::time::Duration::from_secs(TIMEOUT_SMTP_SECS)));
+        if let (Some(usager), Some(mot_passe)) = (config.smtp_usager.as_ref(), config.smtp_mot_passe.as_ref()) {
+            builder = builder.credentials(Credentials::new(usager.to_owned(), mot_passe.to_owned()));
+        }
+
+        let expediteur: Mailbox = config.smtp_expediteur.parse()
+            .map_err(|e| Error::String(format!("EnvoyeurSmtp.charger Expediteur {} invalide : {:?}", config.smtp_expediteur, e)))?;
+
+        let envoyeur = Self { transport: builder.build(), expediteur };
+        Ok(Some(Arc::new(envoyeur)))
+    }
+}
+
+#[async_trait]
+impl EnvoyeurCourriel for EnvoyeurSmtp {
+    async fn envoyer(&self, destinataire: &str, sujet: &str, contenu_html: String) -> Result<(), Error> {
+        let destinataire: Mailbox = destinataire.parse()
+            .map_err(|e| Error::String(format!("EnvoyeurSmtp.envoyer Destinataire invalide : {:?}", e)))?;
+        let courriel = Message::builder()
+            .from(self.expediteur.clone())
+            .to(destinataire)
+            .subject(sujet)
+            .header(XLoop(self.expediteur.email.to_string()))
+            .header(ContentType::TEXT_HTML)
+            .body(contenu_html)
+            .map_err(|e| Error::String(format!("EnvoyeurSmtp.envoyer Erreur preparation courriel : {:?}", e)))?;
+        self.transport.send(courriel).await
+            .map_err(|e| Error::String(format!("EnvoyeurSmtp.envoyer Erreur envoi : {:?}", e)))?;
+        Ok(())
+    }
+}
+
+/// Verifie une adresse de transfert. Les adresses du domaine de reception sont refusees, le
+/// message reviendrait a la messagerie.
+fn verifier_adresse_transfert(adresse: &str, domaine_courriel: Option<&str>) -> Result<(), &'static str> {
+    let adresse: Mailbox = match adresse.parse() {
+        Ok(inner) => inner,
+        Err(_) => return Err("Adresse courriel invalide")
+    };
+    if let Some(domaine) = domaine_courriel {
+        if adresse.email.domain().trim_end_matches('.').eq_ignore_ascii_case(domaine.trim_end_matches('.')) {
+            return Err("Adresse du domaine de la messagerie refusee")
+        }
+    }
+    Ok(())
+}
+
+/// Prepare le sujet et le contenu HTML du transfert d'un message. Utilise le message dechiffre
+/// et nettoye.
+fn preparer_transfert(message: &MessagePostV1, mode: ModeTransfertCourriel) -> (String, String) {
+    let auteur = message.auteur.as_ref().map(|a| a.as_str()).unwrap_or("MilleGrilles");
+    let sujet = format!("Nouveau message de {}", auteur);
+    let contenu_html = match mode {
+        ModeTransfertCourriel::Notification => format!(
+            "<p>Vous avez recu un nouveau message de {} dans votre messagerie MilleGrilles.</p>",
+            ammonia::clean_text(auteur)),
+        ModeTransfertCourriel::Complet => {
+            let nombre_fichiers = message.fichiers.as_ref().map(|f| f.len()).unwrap_or(0);
+            match nombre_fichiers {
+                0 => message.contenu.clone(),
+                n => format!("{}<hr><p>{} fichier(s) disponible(s) dans votre messagerie MilleGrilles.</p>",
+                             message.contenu, n)
+            }
+        }
+    };
+    (sujet, contenu_html)
+}
+
+/// Planifie le transfert du post aux destinataires qui ont configure une adresse externe. Seule
+/// la source (chiffree ou courriel recu) est conservee, thread_courriels_sortants recharge le
+/// message au moment de l'envoi. Idempotent si le post est recu a nouveau.
+pub async fn planifier_transferts_courriel<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, post_id: &str,
+                                              source: SourceLivraison<'_>, profils: &Vec<ProfilUsagerMessages>)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    if gestionnaire.envoyeur_courriel.is_none() {
+        return Ok(())
+    }
+    let champs_source = source.champs()?;
+    let collection = middleware.get_collection(COLLECTION_COURRIELS_SORTANTS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    for profil in profils {
+        let transfert = match profil.transfert_courriel.as_ref() {
+            Some(inner) => inner,
+            None => continue
+        };
+        let courriel_id = message_id_livraison(post_id, profil.user_id.as_str());
+        let mut set_on_insert = doc! {
+            "courriel_id": &courriel_id,
+            "user_id": &profil.user_id,
+            "adresse": &transfert.adresse,
+            "mode": convertir_to_bson(transfert.mode)?,
+            "tentatives": 0i64,
+            "prochain_essai": Utc::now(),
+            "echec": false,
+            CHAMP_CREATION: Utc::now(),
+        };
+        set_on_insert.extend(champs_source.clone());
+        let ops = doc! {
+            "$setOnInsert": set_on_insert,
+            "$currentDate": {CHAMP_MODIFICATION: true}
+        };
+        collection.update_one(doc! {"courriel_id": &courriel_id}, ops, options.clone()).await?;
+        debug!("planifier_transferts_courriel Transfert {} planifie", courriel_id);
+    }
+    Ok(())
+}
+
+/// Planifie un courriel deja prepare (ex. confirmation).
+async fn planifier_courriel<M>(middleware: &M, courriel_id: &str, adresse: &str, sujet: &str, contenu_html: &str)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let ops = doc! {
+        "$setOnInsert": {
+            "courriel_id": courriel_id,
+            "adresse": adresse,
+            "sujet": sujet,
+            "contenu_html": contenu_html,
+            "tentatives": 0i64,
+            "prochain_essai": Utc::now(),
+            "echec": false,
+            CHAMP_CREATION: Utc::now(),
+        },
+        "$currentDate": {CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(COLLECTION_COURRIELS_SORTANTS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(doc! {"courriel_id": courriel_id}, ops, options).await?;
+    Ok(())
+}
+
+/// Courriel en attente d'envoi. Le document est retire apres l'envoi.
+#[derive(Deserialize)]
+struct CourrielSortantDb {
+    courriel_id: String,
+    adresse: String,
+    /// Courriel prepare.
+    #[serde(default)]
+    sujet: Option<String>,
+    #[serde(default)]
+    contenu_html: Option<String>,
+    /// Transfert : source du message et mode.
+    #[serde(flatten)]
+    source: SourceConservee,
+    #[serde(default)]
+    mode: Option<ModeTransfertCourriel>,
+    tentatives: i64,
+}
+
+/// Thread d'envoi des courriels sortants (transferts, confirmations). Les envois au relais SMTP
+/// ne ralentissent pas la livraison des messages.
+pub async fn thread_courriels_sortants<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M)
+    where M: Middleware
+{
+    let envoyeur = match gestionnaire.envoyeur_courriel.as_ref() {
+        Some(inner) => inner.as_ref(),
+        None => return std::future::pending().await
+    };
+
+    // Attendre le chargement des certificats de maitre des cles
+    tokio::time::sleep(tokio::time::Duration::from_secs(20)).await;
+
+    loop {
+        if let Err(e) = traiter_courriels_sortants(gestionnaire, middleware, envoyeur).await {
+            error!("thread_courriels_sortants Erreur envoi courriels : {:?}", e);
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+    }
+}
+
+async fn traiter_courriels_sortants<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, envoyeur: &dyn EnvoyeurCourriel)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
+{
+    let collection = middleware.get_collection_typed::<CourrielSortantDb>(COLLECTION_COURRIELS_SORTANTS_NOM)?;
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! {"prochain_essai": 1})
+        .return_document(ReturnDocument::After)
+        .build();
+
+    for _ in 0..LIMITE_COURRIELS_BATCH {
+        if gestionnaire.arret.est_en_arret() {
+            return Ok(())
+        }
+
+        // Reserver le courriel avec un bail, une seule instance du domaine l'envoie
+        let maintenant = Utc::now();
+        let filtre = doc! {"echec": false, "prochain_essai": {"$lte": maintenant}};
+        let ops = doc! {
+            "$set": {"prochain_essai": maintenant + Duration::seconds(DUREE_BAIL_COURRIEL_SECS)},
+            "$currentDate": {CHAMP_MODIFICATION: true}
+        };
+        let courriel = match collection.find_one_and_update(filtre, ops, options.clone()).await? {
+            Some(inner) => inner,
+            None => break
+        };
+
+        let _garde = match gestionnaire.arret.debuter_tache(format!("courriel {}", courriel.courriel_id)) {
+            Some(inner) => inner,
+            None => return Ok(())
+        };
+        let courriel_id = courriel.courriel_id.clone();
+        let tentatives = courriel.tentatives;
+        let resultat = envoyer_courriel_sortant(gestionnaire, middleware, envoyeur, courriel).await;
+        terminer_courriel_sortant(gestionnaire, middleware, courriel_id.as_str(), tentatives, resultat).await?;
+    }
+
+    Ok(())
+}
+
+async fn envoyer_courriel_sortant<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, envoyeur: &dyn EnvoyeurCourriel,
+                                     courriel: CourrielSortantDb)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
+{
+    let (sujet, contenu_html) = match (courriel.sujet, courriel.contenu_html) {
+        (Some(sujet), Some(contenu_html)) => (sujet, contenu_html),
+        _ => {
+            let message = charger_message_source(gestionnaire, middleware, courriel.source).await?;
+            preparer_transfert(&message, courriel.mode.unwrap_or(ModeTransfertCourriel::Notification))
+        }
+    };
+    envoyeur.envoyer(courriel.adresse.as_str(), sujet.as_str(), contenu_html).await
+}
+
+async fn terminer_courriel_sortant<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, courriel_id: &str,
+                                      tentatives: i64, resultat: Result<(), Error>)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(COLLECTION_COURRIELS_SORTANTS_NOM)?;
+    let filtre = doc! {"courriel_id": courriel_id};
+    match resultat {
+        Ok(()) => {
+            debug!("terminer_courriel_sortant Courriel {} envoye", courriel_id);
+            collection.delete_one(filtre, None).await?;
+        },
+        Err(e) => {
+            let tentatives = tentatives + 1;
+            let echec = tentatives >= gestionnaire.config.livraison_tentatives_max;
+            if echec {
+                error!("terminer_courriel_sortant Courriel {} en echec apres {} tentatives : {:?}", courriel_id, tentatives, e);
+            } else {
+                warn!("terminer_courriel_sortant Courriel {} en erreur (tentative {}) : {:?}", courriel_id, tentatives, e);
+            }
+            let ops = doc! {
+                "$set": {
+                    "tentatives": tentatives,
+                    "prochain_essai": prochain_essai(tentatives),
+                    "echec": echec,
+                    "derniere_erreur": format!("{:?}", e),
+                },
+                "$currentDate": {CHAMP_MODIFICATION: true}
+            };
+            collection.update_one(filtre, ops, None).await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ReponseConfigurerTransfertCourriel {
+    ok: bool,
+    /// Un code de confirmation a ete envoye a l'adresse, le transfert est actif apres
+    /// confirmerTransfertCourriel.
+    confirmation_requise: bool,
+}
+
+/// Demande de transfert en attente de confirmation par l'usager.
+#[derive(Deserialize)]
+struct TransfertEnAttenteDb {
+    transfert: TransfertCourriel,
+    /// Hachage du code de confirmation (hacher_code_confirmation), le code n'est pas conserve.
+    #[serde(default)]
+    hachage_code: String,
+    essais: i64,
+}
+
+/// Hachage SHA-256 (base64) du code de confirmation lie a l'usager.
+fn hacher_code_confirmation(user_id: &str, code: &str) -> String {
+    let mut hacheur = Sha256::new();
+    hacheur.update(user_id.as_bytes());
+    hacheur.update(b":");
+    hacheur.update(code.as_bytes());
+    base64_std.encode(hacheur.finalize())
+}
+
+/// Compare le code recu au hachage conserve en temps constant.
+fn verifier_code_confirmation(user_id: &str, code: &str, hachage_code: &str) -> bool {
+    let hachage_recu = hacher_code_confirmation(user_id, code);
+    hachage_recu.as_bytes().ct_eq(hachage_code.as_bytes()).into()
+}
+
+/// Configure le transfert par courriel. Une nouvelle adresse doit etre confirmee avec le code
+/// envoye a cette adresse, le retrait du transfert est immediat.
+pub async fn commande_configurer_transfert_courriel<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    let commande: TransactionConfigurerTransfertCourriel = {
+        let message_ref = message.message.parse()?;
+        message_ref.contenu()?.deserialize()?
+    };
+
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+
+    let collection_en_attente = middleware.get_collection(COLLECTION_TRANSFERTS_EN_ATTENTE_NOM)?;
+    let transfert = match commande.transfert {
+        Some(inner) => inner,
+        None => {
+            collection_en_attente.delete_one(doc! {"user_id": &user_id}, None).await?;
+            let transaction = TransactionConfigurerTransfertCourriel { user_id: Some(user_id), transfert: None };
+            sauvegarder_traiter_transaction_serializable_v2(middleware, &transaction, gestionnaire,
+                DOMAINE_NOM, constantes::COMMANDE_CONFIGURER_TRANSFERT_COURRIEL).await?;
+            let reponse = ReponseConfigurerTransfertCourriel { ok: true, confirmation_requise: false };
+            return Ok(Some(middleware.build_reponse(reponse)?.0))
+        }
+    };
+
+    if gestionnaire.envoyeur_courriel.is_none() {
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::RequeteInvalide, Some("Transfert par courriel non configure"))?))
+    }
+    let domaine_courriel = gestionnaire.config.domaine_courriel.as_ref().map(|d| d.as_str());
+    if let Err(raison) = verifier_adresse_transfert(transfert.adresse.as_str(), domaine_courriel) {
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::RequeteInvalide, Some(raison))?))
+    }
+
+    let code: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(LONGUEUR_CODE_CONFIRMATION)
+        .map(char::from)
+        .collect();
+    let ops = doc! {
+        "$set": {
+            "transfert": convertir_to_bson(&transfert)?,
+            "hachage_code": hacher_code_confirmation(user_id.as_str(), code.as_str()),
+            "essais": 0i64,
+            "expiration": Utc::now() + Duration::hours(DUREE_CONFIRMATION_TRANSFERT_HEURES),
+        },
+        "$setOnInsert": {"user_id": &user_id, CHAMP_CREATION: Utc::now()},
+        "$unset": {"code": true},
+        "$currentDate": {CHAMP_MODIFICATION: true}
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection_en_attente.update_one(doc! {"user_id": &user_id}, ops, options).await?;
+
+    let contenu_html = format!(
+        "<p>Code de confirmation du transfert de vos messages MilleGrilles vers cette adresse : <strong>{}</strong></p>\
+         <p>Le code expire dans {} heures. Ignorez ce courriel si vous n'avez pas fait cette demande.</p>",
+        code, DUREE_CONFIRMATION_TRANSFERT_HEURES);
+    let courriel_id = format!("confirmation:{}:{}", user_id, ObjectId::new().to_hex());
+    planifier_courriel(middleware, courriel_id.as_str(), transfert.adresse.as_str(),
+                       "Confirmation du transfert de vos messages", contenu_html.as_str()).await?;
+    info!("commande_configurer_transfert_courriel Confirmation du transfert envoyee pour usager {}", user_id);
+
+    let reponse = ReponseConfigurerTransfertCourriel { ok: true, confirmation_requise: true };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct CommandeConfirmerTransfertCourriel {
+    code: String,
+}
+
+/// Active le transfert en attente si le code recu par courriel est valide.
+pub async fn commande_confirmer_transfert_courriel<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    let commande: CommandeConfirmerTransfertCourriel = {
+        let message_ref = message.message.parse()?;
+        message_ref.contenu()?.deserialize()?
+    };
+
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+
+    let collection = middleware.get_collection(COLLECTION_TRANSFERTS_EN_ATTENTE_NOM)?;
+    let filtre = doc! {"user_id": &user_id, "expiration": {"$gt": Utc::now()}};
+    let en_attente: TransfertEnAttenteDb = match collection.find_one(filtre, None).await? {
+        Some(inner) => convertir_bson_deserializable(inner)?,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::RequeteInvalide, Some("Aucun transfert en attente de confirmation"))?))
+    };
+
+    if !verifier_code_confirmation(user_id.as_str(), commande.code.trim(), en_attente.hachage_code.as_str()) {
+        if en_attente.essais + 1 >= ESSAIS_MAX_CONFIRMATION {
+            warn!("commande_confirmer_transfert_courriel Trop de codes invalides pour usager {}, demande retiree", user_id);
+            collection.delete_one(doc! {"user_id": &user_id}, None).await?;
+        } else {
+            let ops = doc! {"$inc": {"essais": 1i64}, "$currentDate": {CHAMP_MODIFICATION: true}};
+            collection.update_one(doc! {"user_id": &user_id}, ops, None).await?;
+        }
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Code de confirmation invalide"))?))
+    }
+
+    collection.delete_one(doc! {"user_id": &user_id}, None).await?;
+    let transaction = TransactionConfigurerTransfertCourriel { user_id: Some(user_id), transfert: Some(en_attente.transfert) };
+    sauvegarder_traiter_transaction_serializable_v2(middleware, &transaction, gestionnaire,
+        DOMAINE_NOM, constantes::COMMANDE_CONFIGURER_TRANSFERT_COURRIEL).await?;
+
+    let reponse = ReponseConfigurerTransfertCourriel { ok: true, confirmation_requise: false };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use millegrilles_common_rust::tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use millegrilles_common_rust::tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serveur SMTP minimal qui conserve le contenu DATA d'un seul courriel.
+    async fn puits_smtp(listener: TcpListener) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (lecture, mut ecriture) = stream.into_split();
+        let mut lecture = BufReader::new(lecture);
+        ecriture.write_all(b"220 localhost ESMTP test\r\n").await.unwrap();
+        let mut data = String::new();
+        let mut dans_data = false;
+        loop {
+            let mut ligne = String::new();
+            if lecture.read_line(&mut ligne).await.unwrap() == 0 {
+                return data
+            }
+            if dans_data {
+                if ligne == ".\r\n" {
+                    dans_data = false;
+                    ecriture.write_all(b"250 2.0.0 Ok\r\n").await.unwrap();
+                } else {
+                    data.push_str(ligne.as_str());
+                }
+                continue
+            }
+            let commande = ligne.to_ascii_uppercase();
+            let reponse: &[u8] = if commande.starts_with("EHLO") || commande.starts_with("HELO") {
+                b"250 localhost\r\n"
+            } else if commande.starts_with("DATA") {
+                dans_data = true;
+                b"354 Go\r\n"
+            } else if commande.starts_with("QUIT") {
+                ecriture.write_all(b"221 Bye\r\n").await.unwrap();
+                return data
+            } else {
+                b"250 Ok\r\n"
+            };
+            ecriture.write_all(reponse).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn envoyeur_smtp_puits_local() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let puits = tokio::spawn(puits_smtp(listener));
+
+            let mut config = ConfigMessagesDomaine::default();
+            config.smtp_hote = Some("127.0.0.1".to_string());
+            config.smtp_port = port;
+            config.smtp_starttls = false;
+            config.smtp_expediteur = "Messages <messages@exemple.com>".to_string();
+            let envoyeur = EnvoyeurSmtp::charger(&config).unwrap().expect("envoyeur configure");
+
+            envoyeur.envoyer("usager@externe.com", "Sujet test", "<p>Bonjour</p>".to_string()).await.unwrap();
+            drop(envoyeur);
+
+            let data = tokio::time::timeout(tokio::time::Duration::from_secs(5), puits).await
+                .expect("puits termine").unwrap();
+            assert!(data.contains("Subject: Sujet test"), "{}", data);
+            assert!(data.contains("To: usager@externe.com"), "{}", data);
+            assert!(data.contains("X-Loop: messages@exemple.com"), "{}", data);
+            assert!(data.contains("<p>Bonjour</p>"), "{}", data);
+        });
+    }
+
+    #[test]
+    fn envoyeur_smtp_destinataire_invalide() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let mut config = ConfigMessagesDomaine::default();
+            config.smtp_hote = Some("127.0.0.1".to_string());
+            config.smtp_starttls = false;
+            let envoyeur = EnvoyeurSmtp::charger(&config).unwrap().expect("envoyeur configure");
+            assert!(envoyeur.envoyer("pas une adresse", "Sujet", String::new()).await.is_err());
+        });
+    }
+
+    #[test]
+    fn valeur_x_loop_adresse_expediteur() {
+        let mut config = ConfigMessagesDomaine::default();
+        config.smtp_expediteur = "Messages <messages@exemple.com>".to_string();
+        assert_eq!(Some("messages@exemple.com".to_string()), valeur_x_loop(&config));
+    }
+
+    #[test]
+    fn code_confirmation_hache() {
+        let hachage = hacher_code_confirmation("usager", "AbCd123456");
+        assert!(!hachage.contains("AbCd123456"));
+        assert!(verifier_code_confirmation("usager", "AbCd123456", hachage.as_str()));
+        assert!(!verifier_code_confirmation("usager", "AbCd123457", hachage.as_str()));
+        assert!(!verifier_code_confirmation("autre", "AbCd123456", hachage.as_str()));
+    }
+
+    #[test]
+    fn adresse_transfert_domaine_refuse() {
+        assert!(verifier_adresse_transfert("usager@externe.com", Some("exemple.com")).is_ok());
+        assert!(verifier_adresse_transfert("usager@externe.com", None).is_ok());
+        assert!(verifier_adresse_transfert("usager@exemple.com", Some("exemple.com")).is_err());
+        assert!(verifier_adresse_transfert("usager@EXEMPLE.com", Some("exemple.com.")).is_err());
+        assert!(verifier_adresse_transfert("pas une adresse", Some("exemple.com")).is_err());
+    }
+}
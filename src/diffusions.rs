@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::str::from_utf8;
+
+use log::{debug, error, info, warn};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::constantes::{DELEGATION_GLOBALE_PROPRIETAIRE, DOMAINE_NOM_MAITREDESCOMPTES, Securite, CHAMP_CREATION, CHAMP_MODIFICATION};
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::middleware::Middleware;
+use millegrilles_common_rust::millegrilles_cryptographie::chiffrage_cles::CleChiffrageHandler;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::millegrilles_cryptographie::x25519::CleSecreteX25519;
+use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument, UpdateOptions};
+use millegrilles_common_rust::recepteur_messages::{MessageValide, TypeMessage};
+use millegrilles_common_rust::tokio;
+use serde::{Deserialize, Serialize};
+
+use crate::commandes::{charger_cle_profil, dechiffrer_message_post, generer_cles_profils, message_id_livraison, sauvegarder_message, MessagePostV1, ProfilUsagerMessages};
+use crate::constantes;
+use crate::constantes::{COLLECTION_DIFFUSIONS_NOM, COLLECTION_USAGERS_NOM, DOMAINE_NOM};
+use crate::domaine_messages::GestionnaireDomaineMessages;
+use crate::erreurs::{ErreurMessages, reponse_erreur};
+use crate::livraisons::prochain_essai;
+use crate::validation::valider_message_post;
+
+/// Nombre d'usagers traites entre chaque sauvegarde de progres d'une diffusion.
+pub const LIMITE_USAGERS_DIFFUSION_BATCH: i64 = 50;
+
+#[derive(Serialize)]
+struct ReponseCommandeDiffuser {
+    ok: bool,
+    diffusion_id: String,
+}
+
+/// Commande de diffusion d'un message a tous les usagers. Le message est conserve tel quel
+/// (chiffre) et traite en arriere-plan par thread_diffusions.
+pub async fn commande_diffuser_message<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
+{
+    if !message.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Delegation globale requise"))?))
+    }
+
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+
+    // Verifier que le message peut etre dechiffre et respecte les limites avant de l'accepter
+    let mut message_post = match dechiffrer_message_post(gestionnaire, middleware, &message.message).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::TimeoutServeur, None)?))
+    };
+    if let Err(e) = valider_message_post(&gestionnaire.config.limites, &mut message_post) {
+        debug!("commande_diffuser_message Message refuse : {}", e);
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::ContenuRefuse, Some(e.as_str()))?))
+    }
+
+    let diffusion_id = message.message.parse()?.id.to_string();
+    let message_str = from_utf8(message.message.buffer.as_slice())?;
+
+    let filtre = doc! {"diffusion_id": &diffusion_id};
+    let ops = doc! {
+        "$setOnInsert": {
+            "diffusion_id": &diffusion_id,
+            "user_id": &user_id,
+            "message": message_str,
+            "usagers_synchronises": false,
+            "traites": 0i64,
+            "echecs": 0i64,
+            "tentatives": 0i64,
+            "termine": false,
+            CHAMP_CREATION: Utc::now(),
+        },
+        "$currentDate": {CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(COLLECTION_DIFFUSIONS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, options).await?;
+
+    info!("commande_diffuser_message Diffusion {} ajoutee", diffusion_id);
+    let reponse = ReponseCommandeDiffuser { ok: true, diffusion_id };
+    Ok(Some(middleware.build_reponse(&reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct DiffusionDb {
+    diffusion_id: String,
+    /// User_id du proprietaire qui a demande la diffusion.
+    user_id: String,
+    /// Message original (chiffre) recu avec la commande.
+    message: String,
+    /// Liste des usagers recuperee du maitre des comptes.
+    usagers_synchronises: bool,
+    /// Dernier usager traite, permet de reprendre la diffusion apres un redemarrage.
+    dernier_user_id: Option<String>,
+    traites: i64,
+    /// Usagers sautes (cle de profil non disponible), liste dans usagers_echec.
+    #[serde(default)]
+    echecs: i64,
+    /// Erreurs consecutives de la diffusion, remis a 0 lorsque la diffusion progresse.
+    #[serde(default)]
+    tentatives: i64,
+}
+
+#[derive(Serialize)]
+struct EvenementDiffusionProgres {
+    diffusion_id: String,
+    traites: i64,
+    echecs: i64,
+    termine: bool,
+    /// Diffusion abandonnee apres livraison_tentatives_max erreurs consecutives.
+    echec: bool,
+}
+
+/// Thread de traitement des diffusions en cours. Reprend les diffusions non terminees au
+/// demarrage.
+pub async fn thread_diffusions<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M)
+    where M: Middleware
+{
+    // Attendre le chargement des certificats de maitre des cles
+    tokio::time::sleep(tokio::time::Duration::from_secs(20)).await;
+
+    // Contenu dechiffre des diffusions en cours, evite une requete au maitre des cles a chaque reprise
+    let mut messages = HashMap::new();
+    loop {
+        if let Err(e) = traiter_diffusions(gestionnaire, middleware, &mut messages).await {
+            error!("thread_diffusions Erreur traitement diffusions : {:?}", e);
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(15)).await;
+    }
+}
+
+async fn traiter_diffusions<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, messages: &mut HashMap<String, MessagePostV1>)
+    -> Result<(), Error>
+    where M: Middleware
+{
+    let collection = middleware.get_collection_typed::<DiffusionDb>(COLLECTION_DIFFUSIONS_NOM)?;
+    let options = FindOptions::builder().sort(doc! {CHAMP_CREATION: 1}).build();
+    // Les diffusions en erreur sont reprises avec un backoff (prochain_essai)
+    let filtre = doc! {"termine": false, "prochain_essai": {"$not": {"$gt": Utc::now()}}};
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut diffusions = Vec::new();
+    while curseur.advance().await? {
+        diffusions.push(curseur.deserialize_current()?);
+    }
+
+    for diffusion in diffusions {
+        let diffusion_id = diffusion.diffusion_id.clone();
+        let resultat = traiter_diffusion(gestionnaire, middleware, messages, diffusion).await;
+        match resultat {
+            Ok(true) => {
+                messages.remove(&diffusion_id);
+            },
+            Ok(false) => (),
+            Err(e) => {
+                if reporter_diffusion(gestionnaire, middleware, &diffusion_id, e).await? {
+                    messages.remove(&diffusion_id);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Conserve l'erreur d'une diffusion et planifie sa reprise. La diffusion est abandonnee apres
+/// livraison_tentatives_max erreurs consecutives. Retourne true si la diffusion est abandonnee.
+async fn reporter_diffusion<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, diffusion_id: &String, erreur: Error)
+    -> Result<bool, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let collection = middleware.get_collection_typed::<DiffusionDb>(COLLECTION_DIFFUSIONS_NOM)?;
+    let filtre = doc! {"diffusion_id": diffusion_id};
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let ops = doc! {"$inc": {"tentatives": 1}, "$currentDate": {CHAMP_MODIFICATION: true}};
+    let diffusion = match collection.find_one_and_update(filtre.clone(), ops, options).await? {
+        Some(inner) => inner,
+        None => Err(Error::String(format!("reporter_diffusion Diffusion {} inconnue", diffusion_id)))?
+    };
+
+    if diffusion.tentatives < gestionnaire.config.livraison_tentatives_max {
+        let prochain = prochain_essai(diffusion.tentatives);
+        warn!("reporter_diffusion Diffusion {} interrompue (tentative {}), reprise a {} : {:?}",
+            diffusion_id, diffusion.tentatives, prochain, erreur);
+        let ops = doc! {"$set": {"prochain_essai": prochain}};
+        collection.update_one(filtre, ops, None).await?;
+        return Ok(false)
+    }
+
+    error!("reporter_diffusion Diffusion {} abandonnee apres {} tentatives, {} usagers traites : {:?}",
+        diffusion_id, diffusion.tentatives, diffusion.traites, erreur);
+    let ops = doc! {
+        "$set": {"termine": true, "echec": true},
+        "$currentDate": {CHAMP_MODIFICATION: true}
+    };
+    collection.update_one(filtre, ops, None).await?;
+    let evenement = EvenementDiffusionProgres {
+        diffusion_id: diffusion.diffusion_id, traites: diffusion.traites, echecs: diffusion.echecs, termine: true, echec: true };
+    emettre_progres(middleware, &diffusion.user_id, evenement).await?;
+
+    Ok(true)
+}
+
+/// Dechiffre et valide le message d'une diffusion.
+async fn dechiffrer_diffusion<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, diffusion_id: &String, message: &str)
+    -> Result<MessagePostV1, Error>
+    where M: Middleware
+{
+    let message = MessageMilleGrillesBufferDefault::from(message.as_bytes().to_vec());
+    let mut message_post = match dechiffrer_message_post(gestionnaire, middleware, &message).await? {
+        Some(inner) => inner,
+        None => Err(Error::Str("dechiffrer_diffusion Timeout dechiffrage du message"))?
+    };
+    // Le message conserve est l'original, nettoyer le contenu a nouveau
+    if let Err(e) = valider_message_post(&gestionnaire.config.limites, &mut message_post) {
+        error!("dechiffrer_diffusion Message de la diffusion {} refuse : {}", diffusion_id, e);
+        Err(Error::String(format!("dechiffrer_diffusion Message refuse : {}", e)))?
+    }
+    Ok(message_post)
+}
+
+/// Traite une diffusion jusqu'au dernier usager. Retourne false si la diffusion est interrompue
+/// par un arret.
+async fn traiter_diffusion<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M,
+                              messages: &mut HashMap<String, MessagePostV1>, diffusion: DiffusionDb)
+    -> Result<bool, Error>
+    where M: Middleware
+{
+    let diffusion_id = diffusion.diffusion_id;
+    if !messages.contains_key(&diffusion_id) {
+        let message_post = dechiffrer_diffusion(gestionnaire, middleware, &diffusion_id, diffusion.message.as_str()).await?;
+        messages.insert(diffusion_id.clone(), message_post);
+    }
+    let message_post = match messages.get(&diffusion_id) {
+        Some(inner) => inner,
+        None => Err(Error::Str("traiter_diffusion Message dechiffre absent"))?
+    };
+
+    let collection_diffusions = middleware.get_collection(COLLECTION_DIFFUSIONS_NOM)?;
+    let filtre_diffusion = doc! {"diffusion_id": &diffusion_id};
+
+    if !diffusion.usagers_synchronises {
+        synchroniser_usagers(gestionnaire, middleware).await?;
+        let ops = doc! {
+            "$set": {"usagers_synchronises": true},
+            "$currentDate": {CHAMP_MODIFICATION: true}
+        };
+        collection_diffusions.update_one(filtre_diffusion.clone(), ops, None).await?;
+    }
+
+    let mut dernier_user_id = diffusion.dernier_user_id;
+    let mut traites = diffusion.traites;
+    let mut echecs = diffusion.echecs;
+    let collection_usagers = middleware.get_collection_typed::<ProfilUsagerMessages>(COLLECTION_USAGERS_NOM)?;
+    loop {
+        // Le progres est conserve apres chaque usager, la diffusion reprend au redemarrage
+        let _garde = match gestionnaire.arret.debuter_tache(format!("diffusion {}", diffusion_id)) {
+            Some(inner) => inner,
+            None => {
+                info!("traiter_diffusion Arret en cours, diffusion {} interrompue apres {} usagers", diffusion_id, traites);
+                return Ok(false)
+            }
+        };
+        let filtre = match dernier_user_id.as_ref() {
+            Some(inner) => doc! {"user_id": {"$gt": inner}},
+            None => doc! {}
+        };
+        let options = FindOptions::builder()
+            .sort(doc! {"user_id": 1})
+            .limit(LIMITE_USAGERS_DIFFUSION_BATCH)
+            .build();
+        let mut curseur = collection_usagers.find(filtre, options).await?;
+        let mut profils = Vec::new();
+        while curseur.advance().await? {
+            profils.push(curseur.deserialize_current()?);
+        }
+
+        if profils.is_empty() {
+            break
+        }
+
+        // Une cle de profil non disponible fait sauter l'usager, pas la diffusion
+        let mut cles_chiffrage: HashMap<String, CleSecreteX25519> = HashMap::new();
+        for p in &profils {
+            if let Some(cle_id) = &p.cle_id {
+                match charger_cle_profil(gestionnaire, middleware, cle_id).await {
+                    Ok(cle) => { cles_chiffrage.insert(cle_id.to_owned(), cle); },
+                    Err(e) => warn!("traiter_diffusion Cle {} du profil {} non disponible : {:?}", cle_id, p.user_id, e)
+                }
+            }
+        }
+        generer_cles_profils(gestionnaire, middleware, &mut profils, &mut cles_chiffrage).await?;
+
+        for profil in profils {
+            let ops = match profil.cle_id.as_ref().and_then(|c| cles_chiffrage.remove(c).map(|cle| (c, cle))) {
+                Some((cle_id, cle_secrete)) => {
+                    // Le message_id est deterministe, une reprise ne cree pas de doublon pour l'usager
+                    let message_id = message_id_livraison(diffusion_id.as_str(), profil.user_id.as_str());
+                    sauvegarder_message(gestionnaire, middleware, Some(message_id.as_str()), profil.user_id.as_str(), cle_id,
+                                        cle_secrete, message_post, Some(diffusion.user_id.as_str())).await?;
+                    traites += 1;
+                    doc! {
+                        "$set": {"dernier_user_id": &profil.user_id, "traites": traites, "tentatives": 0i64},
+                        "$currentDate": {CHAMP_MODIFICATION: true}
+                    }
+                },
+                None => {
+                    warn!("traiter_diffusion Diffusion {} : cle de chiffrage manquante pour profil {}, usager saute",
+                        diffusion_id, profil.user_id);
+                    echecs += 1;
+                    doc! {
+                        "$set": {"dernier_user_id": &profil.user_id, "echecs": echecs, "tentatives": 0i64},
+                        "$push": {"usagers_echec": &profil.user_id},
+                        "$currentDate": {CHAMP_MODIFICATION: true}
+                    }
+                }
+            };
+
+            // Conserver le progres apres chaque usager, la reprise debute apres le dernier usager
+            collection_diffusions.update_one(filtre_diffusion.clone(), ops, None).await?;
+            dernier_user_id = Some(profil.user_id);
+        }
+
+        debug!("traiter_diffusion Diffusion {} : {} usagers traites, {} echecs", diffusion_id, traites, echecs);
+        let evenement = EvenementDiffusionProgres {
+            diffusion_id: diffusion_id.clone(), traites, echecs, termine: false, echec: false };
+        emettre_progres(middleware, &diffusion.user_id, evenement).await?;
+    }
+
+    let ops = doc! {
+        "$set": {"termine": true},
+        "$currentDate": {CHAMP_MODIFICATION: true}
+    };
+    collection_diffusions.update_one(filtre_diffusion, ops, None).await?;
+    info!("traiter_diffusion Diffusion {} terminee pour {} usagers, {} echecs", diffusion_id, traites, echecs);
+    let evenement = EvenementDiffusionProgres { diffusion_id, traites, echecs, termine: true, echec: false };
+    emettre_progres(middleware, &diffusion.user_id, evenement).await?;
+
+    Ok(true)
+}
+
+async fn emettre_progres<M>(middleware: &M, user_id: &String, evenement: EvenementDiffusionProgres)
+    -> Result<(), Error>
+    where M: GenerateurMessages
+{
+    let routage = RoutageMessageAction::builder(
+        DOMAINE_NOM, constantes::EVENEMENT_DIFFUSION_PROGRES, vec![Securite::L2Prive])
+        .partition(user_id)
+        .build();
+    middleware.emettre_evenement(routage, evenement).await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct UsagerMaitredescomptes {
+    #[serde(rename="userId")]
+    user_id: String,
+    #[serde(rename="nomUsager")]
+    nom_usager: String,
+}
+
+#[derive(Deserialize)]
+struct ReponseListeUsagersMaitredescomptes {
+    usagers: Vec<UsagerMaitredescomptes>,
+}
+
+/// Ajoute les usagers connus du maitre des comptes dans la collection des profils.
+///
+/// Ces ecritures ne passent pas par une transaction : un profil sans cle_id n'est qu'une copie
+/// de la liste du maitre des comptes, qui reste la source. La cle de chaque usager atteint par la
+/// diffusion est assignee par generer_cles_profils avec la transaction assignerCleUsager (qui
+/// porte aussi nom_usager), le profil est donc recree par la regeneration. Les profils sans cle
+/// perdus lors d'une regeneration sont recopies a la prochaine synchronisation.
+async fn synchroniser_usagers<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM_MAITREDESCOMPTES, "getListeUsagers", vec![Securite::L3Protege])
+        .timeout_blocking(gestionnaire.config.timeout_requete_longue_ms)
+        .build();
+    let reponse = match middleware.transmettre_requete(routage, doc! {}).await? {
+        Some(TypeMessage::Valide(inner)) => inner,
+        _ => Err(Error::Str("synchroniser_usagers Aucune reponse ou mauvais type du maitre des comptes"))?
+    };
+    let reponse_ref = reponse.message.parse()?;
+    let reponse_usagers: ReponseListeUsagersMaitredescomptes = reponse_ref.contenu()?.deserialize()?;
+
+    debug!("synchroniser_usagers {} usagers recus du maitre des comptes", reponse_usagers.usagers.len());
+    let collection = middleware.get_collection(COLLECTION_USAGERS_NOM)?;
+    for usager in reponse_usagers.usagers {
+        let filtre = doc! {"user_id": &usager.user_id};
+        let ops = doc! {
+            "$set": {"nom_usager": &usager.nom_usager},
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection.update_one(filtre, ops, options).await?;
+    }
+
+    Ok(())
+}
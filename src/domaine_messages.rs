@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use log::{debug, info, warn};
 use millegrilles_common_rust::{chrono, tokio};
 use millegrilles_common_rust::async_trait::async_trait;
@@ -17,15 +19,25 @@ use millegrilles_common_rust::rabbitmq_dao::QueueType;
 use millegrilles_common_rust::recepteur_messages::MessageValide;
 use millegrilles_common_rust::static_cell::StaticCell;
 use millegrilles_common_rust::tokio::spawn;
+use millegrilles_common_rust::tokio::sync::{mpsc, Notify};
 use millegrilles_common_rust::tokio::task::JoinHandle;
 use millegrilles_common_rust::tokio_stream::StreamExt;
 
-use crate::commandes::consommer_commande;
+use crate::arret::{attendre_signal_arret, EtatArret};
+use crate::commandes::{consommer_commande, rotation_cles_profils};
+use crate::config::ConfigMessagesDomaine;
 use crate::config_ressources::{preparer_index_mongodb_messages, preparer_queues};
 use crate::constantes as Constantes;
 use crate::constantes::{COLLECTION_FICHIERS_NOM, COLLECTION_NOM, COLLECTION_RECEPTION_NOM, COLLECTION_USAGERS_NOM, DOMAINE_NOM};
+use crate::courriel::{thread_courriels_sortants, EnvoyeurCourriel, EnvoyeurSmtp};
+use crate::diffusions::thread_diffusions;
+use crate::envoi_evenements::thread_envoi_evenements;
 use crate::evenements::consommer_evenement;
+use crate::livraisons::{entretien_livraisons_echec, thread_livraisons};
+use crate::push::{thread_notifications_push, EnvoyeurPush, EnvoyeurWebPush, NotificationPush, TAILLE_FILE_NOTIFICATIONS_PUSH};
+use crate::reception_courriel::thread_reception_courriel;
 use crate::requetes::consommer_requete;
+use crate::sante::{thread_sante, EtatSante};
 use crate::transactions::aiguillage_transaction;
 
 static GESTIONNAIRE: StaticCell<GestionnaireDomaineMessages> = StaticCell::new();
@@ -38,26 +50,57 @@ pub async fn run() {
     let (gestionnaire, futures_domaine) = initialiser(middleware).await
         .expect("initialiser domaine");
 
-    // Tester connexion redis
-    if let Some(redis) = middleware.redis.as_ref() {
-        match redis.liste_certificats_fingerprints().await {
-            Ok(fingerprints_redis) => {
-                info!("redis.liste_certificats_fingerprints Resultat : {} certificats en cache", fingerprints_redis.len());
-            },
-            Err(e) => warn!("redis.liste_certificats_fingerprints Erreur test de connexion redis : {:?}", e)
-        }
-    }
-
     // Combiner les JoinHandles recus
     let mut futures = FuturesUnordered::new();
     futures.extend(futures_middleware);
     futures.extend(futures_domaine);
 
+    // Tester connexion redis periodiquement pour le serveur de sante
+    if let Some(redis) = middleware.redis.as_ref() {
+        futures.push(spawn(async move {
+            loop {
+                match redis.liste_certificats_fingerprints().await {
+                    Ok(fingerprints_redis) => {
+                        debug!("redis.liste_certificats_fingerprints Resultat : {} certificats en cache", fingerprints_redis.len());
+                        gestionnaire.sante.set_redis(true);
+                    },
+                    Err(e) => {
+                        warn!("redis.liste_certificats_fingerprints Erreur test de connexion redis : {:?}", e);
+                        gestionnaire.sante.set_redis(false);
+                    }
+                }
+                tokio::time::sleep(tokio::time::Duration::from_secs(gestionnaire.config.intervalle_certificats_secs)).await;
+            }
+        }));
+    }
+
     // Demarrer thread d'entretien.
     futures.push(spawn(thread_entretien(gestionnaire, middleware)));
+    futures.push(spawn(thread_diffusions(gestionnaire, middleware)));
+    futures.push(spawn(thread_livraisons(gestionnaire, middleware)));
+    futures.push(spawn(thread_courriels_sortants(gestionnaire, middleware)));
+    futures.push(spawn(thread_envoi_evenements(gestionnaire, middleware)));
+    futures.push(spawn(thread_sante(gestionnaire, middleware)));
+    futures.push(spawn(thread_reception_courriel(gestionnaire, middleware)));
+
+    // Le "await" maintien l'application ouverte. Des qu'une task termine ou qu'un signal
+    // d'arret est recu, l'application arrete.
+    tokio::select! {
+        _ = futures.next() => warn!("domaine_messages Une task est terminee, arret de l'application"),
+        nom_signal = attendre_signal_arret() => info!("domaine_messages Signal {} recu, arret de l'application", nom_signal),
+    }
 
-    // Le "await" maintien l'application ouverte. Des qu'une task termine, l'application arrete.
-    futures.next().await;
+    // Arreter la consommation en premier : les consommateurs MQ refusent les nouvelles commandes
+    // et requetes (ArretEnCours) et les threads de travail ne debutent plus de taches.
+    gestionnaire.arret.arreter();
+
+    // Laisser les commandes, requetes et transactions en cours terminer. Les threads du domaine
+    // restent actifs pour emettre les evenements produits par ces taches.
+    let delai_arret = tokio::time::Duration::from_secs(gestionnaire.config.delai_arret_secs);
+    let interrompues = gestionnaire.arret.drainer(delai_arret).await;
+    for tache in interrompues {
+        warn!("domaine_messages Tache interrompue par l'arret : {}", tache);
+    }
 
     for f in &futures {
         f.abort()
@@ -71,11 +114,13 @@ pub async fn run() {
     info!("domaine_messages Fin execution");
 }
 
-async fn thread_entretien<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M)
+async fn thread_entretien<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M)
     where M: Middleware
 {
     let mut prochain_chargement_certificats_maitredescles = Utc::now();
-    let intervalle_chargement_certificats_maitredescles = chrono::Duration::minutes(5);
+    let intervalle_chargement_certificats_maitredescles = chrono::Duration::seconds(gestionnaire.config.intervalle_certificats_secs as i64);
+    let mut prochaine_rotation_cles = Utc::now() + chrono::Duration::seconds(gestionnaire.config.delai_rotation_cles_secs as i64);
+    let intervalle_rotation_cles = chrono::Duration::seconds(gestionnaire.config.intervalle_rotation_cles_secs as i64);
 
     // Attendre 5 secondes pour init bus
     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
@@ -87,16 +132,33 @@ async fn thread_entretien<M>(_gestionnaire: &GestionnaireDomaineMessages, middle
         if prochain_chargement_certificats_maitredescles < maintenant {
             match charger_certificats_chiffrage(middleware).await {
                 Ok(()) => {
+                    gestionnaire.sante.set_chargement_certificats(true);
                     prochain_chargement_certificats_maitredescles = maintenant + intervalle_chargement_certificats_maitredescles;
                     debug!("domaines_core.entretien Prochain chargement cert maitredescles: {:?}", prochain_chargement_certificats_maitredescles);
                 },
-                Err(e) => warn!("domaines_core.entretien Erreur chargement certificats de maitre des cles : {:?}", e)
+                Err(e) => {
+                    warn!("domaines_core.entretien Erreur chargement certificats de maitre des cles : {:?}", e);
+                    gestionnaire.sante.set_chargement_certificats(false);
+                }
             }
 
         }
 
+        if prochaine_rotation_cles < maintenant {
+            match rotation_cles_profils(gestionnaire, middleware).await {
+                Ok(()) => prochaine_rotation_cles = maintenant + intervalle_rotation_cles,
+                Err(e) => warn!("domaines_core.entretien Erreur rotation des cles de profils : {:?}", e)
+            }
+        }
+
+        if let Err(e) = entretien_livraisons_echec(middleware).await {
+            warn!("domaines_core.entretien Erreur entretien des livraisons en echec : {:?}", e);
+        }
+
+        gestionnaire.sante.set_entretien();
+
         // Sleep
-        tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
+        tokio::time::sleep(tokio::time::Duration::from_secs(gestionnaire.config.intervalle_entretien_secs)).await;
     }
 }
 
@@ -105,13 +167,26 @@ async fn thread_entretien<M>(_gestionnaire: &GestionnaireDomaineMessages, middle
 async fn initialiser<M>(middleware: &'static M) -> Result<(&'static GestionnaireDomaineMessages, FuturesUnordered<JoinHandle<()>>), Error>
     where M: Middleware
 {
-    let gestionnaire = GestionnaireDomaineMessages {};
+    let config = ConfigMessagesDomaine::charger()?;
+    let envoyeur_push = EnvoyeurWebPush::charger(&config)?;
+    let envoyeur_courriel = EnvoyeurSmtp::charger(&config)?;
+    let (notifications_push, reception_notifications_push) = mpsc::channel(TAILLE_FILE_NOTIFICATIONS_PUSH);
+    let gestionnaire = GestionnaireDomaineMessages {
+        config,
+        envoyeur_push,
+        notifications_push,
+        envoyeur_courriel,
+        sante: Arc::new(EtatSante::default()),
+        arret: Arc::new(EtatArret::default()),
+        notifier_evenements: Arc::new(Notify::new()),
+    };
     let gestionnaire = GESTIONNAIRE.try_init(gestionnaire)
         .expect("gestionnaire init");
 
     // Preparer la collection avec index
     let futures = gestionnaire.initialiser(middleware).await
         .expect("initialiser");
+    futures.push(spawn(thread_notifications_push(gestionnaire, middleware, reception_notifications_push)));
 
     // Preparer des ressources additionnelles
     preparer_index_mongodb_messages(middleware, gestionnaire).await
@@ -121,7 +196,19 @@ async fn initialiser<M>(middleware: &'static M) -> Result<(&'static Gestionnaire
 }
 
 #[derive(Clone)]
-pub struct GestionnaireDomaineMessages {}
+pub struct GestionnaireDomaineMessages {
+    pub config: ConfigMessagesDomaine,
+    pub sante: Arc<EtatSante>,
+    pub arret: Arc<EtatArret>,
+    /// Reveille thread_envoi_evenements lorsqu'un evenement est ajoute.
+    pub notifier_evenements: Arc<Notify>,
+    /// Envoi des notifications Web Push, None si non configure.
+    pub envoyeur_push: Option<Arc<dyn EnvoyeurPush>>,
+    /// File des notifications push, videe par thread_notifications_push.
+    pub notifications_push: mpsc::Sender<NotificationPush>,
+    /// Transfert des messages par courriel, None si aucun relais SMTP.
+    pub envoyeur_courriel: Option<Arc<dyn EnvoyeurCourriel>>,
+}
 
 #[async_trait]
 impl AiguillageTransactions for GestionnaireDomaineMessages {
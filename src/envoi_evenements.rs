@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+
+use log::{debug, error, info, warn};
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::constantes::{Securite, CHAMP_CREATION, CHAMP_MODIFICATION};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::epochseconds;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::middleware::Middleware;
+use millegrilles_common_rust::mongo_dao::{convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOptions, UpdateOptions};
+use millegrilles_common_rust::tokio;
+use serde::{Deserialize, Serialize};
+
+use crate::compteurs::emettre_compteurs;
+use crate::constantes::{COLLECTION_EVENEMENTS_NOM, DOMAINE_NOM, EVENEMENT_MESSAGE_LU, EVENEMENT_MESSAGE_SUPPRIME, EVENEMENT_NOUVEAU_MESSAGE};
+use crate::domaine_messages::GestionnaireDomaineMessages;
+use crate::livraisons::prochain_essai;
+use crate::push::planifier_notification_push;
+
+/// Nombre d'evenements emis par passe du thread d'envoi.
+const LIMITE_EVENEMENTS_BATCH: i64 = 500;
+/// Evenements qui modifient les compteurs de l'usager, compteursMaj est emis apres la batch.
+const ACTIONS_COMPTEURS: [&str; 3] = [EVENEMENT_NOUVEAU_MESSAGE, EVENEMENT_MESSAGE_LU, EVENEMENT_MESSAGE_SUPPRIME];
+/// Duree de conservation des evenements emis, utilises pour la deduplication. Les transactions
+/// plus anciennes rejouees (regeneration) ne produisent pas d'evenements.
+const DUREE_CONSERVATION_EVENEMENTS_HEURES: i64 = 24;
+
+#[derive(Serialize)]
+pub struct EvenementNouveauMessage {
+    pub evenement_id: String,
+    pub message_id: String,
+    pub user_id: String,
+    /// Information de sync du message, permet aux clients connectes d'afficher le message
+    /// sans requete additionnelle.
+    #[serde(with="epochseconds")]
+    pub date_traitement: DateTime<Utc>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub conversation_id: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub nombre_fichiers: Option<i64>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub taille: Option<i64>,
+    /// Contenu chiffre (DataChiffre) lorsque sa taille est sous le seuil configure.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub message: Option<Document>,
+}
+
+#[derive(Serialize)]
+pub struct EvenementMessages {
+    pub evenement_id: String,
+    pub message_ids: Vec<String>,
+    pub user_id: String,
+}
+
+#[derive(Deserialize)]
+struct EvenementDb {
+    evenement_id: String,
+    action: String,
+    user_id: String,
+    contenu: Document,
+    /// Tentatives d'emission en erreur.
+    #[serde(default)]
+    tentatives: i64,
+}
+
+/// Identificateur de deduplication de l'evenement produit par une transaction.
+pub fn evenement_id(transaction_id: &str, action: &str) -> String {
+    format!("{}:{}", transaction_id, action)
+}
+
+/// Conserve un evenement a emettre pour une transaction. Appele lors du traitement de la
+/// transaction, l'evenement est emis par thread_envoi_evenements. Le contenu doit inclure
+/// l'evenement_id pour permettre la deduplication par les clients.
+pub async fn ajouter_evenement<M,S>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M,
+                                    evenement_id: String, action: &str, user_id: &str,
+                                    estampille: &DateTime<Utc>, contenu: S)
+    -> Result<(), Error>
+    where M: MongoDao, S: Serialize
+{
+    if *estampille < Utc::now() - Duration::hours(DUREE_CONSERVATION_EVENEMENTS_HEURES) {
+        debug!("ajouter_evenement Transaction rejouee, evenement {} ignore", evenement_id);
+        return Ok(())
+    }
+
+    let filtre = doc! {"evenement_id": &evenement_id};
+    let ops = doc! {
+        "$setOnInsert": {
+            "evenement_id": &evenement_id,
+            "action": action,
+            "user_id": user_id,
+            "contenu": convertir_to_bson(contenu)?,
+            "emis": false,
+            "tentatives": 0i64,
+            CHAMP_CREATION: Utc::now(),
+        },
+        "$currentDate": {CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(COLLECTION_EVENEMENTS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, options).await?;
+
+    gestionnaire.notifier_evenements.notify_one();
+
+    Ok(())
+}
+
+/// Thread d'emission des evenements en attente. Les evenements sont emis au moins une fois,
+/// dans l'ordre de creation. Un evenement en erreur est reessaye avec un backoff, puis mis de
+/// cote (echec) apres livraison_tentatives_max tentatives.
+pub async fn thread_envoi_evenements<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M)
+    where M: Middleware
+{
+    let mut prochain_nettoyage = Utc::now();
+    loop {
+        if let Err(e) = envoyer_evenements(gestionnaire, middleware).await {
+            error!("thread_envoi_evenements Erreur emission evenements : {:?}", e);
+        }
+
+        if prochain_nettoyage < Utc::now() {
+            match nettoyer_evenements(middleware).await {
+                Ok(()) => prochain_nettoyage = Utc::now() + Duration::hours(1),
+                Err(e) => error!("thread_envoi_evenements Erreur nettoyage evenements : {:?}", e)
+            }
+        }
+
+        tokio::select! {
+            _ = gestionnaire.notifier_evenements.notified() => (),
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(5)) => (),
+        }
+    }
+}
+
+async fn envoyer_evenements<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let collection = middleware.get_collection_typed::<EvenementDb>(COLLECTION_EVENEMENTS_NOM)?;
+    let options = FindOptions::builder()
+        .sort(doc! {CHAMP_CREATION: 1})
+        .limit(LIMITE_EVENEMENTS_BATCH)
+        .build();
+    let filtre = doc! {"emis": false, "echec": {"$ne": true}, "prochain_essai": {"$not": {"$gt": Utc::now()}}};
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut evenements = Vec::new();
+    while curseur.advance().await? {
+        evenements.push(curseur.deserialize_current()?);
+    }
+
+    let collection = middleware.get_collection(COLLECTION_EVENEMENTS_NOM)?;
+    let mut usagers_compteurs = HashSet::new();
+    let mut erreur = None;
+    for evenement in evenements {
+        let routage = RoutageMessageAction::builder(DOMAINE_NOM, evenement.action.as_str(), vec![Securite::L2Prive])
+            .partition(evenement.user_id.as_str())
+            .build();
+        // Arreter a la premiere erreur, l'evenement est reessaye apres son backoff
+        if let Err(e) = middleware.emettre_evenement(routage, &evenement.contenu).await {
+            if let Err(e) = reporter_evenement(gestionnaire, middleware, &evenement).await {
+                error!("envoyer_evenements Erreur conservation tentative {} : {:?}", evenement.evenement_id, e);
+            }
+            erreur = Some(e);
+            break
+        }
+
+        let ops = doc! {
+            "$set": {"emis": true, "date_emission": Utc::now()},
+            "$currentDate": {CHAMP_MODIFICATION: true}
+        };
+        if let Err(e) = collection.update_one(doc! {"evenement_id": &evenement.evenement_id}, ops, None).await {
+            erreur = Some(e.into());
+            break
+        }
+
+        if ACTIONS_COMPTEURS.contains(&evenement.action.as_str()) {
+            usagers_compteurs.insert(evenement.user_id.clone());
+        }
+
+        // Notification push envoyee par thread_notifications_push, n'attend pas le service push
+        if evenement.action.as_str() == EVENEMENT_NOUVEAU_MESSAGE {
+            if let Ok(message_id) = evenement.contenu.get_str("message_id") {
+                planifier_notification_push(gestionnaire, evenement.user_id.as_str(), message_id);
+            }
+        }
+    }
+
+    // Les compteurs sont calcules une seule fois par usager pour la batch (ex. diffusion)
+    for user_id in usagers_compteurs {
+        if let Err(e) = emettre_compteurs(middleware, user_id.as_str()).await {
+            warn!("envoyer_evenements Erreur emission compteurs {} : {:?}", user_id, e);
+        }
+    }
+
+    match erreur {
+        Some(e) => Err(e),
+        None => Ok(())
+    }
+}
+
+/// Conserve une tentative d'emission en erreur et planifie le reessai. L'evenement est mis de
+/// cote (echec) apres livraison_tentatives_max tentatives pour ne pas bloquer les suivants.
+async fn reporter_evenement<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, evenement: &EvenementDb)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let tentatives = evenement.tentatives + 1;
+    let echec = tentatives >= gestionnaire.config.livraison_tentatives_max;
+    if echec {
+        error!("reporter_evenement Evenement {} ({}) abandonne apres {} tentatives",
+            evenement.evenement_id, evenement.action, tentatives);
+    } else {
+        warn!("reporter_evenement Erreur emission evenement {} (tentative {}), reessai planifie",
+            evenement.evenement_id, tentatives);
+    }
+    let ops = doc! {
+        "$set": {"tentatives": tentatives, "prochain_essai": prochain_essai(tentatives), "echec": echec},
+        "$currentDate": {CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(COLLECTION_EVENEMENTS_NOM)?;
+    collection.update_one(doc! {"evenement_id": &evenement.evenement_id}, ops, None).await?;
+    Ok(())
+}
+
+/// Retire les evenements emis qui ne sont plus utiles pour la deduplication et les evenements
+/// abandonnes (echec) apres la meme duree.
+async fn nettoyer_evenements<M>(middleware: &M) -> Result<(), Error>
+    where M: MongoDao
+{
+    let date_limite = Utc::now() - Duration::hours(DUREE_CONSERVATION_EVENEMENTS_HEURES);
+    let filtre = doc! {"$or": [
+        {"emis": true, "date_emission": {"$lt": date_limite}},
+        {"echec": true, CHAMP_MODIFICATION: {"$lt": date_limite}}
+    ]};
+    let collection = middleware.get_collection(COLLECTION_EVENEMENTS_NOM)?;
+    let resultat = collection.delete_many(filtre, None).await?;
+    if resultat.deleted_count > 0 {
+        info!("nettoyer_evenements {} evenements emis ou abandonnes retires", resultat.deleted_count);
+    }
+    Ok(())
+}
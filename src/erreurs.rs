@@ -0,0 +1,57 @@
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use serde::Serialize;
+
+/// Codes d'erreur du domaine Messages. Les valeurs numeriques sont stables, elles sont utilisees
+/// par les applications clientes. Les codes 1 et 3 proviennent de la premiere version de posterV1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErreurMessages {
+    DestinatairesInconnus = 1,
+    TimeoutServeur = 3,
+    RequeteInvalide = 400,
+    AccesRefuse = 403,
+    MessageInconnu = 404,
+    LimiteDepassee = 413,
+    ContenuRefuse = 422,
+    ErreurInterne = 500,
+    ArretEnCours = 503,
+}
+
+impl ErreurMessages {
+    pub fn code(&self) -> usize {
+        *self as usize
+    }
+
+    /// Raison lisible par une machine (ne change pas entre les versions).
+    pub fn raison(&self) -> &'static str {
+        match self {
+            ErreurMessages::DestinatairesInconnus => "destinataires_inconnus",
+            ErreurMessages::TimeoutServeur => "timeout_serveur",
+            ErreurMessages::RequeteInvalide => "requete_invalide",
+            ErreurMessages::AccesRefuse => "acces_refuse",
+            ErreurMessages::MessageInconnu => "message_inconnu",
+            ErreurMessages::LimiteDepassee => "limite_depassee",
+            ErreurMessages::ContenuRefuse => "contenu_refuse",
+            ErreurMessages::ErreurInterne => "erreur_interne",
+            ErreurMessages::ArretEnCours => "arret_en_cours",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReponseErreurMessages<'a> {
+    ok: bool,
+    code: usize,
+    raison: &'static str,
+    err: Option<&'a str>,
+}
+
+/// Prepare une reponse d'erreur avec le code numerique et la raison de l'erreur.
+pub fn reponse_erreur<M>(middleware: &M, erreur: ErreurMessages, err: Option<&str>)
+    -> Result<MessageMilleGrillesBufferDefault, Error>
+    where M: GenerateurMessages
+{
+    let reponse = ReponseErreurMessages { ok: false, code: erreur.code(), raison: erreur.raison(), err };
+    Ok(middleware.build_reponse(reponse)?.0)
+}
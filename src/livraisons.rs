@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+
+use log::{debug, error, info, warn};
+use millegrilles_common_rust::base64::{Engine as _, engine::general_purpose::STANDARD as base64_std};
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::bson::oid::ObjectId;
+use millegrilles_common_rust::certificats::ValidateurX509;
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::constantes::{CHAMP_CREATION, CHAMP_MODIFICATION};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::Middleware;
+use millegrilles_common_rust::millegrilles_cryptographie::chiffrage_cles::CleChiffrageHandler;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::millegrilles_cryptographie::x25519::CleSecreteX25519;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions};
+use millegrilles_common_rust::tokio;
+use serde::{Deserialize, Serialize};
+
+use crate::commandes::{charger_cle_profil, dechiffrer_message_post, generer_cles_profils, message_id_livraison, sauvegarder_message, MessagePostV1, ProfilUsagerMessages};
+use crate::constantes::{COLLECTION_LIVRAISONS_NOM, COLLECTION_USAGERS_NOM, EVENEMENT_LIVRAISON_ECHEC};
+use crate::domaine_messages::GestionnaireDomaineMessages;
+use crate::envoi_evenements::{ajouter_evenement, evenement_id};
+use crate::metriques::metriques;
+use crate::reception_courriel::convertir_courriel;
+use crate::validation::valider_message_post;
+
+/// Nombre de livraisons traitees par passe de thread_livraisons.
+const LIMITE_LIVRAISONS_BATCH: i64 = 100;
+/// Delai (secondes) avant le premier reessai, double a chaque tentative.
+const DELAI_REESSAI_INITIAL_SECS: i64 = 5;
+/// Delai maximal (secondes) entre deux tentatives.
+const DELAI_REESSAI_MAX_SECS: i64 = 3600;
+/// Delai (secondes) reserve a la livraison directe par la commande avant que thread_livraisons
+/// ne prenne la livraison en charge.
+const DELAI_LIVRAISON_DIRECTE_SECS: i64 = 60;
+/// Duree (secondes) du bail d'une livraison reservee par thread_livraisons. Une livraison
+/// interrompue (arret, crash) redevient disponible a la fin du bail.
+const DUREE_BAIL_LIVRAISON_SECS: i64 = 300;
+/// Duree de conservation (jours) des livraisons en echec, consultables pour diagnostic.
+const DUREE_CONSERVATION_ECHECS_JOURS: i64 = 30;
+
+/// Livraison d'un message (post) a un destinataire. Le document est retire lorsque le message
+/// est sauvegarde pour le destinataire.
+#[derive(Deserialize)]
+struct LivraisonDb {
+    /// Id du message posterV1 original.
+    post_id: String,
+    user_id: String,
+    #[serde(flatten)]
+    source: SourceConservee,
+    expediteur: Option<String>,
+    tentatives: i64,
+}
+
+/// Evenement emis a l'expediteur lorsqu'une livraison est abandonnee.
+#[derive(Serialize)]
+struct EvenementLivraisonEchec<'a> {
+    evenement_id: String,
+    post_id: &'a str,
+    /// User_id du destinataire en echec.
+    destinataire: &'a str,
+    tentatives: i64,
+}
+
+/// Source d'un message a livrer, conservee pour reprendre la livraison.
+#[derive(Clone, Copy)]
+pub enum SourceLivraison<'a> {
+    /// Commande posterV1 (chiffree).
+    Message(&'a MessageMilleGrillesBufferDefault),
+    /// Courriel (RFC 5322) recu par reception_courriel et destinataires de l'enveloppe SMTP.
+    Courriel(&'a [u8], &'a Vec<String>),
+}
+
+impl<'a> SourceLivraison<'a> {
+    /// Champs conserves dans le document pour recharger le message (voir SourceConservee).
+    pub fn champs(&self) -> Result<Document, Error> {
+        match *self {
+            SourceLivraison::Message(message) => Ok(doc! {"message": std::str::from_utf8(message.buffer.as_slice())?}),
+            // Un courriel n'est pas necessairement UTF-8, le contenu est conserve tel quel
+            SourceLivraison::Courriel(courriel, enveloppe) => Ok(doc! {
+                "courriel": base64_std.encode(courriel),
+                "enveloppe": enveloppe.clone(),
+            })
+        }
+    }
+}
+
+/// Source conservee avec une livraison ou un courriel sortant (voir SourceLivraison).
+#[derive(Deserialize)]
+pub struct SourceConservee {
+    /// Message original (chiffre) recu avec la commande.
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Courriel original (RFC 5322, base64) recu par reception_courriel.
+    #[serde(default)]
+    pub courriel: Option<String>,
+    /// Destinataires (noms d'usagers) de l'enveloppe SMTP du courriel.
+    #[serde(default)]
+    pub enveloppe: Option<Vec<String>>,
+}
+
+/// Recharge le message d'une source conservee et nettoie son contenu. Le message conserve est
+/// l'original, il doit etre valide a nouveau.
+pub async fn charger_message_source<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, source: SourceConservee)
+    -> Result<MessagePostV1, Error>
+    where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
+{
+    let mut message_post = match (source.message, source.courriel) {
+        (Some(message), _) => {
+            let message = MessageMilleGrillesBufferDefault::from(message.into_bytes());
+            match dechiffrer_message_post(gestionnaire, middleware, &message).await? {
+                Some(inner) => inner,
+                None => Err(Error::Str("charger_message_source Timeout dechiffrage du message"))?
+            }
+        },
+        (None, Some(courriel)) => {
+            let courriel = base64_std.decode(courriel.as_str())
+                .map_err(|e| Error::String(format!("charger_message_source Courriel base64 invalide : {:?}", e)))?;
+            convertir_courriel(courriel.as_slice(), source.enveloppe.unwrap_or_default())
+                .map_err(|e| Error::String(format!("charger_message_source Courriel refuse : {:?}", e)))?
+        },
+        (None, None) => Err(Error::Str("charger_message_source Aucun message source"))?
+    };
+    valider_message_post(&gestionnaire.config.limites, &mut message_post)
+        .map_err(|e| Error::String(format!("charger_message_source Message refuse : {}", e)))?;
+    Ok(message_post)
+}
+
+/// Cree une livraison pour chaque destinataire. Idempotent si la commande est recue a nouveau.
+pub async fn creer_livraisons<M>(middleware: &M, source: SourceLivraison<'_>,
+                                 expediteur: Option<&str>, user_ids: &Vec<&str>)
+    -> Result<String, Error>
+    where M: MongoDao
+{
+    let champs_source = source.champs()?;
+    let post_id = match source {
+        SourceLivraison::Message(message) => message.parse()?.id.to_string(),
+        // Un courriel n'a pas d'identificateur fiable, chaque reception est un nouveau post
+        SourceLivraison::Courriel(..) => format!("courriel:{}", ObjectId::new().to_hex())
+    };
+    let collection = middleware.get_collection(COLLECTION_LIVRAISONS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    for user_id in user_ids {
+        let filtre = doc! {"post_id": &post_id, "user_id": user_id};
+        let mut set_on_insert = doc! {
+            "post_id": &post_id,
+            "user_id": user_id,
+            "expediteur": expediteur,
+            "tentatives": 0i64,
+            "prochain_essai": Utc::now() + Duration::seconds(DELAI_LIVRAISON_DIRECTE_SECS),
+            "echec": false,
+            CHAMP_CREATION: Utc::now(),
+        };
+        set_on_insert.extend(champs_source.clone());
+        let ops = doc! {
+            "$setOnInsert": set_on_insert,
+            "$currentDate": {CHAMP_MODIFICATION: true}
+        };
+        collection.update_one(filtre, ops, options.clone()).await?;
+    }
+    Ok(post_id)
+}
+
+/// Sauvegarde le message pour le destinataire et retire la livraison. En cas d'erreur, la
+/// livraison est conservee et un reessai est planifie.
+pub async fn livrer<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, post_id: &str, user_id: &str,
+                       cle_id: &str, cle_secrete: CleSecreteX25519, message: &MessagePostV1, expediteur: Option<&str>)
+    -> Result<(), Error>
+    where M: GenerateurMessages + ValidateurX509 + MongoDao
+{
+    let message_id = message_id_livraison(post_id, user_id);
+    let resultat = sauvegarder_message(gestionnaire, middleware, Some(message_id.as_str()), user_id, cle_id,
+                                       cle_secrete, message, expediteur).await;
+    terminer_livraison(gestionnaire, middleware, post_id, user_id, resultat).await
+}
+
+/// Retire la livraison completee ou planifie un reessai selon le resultat.
+pub async fn terminer_livraison<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, post_id: &str, user_id: &str,
+                                   resultat: Result<(), Error>)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(COLLECTION_LIVRAISONS_NOM)?;
+    let filtre = doc! {"post_id": post_id, "user_id": user_id};
+    match resultat {
+        Ok(()) => {
+            collection.delete_one(filtre, None).await?;
+        },
+        Err(e) => {
+            let livraison: Option<LivraisonDb> = match collection.find_one(filtre.clone(), None).await? {
+                Some(inner) => Some(convertir_bson_deserializable(inner)?),
+                None => None
+            };
+            let tentatives = livraison.as_ref().map(|l| l.tentatives).unwrap_or(0) + 1;
+            let echec = tentatives >= gestionnaire.config.livraison_tentatives_max;
+            if echec {
+                error!("terminer_livraison Livraison {} pour {} en echec apres {} tentatives : {:?}", post_id, user_id, tentatives, e);
+                // Un expediteur local est avise, un courriel n'a pas d'expediteur a aviser
+                if let Some(expediteur) = livraison.as_ref().and_then(|l| l.expediteur.as_ref()) {
+                    let evenement_id = evenement_id(message_id_livraison(post_id, user_id).as_str(), EVENEMENT_LIVRAISON_ECHEC);
+                    let evenement = EvenementLivraisonEchec {
+                        evenement_id: evenement_id.clone(), post_id, destinataire: user_id, tentatives,
+                    };
+                    ajouter_evenement(gestionnaire, middleware, evenement_id, EVENEMENT_LIVRAISON_ECHEC,
+                                      expediteur.as_str(), &Utc::now(), evenement).await?;
+                }
+            } else {
+                warn!("terminer_livraison Erreur livraison {} pour {} (tentative {}), reessai planifie : {:?}", post_id, user_id, tentatives, e);
+            }
+            let ops = doc! {
+                "$set": {
+                    "tentatives": tentatives,
+                    "prochain_essai": prochain_essai(tentatives),
+                    "echec": echec,
+                    "derniere_erreur": format!("{:?}", e),
+                },
+                "$currentDate": {CHAMP_MODIFICATION: true}
+            };
+            collection.update_one(filtre, ops, None).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Retire les livraisons en echec depuis plus de DUREE_CONSERVATION_ECHECS_JOURS et met a jour
+/// la metrique des livraisons en echec. Appele par thread_entretien.
+pub async fn entretien_livraisons_echec<M>(middleware: &M) -> Result<(), Error>
+    where M: MongoDao
+{
+    let collection = middleware.get_collection(COLLECTION_LIVRAISONS_NOM)?;
+    let date_limite = Utc::now() - Duration::days(DUREE_CONSERVATION_ECHECS_JOURS);
+    let filtre = doc! {"echec": true, CHAMP_MODIFICATION: {"$lt": date_limite}};
+    let resultat = collection.delete_many(filtre, None).await?;
+    if resultat.deleted_count > 0 {
+        info!("entretien_livraisons_echec {} livraisons en echec retirees", resultat.deleted_count);
+    }
+
+    let echecs = collection.count_documents(doc! {"echec": true}, None).await?;
+    metriques().livraisons_echec.set(echecs as i64);
+
+    Ok(())
+}
+
+/// Backoff exponentiel a partir du nombre de tentatives.
+pub fn prochain_essai(tentatives: i64) -> DateTime<Utc> {
+    let exposant = (tentatives - 1).clamp(0, 20) as u32;
+    let delai = (DELAI_REESSAI_INITIAL_SECS * 2i64.pow(exposant)).min(DELAI_REESSAI_MAX_SECS);
+    Utc::now() + Duration::seconds(delai)
+}
+
+/// Thread de reessai des livraisons en attente. Reprend les livraisons non completees au
+/// demarrage.
+pub async fn thread_livraisons<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M)
+    where M: Middleware
+{
+    // Attendre le chargement des certificats de maitre des cles
+    tokio::time::sleep(tokio::time::Duration::from_secs(20)).await;
+
+    loop {
+        if let Err(e) = traiter_livraisons(gestionnaire, middleware).await {
+            error!("thread_livraisons Erreur traitement livraisons : {:?}", e);
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+    }
+}
+
+async fn traiter_livraisons<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
+{
+    let collection = middleware.get_collection_typed::<LivraisonDb>(COLLECTION_LIVRAISONS_NOM)?;
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! {"prochain_essai": 1})
+        .return_document(ReturnDocument::After)
+        .build();
+
+    // Les destinataires d'un meme post partagent le message dechiffre
+    let mut messages: HashMap<String, MessagePostV1> = HashMap::new();
+    for _ in 0..LIMITE_LIVRAISONS_BATCH {
+        if gestionnaire.arret.est_en_arret() {
+            return Ok(())
+        }
+
+        // Reserver la livraison avec un bail, une seule instance du domaine la traite
+        let maintenant = Utc::now();
+        let filtre = doc! {"echec": false, "prochain_essai": {"$lte": maintenant}};
+        let ops = doc! {
+            "$set": {"prochain_essai": maintenant + Duration::seconds(DUREE_BAIL_LIVRAISON_SECS)},
+            "$currentDate": {CHAMP_MODIFICATION: true}
+        };
+        let livraison = match collection.find_one_and_update(filtre, ops, options.clone()).await? {
+            Some(inner) => inner,
+            None => break
+        };
+        debug!("traiter_livraisons Livraison {} pour {} reservee", livraison.post_id, livraison.user_id);
+
+        // Si l'arret debute apres la reservation, la livraison reprend a la fin du bail
+        let _garde = match gestionnaire.arret.debuter_tache(format!("livraison {}", livraison.post_id)) {
+            Some(inner) => inner,
+            None => return Ok(())
+        };
+        let post_id = livraison.post_id.clone();
+        let user_id = livraison.user_id.clone();
+        let resultat = traiter_livraison(gestionnaire, middleware, &mut messages, livraison).await;
+        if resultat.is_ok() {
+            info!("traiter_livraisons Livraison {} pour {} completee", post_id, user_id);
+        }
+        terminer_livraison(gestionnaire, middleware, post_id.as_str(), user_id.as_str(), resultat).await?;
+    }
+
+    Ok(())
+}
+
+async fn traiter_livraison<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M,
+                              messages: &mut HashMap<String, MessagePostV1>, livraison: LivraisonDb)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao + CleChiffrageHandler + ValidateurX509
+{
+    if !messages.contains_key(&livraison.post_id) {
+        let message_post = charger_message_source(gestionnaire, middleware, livraison.source).await?;
+        messages.insert(livraison.post_id.clone(), message_post);
+    }
+    let message_post = match messages.get(&livraison.post_id) {
+        Some(inner) => inner,
+        None => Err(Error::Str("traiter_livraison Message du post non charge"))?
+    };
+
+    let collection_usagers = middleware.get_collection_typed::<ProfilUsagerMessages>(COLLECTION_USAGERS_NOM)?;
+    let profil = match collection_usagers.find_one(doc! {"user_id": &livraison.user_id}, None).await? {
+        Some(inner) => inner,
+        None => Err(Error::Str("traiter_livraison Profil usager inconnu"))?
+    };
+
+    let mut cles_chiffrage = HashMap::new();
+    let cle_id = match profil.cle_id.as_ref() {
+        Some(cle_id) => {
+            let cle = charger_cle_profil(gestionnaire, middleware, cle_id).await?;
+            cles_chiffrage.insert(cle_id.to_owned(), cle);
+            cle_id.to_owned()
+        },
+        None => {
+            let mut profils = vec![profil];
+            generer_cles_profils(gestionnaire, middleware, &mut profils, &mut cles_chiffrage).await?;
+            match profils.remove(0).cle_id {
+                Some(inner) => inner,
+                None => Err(Error::Str("traiter_livraison Cle de profil non generee"))?
+            }
+        }
+    };
+    let cle_secrete = match cles_chiffrage.remove(&cle_id) {
+        Some(inner) => inner,
+        None => Err(Error::Str("traiter_livraison Cle de chiffrage manquante"))?
+    };
+
+    let message_id = message_id_livraison(livraison.post_id.as_str(), livraison.user_id.as_str());
+    sauvegarder_message(gestionnaire, middleware, Some(message_id.as_str()), livraison.user_id.as_str(), cle_id,
+                        cle_secrete, message_post, livraison.expediteur.as_ref().map(|e| e.as_str())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delai_secs(tentatives: i64) -> i64 {
+        (prochain_essai(tentatives) - Utc::now()).num_seconds()
+    }
+
+    #[test]
+    fn prochain_essai_double_le_delai() {
+        assert!((DELAI_REESSAI_INITIAL_SECS - 1..=DELAI_REESSAI_INITIAL_SECS).contains(&delai_secs(1)));
+        assert!((DELAI_REESSAI_INITIAL_SECS * 2 - 1..=DELAI_REESSAI_INITIAL_SECS * 2).contains(&delai_secs(2)));
+        assert!((DELAI_REESSAI_INITIAL_SECS * 8 - 1..=DELAI_REESSAI_INITIAL_SECS * 8).contains(&delai_secs(4)));
+    }
+
+    #[test]
+    fn prochain_essai_borne() {
+        // Une valeur de tentatives invalide ne doit pas produire un delai negatif
+        assert!(delai_secs(0) >= 0);
+        assert!(delai_secs(-3) >= 0);
+        // Le delai plafonne sans debordement pour un grand nombre de tentatives
+        assert!(delai_secs(50) <= DELAI_REESSAI_MAX_SECS);
+        assert!(delai_secs(i64::MAX) <= DELAI_REESSAI_MAX_SECS);
+        assert!(delai_secs(50) >= DELAI_REESSAI_MAX_SECS - 1);
+    }
+}
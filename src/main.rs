@@ -11,6 +11,20 @@ mod requetes;
 mod evenements;
 mod transactions;
 mod structures_messages;
+mod diffusions;
+mod regeneration;
+mod erreurs;
+mod compteurs;
+mod validation;
+mod config;
+mod sante;
+mod metriques;
+mod arret;
+mod livraisons;
+mod envoi_evenements;
+mod push;
+mod courriel;
+mod reception_courriel;
 
 fn main() {
     env_logger::init();
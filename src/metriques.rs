@@ -0,0 +1,87 @@
+use std::sync::OnceLock;
+
+use millegrilles_common_rust::error::Error;
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+static METRIQUES: OnceLock<Metriques> = OnceLock::new();
+
+/// Metriques Prometheus du domaine, exposees sur /metrics par le serveur de sante.
+pub struct Metriques {
+    registre: Registry,
+    /// Messages recus avec posterV1.
+    pub posts_recus: IntCounter,
+    /// Destinataires par statut (resolu, inconnu, cle_manquante).
+    pub destinataires: IntCounterVec,
+    /// Messages traites par type (commande, requete) et action.
+    pub actions: IntCounterVec,
+    /// Messages en erreur par type (commande, requete) et action.
+    pub actions_erreurs: IntCounterVec,
+    /// Duree (secondes) d'un aller-retour avec le maitre des cles pour une cle de profil
+    /// (chargement ou sauvegarde d'une nouvelle cle).
+    pub latence_maitredescles: Histogram,
+    /// Duree (secondes) du chiffrage d'un message pour un destinataire.
+    pub duree_chiffrage: Histogram,
+    /// Nombre de fuuids par batch transmise au domaine Fichiers.
+    pub taille_batch_fuuids: Histogram,
+    /// Livraisons abandonnees apres livraison_tentatives_max, conservees avant leur purge.
+    pub livraisons_echec: IntGauge,
+}
+
+impl Metriques {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registre = Registry::new_custom(Some("messages".to_string()), None)?;
+
+        let posts_recus = IntCounter::new("posts_recus_total", "Messages recus avec posterV1")?;
+        let destinataires = IntCounterVec::new(
+            Opts::new("destinataires_total", "Destinataires des messages recus par statut"), &["statut"])?;
+        let actions = IntCounterVec::new(
+            Opts::new("actions_total", "Messages traites par type et action"), &["type", "action"])?;
+        let actions_erreurs = IntCounterVec::new(
+            Opts::new("actions_erreurs_total", "Messages en erreur par type et action"), &["type", "action"])?;
+        let latence_maitredescles = Histogram::with_opts(
+            HistogramOpts::new("maitredescles_latence_secondes", "Aller-retour avec le maitre des cles"))?;
+        let duree_chiffrage = Histogram::with_opts(
+            HistogramOpts::new("chiffrage_duree_secondes", "Chiffrage d'un message pour un destinataire")
+                .buckets(prometheus::exponential_buckets(0.0001, 4.0, 8)?))?;
+        let taille_batch_fuuids = Histogram::with_opts(
+            HistogramOpts::new("reclamer_fuuids_batch_taille", "Nombre de fuuids par batch de reclamerFuuids")
+                .buckets(prometheus::exponential_buckets(1.0, 10.0, 6)?))?;
+        let livraisons_echec = IntGauge::new("livraisons_echec", "Livraisons abandonnees en attente de purge")?;
+
+        registre.register(Box::new(posts_recus.clone()))?;
+        registre.register(Box::new(destinataires.clone()))?;
+        registre.register(Box::new(actions.clone()))?;
+        registre.register(Box::new(actions_erreurs.clone()))?;
+        registre.register(Box::new(latence_maitredescles.clone()))?;
+        registre.register(Box::new(duree_chiffrage.clone()))?;
+        registre.register(Box::new(taille_batch_fuuids.clone()))?;
+        registre.register(Box::new(livraisons_echec.clone()))?;
+
+        Ok(Self {
+            registre, posts_recus, destinataires, actions, actions_erreurs,
+            latence_maitredescles, duree_chiffrage, taille_batch_fuuids, livraisons_echec,
+        })
+    }
+
+    /// Comptabilise le traitement d'une commande ou requete.
+    pub fn action(&self, type_message: &str, action: &str, erreur: bool) {
+        self.actions.with_label_values(&[type_message, action]).inc();
+        if erreur {
+            self.actions_erreurs.with_label_values(&[type_message, action]).inc();
+        }
+    }
+}
+
+pub fn metriques() -> &'static Metriques {
+    METRIQUES.get_or_init(|| Metriques::new().expect("metriques init"))
+}
+
+/// Exporte les metriques en format texte Prometheus.
+pub fn exporter_metriques() -> Result<String, Error> {
+    let familles = metriques().registre.gather();
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&familles, &mut buffer)
+        .map_err(|e| Error::String(format!("exporter_metriques Erreur encodage : {:?}", e)))?;
+    String::from_utf8(buffer)
+        .map_err(|e| Error::String(format!("exporter_metriques Erreur utf-8 : {:?}", e)))
+}
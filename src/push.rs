@@ -0,0 +1,645 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use jwt_simple::prelude::{Claims, Duration as DureeJeton, ECDSAP256KeyPairLike, ECDSAP256PublicKeyLike, ES256KeyPair};
+use log::{debug, error, info, warn};
+use millegrilles_common_rust::async_trait::async_trait;
+use millegrilles_common_rust::base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD as base64_url_nopad};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::constantes::{CHAMP_CREATION, CHAMP_MODIFICATION};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongodb::options::UpdateOptions;
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio;
+use millegrilles_common_rust::tokio::sync::mpsc;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigMessagesDomaine;
+use crate::constantes::{COLLECTION_ABONNEMENTS_PUSH_NOM, EVENEMENT_NOUVEAU_MESSAGE};
+use crate::domaine_messages::GestionnaireDomaineMessages;
+use crate::erreurs::{ErreurMessages, reponse_erreur};
+
+/// Duree de vie (secondes) d'une notification conservee par le service push.
+const TTL_NOTIFICATION_PUSH: u32 = 24 * 3600;
+/// Delai maximal (secondes) d'envoi d'une notification a un service push.
+const TIMEOUT_NOTIFICATION_PUSH_SECS: u64 = 10;
+/// Nombre maximal d'abonnements push (appareils) par usager.
+const LIMITE_ABONNEMENTS_PUSH_USAGER: u64 = 20;
+/// Notifications en attente d'envoi. Au-dela, les notifications sont abandonnees.
+pub const TAILLE_FILE_NOTIFICATIONS_PUSH: usize = 1000;
+/// Duree de validite (heures) du jeton VAPID, 24 heures au maximum (RFC 8292).
+const DUREE_JETON_VAPID_HEURES: u64 = 12;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClesAbonnementPush {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+/// Abonnement Web Push d'un appareil (navigateur) de l'usager.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AbonnementPush {
+    pub user_id: String,
+    pub endpoint: String,
+    pub keys: ClesAbonnementPush,
+}
+
+pub enum ResultatPush {
+    Ok,
+    /// L'abonnement n'existe plus aupres du service push, il doit etre retire.
+    Expire,
+}
+
+/// Transmet une notification a un abonnement. Permet de remplacer le service Web Push (ex. par
+/// un serveur local de test).
+#[async_trait]
+pub trait EnvoyeurPush: Send + Sync {
+    async fn envoyer(&self, abonnement: &AbonnementPush, contenu: &[u8]) -> Result<ResultatPush, Error>;
+}
+
+/// Envoyeur Web Push avec signature VAPID.
+pub struct EnvoyeurWebPush {
+    client: reqwest::Client,
+    cle_vapid: ES256KeyPair,
+    /// Cle publique VAPID (point non compresse, base64url) transmise aux services push.
+    cle_publique: String,
+    sujet: String,
+}
+
+impl EnvoyeurWebPush {
+    /// Charge la cle privee VAPID (PEM PKCS#8). Retourne None si push n'est pas configure.
+    pub fn charger(config: &ConfigMessagesDomaine) -> Result<Option<Arc<dyn EnvoyeurPush>>, Error> {
+        let fichier = match config.fichier_cle_vapid.as_ref() {
+            Some(inner) => inner,
+            None => {
+                info!("EnvoyeurWebPush.charger Aucune cle VAPID, notifications push desactivees");
+                return Ok(None)
+            }
+        };
+        let pem = std::fs::read_to_string(fichier.as_str())
+            .map_err(|e| Error::String(format!("EnvoyeurWebPush.charger Erreur lecture {} : {:?}", fichier, e)))?;
+        let cle_vapid = ES256KeyPair::from_pem(pem.as_str())
+            .map_err(|e| Error::String(format!("EnvoyeurWebPush.charger Cle VAPID invalide : {:?}", e)))?;
+        let cle_publique = base64_url_nopad.encode(cle_vapid.public_key().public_key().to_bytes_uncompressed());
+        let envoyeur = Self { client: client_push()?, cle_vapid, cle_publique, sujet: config.sujet_vapid.clone() };
+        Ok(Some(Arc::new(envoyeur)))
+    }
+}
+
+#[async_trait]
+impl EnvoyeurPush for EnvoyeurWebPush {
+    async fn envoyer(&self, abonnement: &AbonnementPush, contenu: &[u8]) -> Result<ResultatPush, Error> {
+        // Les adresses IP litterales ne passent pas par le resolveur, verifier l'endpoint a chaque envoi
+        if let Err(raison) = verifier_endpoint_push(abonnement.endpoint.as_str()) {
+            Err(Error::String(format!("EnvoyeurWebPush.envoyer Endpoint refuse : {}", raison)))?
+        }
+        envoyer_notification(&self.client, &self.cle_vapid, self.cle_publique.as_str(), self.sujet.as_str(),
+                             abonnement, contenu).await
+    }
+}
+
+/// Client HTTP des services push. Les noms sont resolus par ResolveurAdressesPubliques, sans
+/// proxy ni redirection.
+fn client_push() -> Result<reqwest::Client, Error> {
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(ResolveurAdressesPubliques))
+        .no_proxy()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(std::time::Duration::from_secs(TIMEOUT_NOTIFICATION_PUSH_SECS))
+        .build()
+        .map_err(|e| Error::String(format!("client_push Erreur preparation client : {:?}", e)))
+}
+
+/// Chiffre le contenu (aes128gcm, RFC 8291) et le transmet au service push avec un jeton
+/// VAPID (RFC 8292).
+async fn envoyer_notification(client: &reqwest::Client, cle_vapid: &ES256KeyPair, cle_publique: &str, sujet: &str,
+                              abonnement: &AbonnementPush, contenu: &[u8])
+    -> Result<ResultatPush, Error>
+{
+    let p256dh = base64_url_nopad.decode(abonnement.keys.p256dh.trim_end_matches('='))?;
+    let auth = base64_url_nopad.decode(abonnement.keys.auth.trim_end_matches('='))?;
+    let contenu_chiffre = ece::encrypt(p256dh.as_slice(), auth.as_slice(), contenu)
+        .map_err(|e| Error::String(format!("envoyer_notification Erreur chiffrage : {:?}", e)))?;
+
+    let url = reqwest::Url::parse(abonnement.endpoint.as_str())
+        .map_err(|e| Error::String(format!("envoyer_notification Endpoint invalide : {:?}", e)))?;
+    let claims = Claims::create(DureeJeton::from_hours(DUREE_JETON_VAPID_HEURES))
+        .with_audience(url.origin().ascii_serialization())
+        .with_subject(sujet);
+    let jeton = cle_vapid.sign(claims)
+        .map_err(|e| Error::String(format!("envoyer_notification Erreur signature VAPID : {:?}", e)))?;
+
+    let reponse = client.post(url)
+        .header("ttl", TTL_NOTIFICATION_PUSH.to_string())
+        .header("content-encoding", "aes128gcm")
+        .header("content-type", "application/octet-stream")
+        .header("authorization", format!("vapid t={}, k={}", jeton, cle_publique))
+        .body(contenu_chiffre)
+        .send().await
+        .map_err(|e| Error::String(format!("envoyer_notification Erreur envoi : {:?}", e)))?;
+
+    match reponse.status().as_u16() {
+        200..=299 => Ok(ResultatPush::Ok),
+        404 | 410 => Ok(ResultatPush::Expire),
+        code => Err(Error::String(format!("envoyer_notification Reponse {} du service push", code)))
+    }
+}
+
+/// Resolveur DNS du client push. Un nom est refuse si une de ses adresses n'est pas publique.
+/// Le client se connecte uniquement aux adresses verifiees, le nom n'est pas resolu a nouveau
+/// entre la verification et la connexion.
+struct ResolveurAdressesPubliques;
+
+impl Resolve for ResolveurAdressesPubliques {
+    fn resolve(&self, nom: Name) -> Resolving {
+        let hote = nom.as_str().to_owned();
+        Box::pin(async move {
+            let adresses: Addrs = Box::new(resoudre_adresses_publiques(hote.as_str()).await?.into_iter());
+            Ok(adresses)
+        })
+    }
+}
+
+/// Resout un nom d'hote et verifie que chaque adresse est publique. Le port des adresses est
+/// remplace par celui de l'URL lors de la connexion.
+async fn resoudre_adresses_publiques(hote: &str) -> Result<Vec<SocketAddr>, String> {
+    let adresses: Vec<SocketAddr> = tokio::net::lookup_host((hote, 0)).await
+        .map_err(|e| format!("Erreur resolution {} : {:?}", hote, e))?
+        .collect();
+    if adresses.is_empty() {
+        return Err(format!("Aucune adresse pour {}", hote))
+    }
+    if let Some(interne) = adresses.iter().find(|a| !ip_publique(&a.ip())) {
+        return Err(format!("{} resout vers une adresse interne {}", hote, interne.ip()))
+    }
+    Ok(adresses)
+}
+
+/// Notification sans contenu du message, le client recupere le message au besoin.
+#[derive(Serialize)]
+struct NotificationNouveauMessage<'a> {
+    action: &'a str,
+    message_id: &'a str,
+}
+
+/// Nouveau message a notifier par thread_notifications_push.
+pub struct NotificationPush {
+    pub user_id: String,
+    pub message_id: String,
+}
+
+/// Met une notification en file sans attendre l'envoi. La notification est abandonnee si
+/// push n'est pas configure ou si la file est pleine.
+pub fn planifier_notification_push(gestionnaire: &GestionnaireDomaineMessages, user_id: &str, message_id: &str) {
+    if gestionnaire.envoyeur_push.is_none() {
+        return
+    }
+    let notification = NotificationPush { user_id: user_id.to_owned(), message_id: message_id.to_owned() };
+    if let Err(e) = gestionnaire.notifications_push.try_send(notification) {
+        warn!("planifier_notification_push Notification {} abandonnee : {:?}", message_id, e);
+    }
+}
+
+/// Thread d'envoi des notifications push. Les services push externes ne ralentissent pas
+/// l'emission des evenements.
+pub async fn thread_notifications_push<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M,
+                                          mut notifications: mpsc::Receiver<NotificationPush>)
+    where M: MongoDao
+{
+    while let Some(notification) = notifications.recv().await {
+        if let Err(e) = notifier_nouveau_message(gestionnaire, middleware, notification.user_id.as_str(), notification.message_id.as_str()).await {
+            warn!("thread_notifications_push Erreur notification {} : {:?}", notification.message_id, e);
+        }
+    }
+    error!("thread_notifications_push File de notifications fermee");
+}
+
+/// Transmet une notification de nouveau message a tous les abonnements de l'usager.
+async fn notifier_nouveau_message<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, user_id: &str, message_id: &str)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let envoyeur = match gestionnaire.envoyeur_push.as_ref() {
+        Some(inner) => inner,
+        None => return Ok(())
+    };
+
+    let collection = middleware.get_collection_typed::<AbonnementPush>(COLLECTION_ABONNEMENTS_PUSH_NOM)?;
+    let mut curseur = collection.find(doc! {"user_id": user_id}, None).await?;
+    let mut abonnements = Vec::new();
+    while curseur.advance().await? {
+        abonnements.push(curseur.deserialize_current()?);
+    }
+    if abonnements.is_empty() {
+        return Ok(())
+    }
+
+    let notification = NotificationNouveauMessage { action: EVENEMENT_NOUVEAU_MESSAGE, message_id };
+    let contenu = serde_json::to_vec(&notification)?;
+    let timeout = tokio::time::Duration::from_secs(TIMEOUT_NOTIFICATION_PUSH_SECS);
+    let expires = transmettre_notification(envoyeur.as_ref(), &abonnements, contenu.as_slice(), timeout).await;
+
+    let collection = middleware.get_collection(COLLECTION_ABONNEMENTS_PUSH_NOM)?;
+    for endpoint in expires {
+        info!("notifier_nouveau_message Abonnement expire pour usager {}, retire", user_id);
+        collection.delete_one(doc! {"endpoint": endpoint, "user_id": user_id}, None).await?;
+    }
+
+    Ok(())
+}
+
+/// Transmet le contenu a chaque abonnement. Retourne les endpoints expires.
+async fn transmettre_notification<'a>(envoyeur: &dyn EnvoyeurPush, abonnements: &'a Vec<AbonnementPush>, contenu: &[u8],
+                                      timeout: tokio::time::Duration)
+    -> Vec<&'a str>
+{
+    let mut expires = Vec::new();
+    for abonnement in abonnements {
+        match tokio::time::timeout(timeout, envoyeur.envoyer(abonnement, contenu)).await {
+            Ok(Ok(ResultatPush::Ok)) => debug!("transmettre_notification Notification transmise a {}", abonnement.endpoint),
+            Ok(Ok(ResultatPush::Expire)) => expires.push(abonnement.endpoint.as_str()),
+            Ok(Err(e)) => warn!("transmettre_notification Erreur notification usager {} : {:?}", abonnement.user_id, e),
+            Err(_) => warn!("transmettre_notification Timeout notification usager {} sur {}", abonnement.user_id, abonnement.endpoint)
+        }
+    }
+    expires
+}
+
+/// Verifie qu'un endpoint est une URL https vers un hote public. Refuse les hotes locaux et
+/// les adresses IP privees pour que le domaine ne serve pas a joindre le reseau interne.
+/// Retourne le nom DNS a resoudre, les adresses resolues sont verifiees par
+/// resoudre_adresses_publiques.
+fn verifier_endpoint_push(endpoint: &str) -> Result<Option<String>, &'static str> {
+    let reste = match endpoint.strip_prefix("https://") {
+        Some(inner) => inner,
+        None => return Err("Endpoint doit etre https")
+    };
+    let autorite = reste.split(['/', '?', '#']).next().unwrap_or("");
+    if autorite.contains('@') {
+        return Err("Endpoint avec information d'usager refuse")
+    }
+
+    let hote = match autorite.strip_prefix('[') {
+        Some(inner) => match inner.split_once(']') {
+            Some((ipv6, _)) => ipv6,
+            None => return Err("Endpoint invalide")
+        },
+        None => autorite.split(':').next().unwrap_or("")
+    };
+    let hote = hote.trim_end_matches('.').to_lowercase();
+    if hote.is_empty() {
+        return Err("Endpoint sans hote")
+    }
+
+    match hote.parse::<IpAddr>() {
+        Ok(ip) => match ip_publique(&ip) {
+            true => Ok(None),
+            false => Err("Endpoint vers une adresse interne")
+        },
+        Err(_) => {
+            // Les noms sans domaine et les suffixes reserves sont resolus localement
+            let interne = !hote.contains('.') ||
+                [".localhost", ".local", ".internal", ".lan", ".home.arpa"].iter().any(|s| hote.ends_with(s));
+            match interne {
+                true => Err("Endpoint vers un hote interne"),
+                false => Ok(Some(hote))
+            }
+        }
+    }
+}
+
+fn ip_publique(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ipv4_publique(ip),
+        IpAddr::V6(ip) => ipv6_publique(ip),
+    }
+}
+
+fn ipv4_publique(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    // 100.64.0.0/10 (CGNAT) et 0.0.0.0/8 ne sont pas couverts par les methodes stables
+    let cgnat = octets[0] == 100 && (octets[1] & 0xc0) == 64;
+    !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() ||
+        ip.is_broadcast() || ip.is_documentation() || ip.is_multicast() || cgnat || octets[0] == 0)
+}
+
+fn ipv6_publique(ip: &Ipv6Addr) -> bool {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return ipv4_publique(&ipv4)
+    }
+    let premier = ip.segments()[0];
+    // fc00::/7 (unique local) et fe80::/10 (lien local)
+    let unique_local = (premier & 0xfe00) == 0xfc00;
+    let lien_local = (premier & 0xffc0) == 0xfe80;
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || lien_local)
+}
+
+#[derive(Deserialize)]
+struct CommandeAjouterAbonnementPush {
+    endpoint: String,
+    keys: ClesAbonnementPush,
+}
+
+#[derive(Deserialize)]
+struct CommandeRetirerAbonnementPush {
+    endpoint: String,
+}
+
+#[derive(Serialize)]
+struct ReponseAbonnementPush {
+    ok: bool,
+}
+
+/// Ajoute ou remplace l'abonnement push d'un appareil de l'usager. Les abonnements sont un
+/// etat d'appareil, ils ne sont pas conserves dans des transactions. Un endpoint appartient
+/// a l'usager qui l'a enregistre en premier.
+pub async fn commande_ajouter_abonnement_push<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+    let commande: CommandeAjouterAbonnementPush = {
+        let message_ref = message.message.parse()?;
+        message_ref.contenu()?.deserialize()?
+    };
+    match verifier_endpoint_push(commande.endpoint.as_str()) {
+        Ok(Some(hote)) => {
+            // Refuser des l'abonnement un nom qui resout vers le reseau interne
+            if let Err(e) = resoudre_adresses_publiques(hote.as_str()).await {
+                debug!("commande_ajouter_abonnement_push Endpoint refuse : {}", e);
+                return Ok(Some(reponse_erreur(middleware, ErreurMessages::RequeteInvalide, Some("Endpoint vers une adresse interne ou non resolu"))?))
+            }
+        },
+        Ok(None) => (),
+        Err(raison) => return Ok(Some(reponse_erreur(middleware, ErreurMessages::RequeteInvalide, Some(raison))?))
+    }
+
+    let collection = middleware.get_collection(COLLECTION_ABONNEMENTS_PUSH_NOM)?;
+    match collection.find_one(doc! {"endpoint": &commande.endpoint}, None).await? {
+        Some(existant) => {
+            if existant.get_str("user_id").ok() != Some(user_id.as_str()) {
+                warn!("commande_ajouter_abonnement_push Endpoint deja associe a un autre usager, refuse pour {}", user_id);
+                return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Endpoint associe a un autre usager"))?))
+            }
+        },
+        None => {
+            let nombre = collection.count_documents(doc! {"user_id": &user_id}, None).await?;
+            if nombre >= LIMITE_ABONNEMENTS_PUSH_USAGER {
+                return Ok(Some(reponse_erreur(middleware, ErreurMessages::LimiteDepassee, Some("Nombre maximal d'abonnements push atteint"))?))
+            }
+        }
+    }
+
+    // L'index unique sur endpoint refuse un insert concurrent par un autre usager
+    let filtre = doc! {"endpoint": &commande.endpoint, "user_id": &user_id};
+    let ops = doc! {
+        "$set": {
+            "keys": {"p256dh": &commande.keys.p256dh, "auth": &commande.keys.auth},
+        },
+        "$setOnInsert": {CHAMP_CREATION: Utc::now()},
+        "$currentDate": {CHAMP_MODIFICATION: true}
+    };
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, options).await?;
+
+    Ok(Some(middleware.build_reponse(ReponseAbonnementPush { ok: true })?.0))
+}
+
+pub async fn commande_retirer_abonnement_push<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+    let commande: CommandeRetirerAbonnementPush = {
+        let message_ref = message.message.parse()?;
+        message_ref.contenu()?.deserialize()?
+    };
+
+    let filtre = doc! {"endpoint": &commande.endpoint, "user_id": &user_id};
+    let collection = middleware.get_collection(COLLECTION_ABONNEMENTS_PUSH_NOM)?;
+    collection.delete_one(filtre, None).await?;
+
+    Ok(Some(middleware.build_reponse(ReponseAbonnementPush { ok: true })?.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use millegrilles_common_rust::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use millegrilles_common_rust::tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Envoyeur de test, le resultat depend du endpoint.
+    #[derive(Default)]
+    struct EnvoyeurStub {
+        envois: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl EnvoyeurPush for EnvoyeurStub {
+        async fn envoyer(&self, abonnement: &AbonnementPush, _contenu: &[u8]) -> Result<ResultatPush, Error> {
+            self.envois.lock().unwrap().push(abonnement.endpoint.clone());
+            if abonnement.endpoint.contains("lent") {
+                tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+            }
+            if abonnement.endpoint.contains("expire") {
+                Ok(ResultatPush::Expire)
+            } else if abonnement.endpoint.contains("erreur") {
+                Err(Error::Str("EnvoyeurStub erreur"))
+            } else {
+                Ok(ResultatPush::Ok)
+            }
+        }
+    }
+
+    fn abonnement(endpoint: &str) -> AbonnementPush {
+        AbonnementPush {
+            user_id: "usager".to_string(),
+            endpoint: endpoint.to_string(),
+            keys: ClesAbonnementPush { p256dh: "p256dh".to_string(), auth: "auth".to_string() },
+        }
+    }
+
+    fn executer<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(future)
+    }
+
+    /// Serveur HTTP local minimal. Lit une requete, repond avec le code recu et retourne les
+    /// entetes et le corps de la requete.
+    async fn serveur_stub(listener: TcpListener, code: u16) -> (String, Vec<u8>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = Vec::new();
+        let mut bloc = [0u8; 4096];
+        let (entetes, longueur) = loop {
+            let n = stream.read(&mut bloc).await.unwrap();
+            assert!(n > 0, "requete incomplete");
+            buffer.extend_from_slice(&bloc[..n]);
+            if let Some(position) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                let entetes = String::from_utf8(buffer[..position].to_vec()).unwrap().to_lowercase();
+                let longueur = entetes.lines()
+                    .find_map(|l| l.strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                    .unwrap_or(0);
+                buffer.drain(..position + 4);
+                break (entetes, longueur)
+            }
+        };
+        while buffer.len() < longueur {
+            let n = stream.read(&mut bloc).await.unwrap();
+            assert!(n > 0, "corps incomplet");
+            buffer.extend_from_slice(&bloc[..n]);
+        }
+        let reponse = format!("HTTP/1.1 {} Stub\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", code);
+        stream.write_all(reponse.as_bytes()).await.unwrap();
+        (entetes, buffer)
+    }
+
+    #[test]
+    fn envoyer_notification_serveur_local() {
+        executer(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let serveur = tokio::spawn(serveur_stub(listener, 201));
+
+            let (cles_appareil, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+            let mut abonnement = abonnement(format!("http://127.0.0.1:{}/push/abcd", port).as_str());
+            abonnement.keys = ClesAbonnementPush {
+                p256dh: base64_url_nopad.encode(cles_appareil.pub_as_raw().unwrap()),
+                auth: base64_url_nopad.encode(auth),
+            };
+            // Client sans resolveur public, le serveur de test est local
+            let client = reqwest::Client::builder().no_proxy().build().unwrap();
+            let cle_vapid = ES256KeyPair::generate();
+            let resultat = envoyer_notification(&client, &cle_vapid, "CLEPUBLIQUE", "mailto:admin@exemple.com",
+                                                &abonnement, b"{\"ok\":true}").await.unwrap();
+            assert!(matches!(resultat, ResultatPush::Ok));
+
+            let (entetes, corps) = serveur.await.unwrap();
+            assert!(entetes.starts_with("post /push/abcd "), "{}", entetes);
+            assert!(entetes.contains("content-encoding: aes128gcm"));
+            assert!(entetes.contains(format!("ttl: {}", TTL_NOTIFICATION_PUSH).as_str()));
+            assert!(entetes.contains("authorization: vapid t="));
+            assert!(entetes.contains(", k=clepublique"));
+            let contenu = ece::decrypt(&cles_appareil.raw_components().unwrap(), &auth, corps.as_slice()).unwrap();
+            assert_eq!(b"{\"ok\":true}".to_vec(), contenu);
+        });
+    }
+
+    #[test]
+    fn envoyer_notification_abonnement_expire() {
+        executer(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let serveur = tokio::spawn(serveur_stub(listener, 410));
+
+            let (cles_appareil, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+            let mut abonnement = abonnement(format!("http://127.0.0.1:{}/push/abcd", port).as_str());
+            abonnement.keys = ClesAbonnementPush {
+                p256dh: base64_url_nopad.encode(cles_appareil.pub_as_raw().unwrap()),
+                auth: base64_url_nopad.encode(auth),
+            };
+            let client = reqwest::Client::builder().no_proxy().build().unwrap();
+            let resultat = envoyer_notification(&client, &ES256KeyPair::generate(), "CLEPUBLIQUE", "mailto:admin@exemple.com",
+                                                &abonnement, b"{}").await.unwrap();
+            assert!(matches!(resultat, ResultatPush::Expire));
+            serveur.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn client_push_refuse_nom_local() {
+        executer(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let client = client_push().unwrap();
+            // Le nom passe par le resolveur, qui refuse l'adresse de bouclage
+            let resultat = client.post(format!("http://localhost:{}/push", port)).body("{}").send().await;
+            assert!(resultat.is_err());
+            let connexion = tokio::time::timeout(tokio::time::Duration::from_millis(200), listener.accept()).await;
+            assert!(connexion.is_err(), "aucune connexion ne doit atteindre le serveur local");
+        });
+    }
+
+    #[test]
+    fn resoudre_adresses_internes_refusees() {
+        assert!(executer(resoudre_adresses_publiques("localhost")).is_err());
+    }
+
+    #[test]
+    fn transmettre_notification_retourne_expires() {
+        let envoyeur = EnvoyeurStub::default();
+        let abonnements = vec![
+            abonnement("https://push.example.com/ok"),
+            abonnement("https://push.example.com/expire"),
+            abonnement("https://push.example.com/erreur"),
+        ];
+        let timeout = tokio::time::Duration::from_secs(1);
+        let expires = executer(transmettre_notification(&envoyeur, &abonnements, b"{}", timeout));
+        assert_eq!(vec!["https://push.example.com/expire"], expires);
+        // Une erreur sur un abonnement n'empeche pas l'envoi aux suivants
+        assert_eq!(3, envoyeur.envois.lock().unwrap().len());
+    }
+
+    #[test]
+    fn transmettre_notification_timeout() {
+        let envoyeur = EnvoyeurStub::default();
+        let abonnements = vec![
+            abonnement("https://push.example.com/lent"),
+            abonnement("https://push.example.com/expire"),
+        ];
+        let timeout = tokio::time::Duration::from_millis(50);
+        let expires = executer(async {
+            tokio::time::timeout(tokio::time::Duration::from_secs(5),
+                                 transmettre_notification(&envoyeur, &abonnements, b"{}", timeout)).await
+        }).expect("service lent doit etre abandonne");
+        assert_eq!(vec!["https://push.example.com/expire"], expires);
+    }
+
+    #[test]
+    fn endpoint_publics_acceptes() {
+        assert!(verifier_endpoint_push("https://fcm.googleapis.com/fcm/send/abcd").is_ok());
+        assert!(verifier_endpoint_push("https://updates.push.services.mozilla.com:443/wpush/v2/x").is_ok());
+        assert!(verifier_endpoint_push("https://8.8.8.8/push").is_ok());
+        assert!(verifier_endpoint_push("https://[2001:4860::1]/push").is_ok());
+    }
+
+    #[test]
+    fn endpoint_internes_refuses() {
+        for endpoint in [
+            "http://push.example.com/x",
+            "https://localhost/x",
+            "https://LOCALHOST./x",
+            "https://serveur/x",
+            "https://mq.millegrille.local/x",
+            "https://app.localhost:8443/x",
+            "https://127.0.0.1/x",
+            "https://10.0.0.5:9000/x",
+            "https://172.16.1.1/x",
+            "https://192.168.1.10/x",
+            "https://169.254.169.254/latest",
+            "https://100.64.0.1/x",
+            "https://0.0.0.0/x",
+            "https://[::1]/x",
+            "https://[fd00::1]/x",
+            "https://[fe80::1]/x",
+            "https://[::ffff:127.0.0.1]/x",
+            "https://usager@push.example.com/x",
+            "https:///x",
+        ] {
+            assert!(verifier_endpoint_push(endpoint).is_err(), "{} doit etre refuse", endpoint);
+        }
+    }
+}
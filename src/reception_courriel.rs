@@ -0,0 +1,500 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
+use mail_parser::{MessageParser, MimeHeaders};
+use millegrilles_common_rust::base64::{Engine as _, engine::general_purpose::STANDARD as base64_std};
+use millegrilles_common_rust::chrono::{TimeZone, Utc};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::middleware::Middleware;
+use millegrilles_common_rust::tokio;
+use millegrilles_common_rust::tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use millegrilles_common_rust::tokio::net::{TcpListener, TcpStream};
+use millegrilles_common_rust::tokio::sync::Semaphore;
+
+use crate::commandes::{poster_message, MessagePostV1};
+use crate::config::parser_reseau;
+use crate::courriel::valeur_x_loop;
+use crate::domaine_messages::GestionnaireDomaineMessages;
+use crate::livraisons::SourceLivraison;
+use crate::metriques::metriques;
+use crate::validation::valider_message_post;
+
+/// Delai maximal (secondes) d'attente d'une ligne du client SMTP.
+const TIMEOUT_LIGNE_SMTP_SECS: u64 = 60;
+/// Nombre maximal de destinataires (RCPT TO) par courriel.
+const NOMBRE_MAX_RCPT: usize = 100;
+/// Longueur maximale d'une ligne SMTP, CRLF inclus (RFC 5321, 4.5.3.1.6).
+const LONGUEUR_MAX_LIGNE_SMTP: usize = 1000;
+/// Nombre maximal de sessions SMTP simultanees.
+const NOMBRE_MAX_SESSIONS_SMTP: usize = 20;
+
+/// Raison du refus d'un courriel par convertir_courriel.
+#[derive(Debug, PartialEq)]
+pub enum RefusCourriel {
+    /// Le courriel ne peut pas etre lu.
+    Invalide,
+    /// Piece jointe qui ne peut pas etre incluse dans le contenu (nom de la piece).
+    PieceJointe(String),
+}
+
+/// Convertit un courriel (RFC 5322) en MessagePostV1. Les destinataires sont les noms d'usagers
+/// de l'enveloppe SMTP (RCPT TO), les entetes To: et Cc: ne servent qu'a l'affichage. Les images
+/// inline (cid:) sont incluses dans le contenu. Les autres pieces jointes sont refusees, les
+/// fichiers d'un MessagePostV1 doivent deja etre conserves par Fichiers.
+pub fn convertir_courriel(courriel: &[u8], destinataires: Vec<String>) -> Result<MessagePostV1, RefusCourriel> {
+    let message = match MessageParser::default().parse(courriel) {
+        Some(inner) => inner,
+        None => Err(RefusCourriel::Invalide)?
+    };
+
+    let expediteur = message.from().and_then(|a| a.first());
+    let adresse_expediteur = expediteur.and_then(|a| a.address()).map(|a| a.to_string());
+    let auteur = expediteur
+        .and_then(|a| a.name().map(|n| n.to_string()))
+        .or_else(|| adresse_expediteur.clone());
+    let reply_to = message.reply_to()
+        .and_then(|a| a.first())
+        .and_then(|a| a.address())
+        .map(|a| a.to_string())
+        .or_else(|| adresse_expediteur.clone());
+    let date_post = message.date().and_then(|d| Utc.timestamp_opt(d.to_timestamp(), 0).single());
+
+    let mut contenu = match message.body_html(0) {
+        Some(html) => html.to_string(),
+        None => match message.body_text(0) {
+            Some(texte) => format!("<p>{}</p>", echapper_html(texte.as_ref()).replace('\n', "<br>")),
+            None => String::new()
+        }
+    };
+
+    // Images inline (cid:) remplacees, toute autre piece jointe refuse le courriel
+    for piece in message.attachments() {
+        let mimetype = match piece.content_type() {
+            Some(ct) => match ct.subtype() {
+                Some(subtype) => format!("{}/{}", ct.ctype(), subtype),
+                None => ct.ctype().to_string()
+            },
+            None => "application/octet-stream".to_string()
+        };
+        if let Some(cid) = piece.content_id() {
+            let reference = format!("cid:{}", cid.trim_matches(|c| c == '<' || c == '>'));
+            if mimetype.starts_with("image/") && contenu.contains(reference.as_str()) {
+                let url = format!("data:{};base64,{}", mimetype, base64_std.encode(piece.contents()));
+                contenu = contenu.replace(reference.as_str(), url.as_str());
+                continue
+            }
+        }
+        Err(RefusCourriel::PieceJointe(piece.attachment_name().unwrap_or("piece-jointe").to_string()))?
+    }
+    if let Some(sujet) = message.subject() {
+        contenu = format!("<p><strong>{}</strong></p>{}", echapper_html(sujet), contenu);
+    }
+
+    Ok(MessagePostV1 {
+        contenu,
+        destinataires,
+        reply_to,
+        date_post,
+        origine: adresse_expediteur.map(|a| format!("mailto:{}", a)),
+        auteur,
+        fichiers: None,
+        conversation_id: None,
+        expiration: None,
+    })
+}
+
+/// Retourne la partie locale (nom d'usager) si l'adresse appartient au domaine.
+fn nom_usager_local(adresse: &str, domaine: &str) -> Option<String> {
+    let adresse = adresse.trim().trim_matches(|c| c == '<' || c == '>');
+    let (local, domaine_adresse) = adresse.rsplit_once('@')?;
+    if local.is_empty() || !domaine_adresse.eq_ignore_ascii_case(domaine) {
+        return None
+    }
+    Some(local.to_string())
+}
+
+/// Indique si le courriel porte l'entete X-Loop des courriels transferes par le domaine.
+fn boucle_transfert(courriel: &[u8], valeur_x_loop: &str) -> bool {
+    match MessageParser::default().parse_headers(courriel) {
+        Some(message) => message.headers_raw()
+            .any(|(nom, valeur)| nom.eq_ignore_ascii_case("X-Loop") && valeur.trim().eq_ignore_ascii_case(valeur_x_loop)),
+        None => false
+    }
+}
+
+fn echapper_html(texte: &str) -> String {
+    texte.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Indique si l'adresse fait partie d'une des sources autorisees (reseau, longueur du prefixe).
+/// Une adresse IPv4 mappee en IPv6 (ecoute double pile) est comparee comme IPv4.
+fn source_autorisee(sources: &[(IpAddr, u8)], adresse: IpAddr) -> bool {
+    let adresse = adresse.to_canonical();
+    sources.iter().any(|(reseau, longueur)| match (reseau, adresse) {
+        (IpAddr::V4(reseau), IpAddr::V4(adresse)) => {
+            let masque = u32::MAX.checked_shl(32 - *longueur as u32).unwrap_or(0);
+            u32::from(*reseau) & masque == u32::from(adresse) & masque
+        },
+        (IpAddr::V6(reseau), IpAddr::V6(adresse)) => {
+            let masque = u128::MAX.checked_shl(128 - *longueur as u32).unwrap_or(0);
+            u128::from(*reseau) & masque == u128::from(adresse) & masque
+        },
+        _ => false
+    })
+}
+
+/// Serveur SMTP local de reception des courriels, destine a recevoir les courriels relayes par
+/// le MTA de la MilleGrille. Seules les connexions des sources configurees et les adresses du
+/// domaine configure sont acceptees. Chaque session est traitee dans une task, un client lent
+/// ne bloque pas les autres.
+pub async fn thread_reception_courriel<M>(gestionnaire: &'static GestionnaireDomaineMessages, middleware: &'static M)
+    where M: Middleware + 'static
+{
+    let (adresse, domaine) = match (gestionnaire.config.adresse_reception_courriel.as_ref(), gestionnaire.config.domaine_courriel.as_ref()) {
+        (Some(adresse), Some(domaine)) => (adresse.as_str(), domaine.as_str()),
+        _ => {
+            info!("thread_reception_courriel Reception de courriels desactivee");
+            return std::future::pending().await
+        }
+    };
+    let listener = match TcpListener::bind(adresse).await {
+        Ok(inner) => inner,
+        Err(e) => {
+            error!("thread_reception_courriel Erreur ouverture {} : {:?}, reception desactivee", adresse, e);
+            return std::future::pending().await
+        }
+    };
+    // Sources deja validees par la configuration
+    let sources: Vec<(IpAddr, u8)> = gestionnaire.config.sources_reception_courriel.iter()
+        .filter_map(|s| parser_reseau(s.as_str()))
+        .collect();
+    info!("thread_reception_courriel Reception SMTP sur {} pour le domaine {}, sources {:?}",
+        adresse, domaine, gestionnaire.config.sources_reception_courriel);
+
+    let sessions = Arc::new(Semaphore::new(NOMBRE_MAX_SESSIONS_SMTP));
+    loop {
+        let permis = match sessions.clone().acquire_owned().await {
+            Ok(inner) => inner,
+            Err(_) => return
+        };
+        let (stream, adresse_client) = match listener.accept().await {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("thread_reception_courriel Erreur accept : {:?}", e);
+                continue
+            }
+        };
+        if !source_autorisee(sources.as_slice(), adresse_client.ip()) {
+            warn!("thread_reception_courriel Connexion refusee de {}", adresse_client);
+            drop(permis);
+            tokio::spawn(async move {
+                let mut stream = stream;
+                let _ = ecrire_ligne(&mut stream, "554 5.7.1 Source non autorisee").await;
+            });
+            continue
+        }
+        tokio::spawn(async move {
+            if let Err(e) = traiter_session_smtp(gestionnaire, middleware, domaine, stream).await {
+                debug!("thread_reception_courriel Erreur session : {:?}", e);
+            }
+            drop(permis);
+        });
+    }
+}
+
+async fn traiter_session_smtp<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, domaine: &str, stream: TcpStream)
+    -> Result<(), Error>
+    where M: Middleware
+{
+    let (lecture, mut ecriture) = stream.into_split();
+    let mut lecture = BufReader::new(lecture);
+    let taille_max = gestionnaire.config.limites.taille_max_contenu;
+
+    ecrire_ligne(&mut ecriture, format!("220 {} ESMTP MilleGrilles Messages", domaine).as_str()).await?;
+
+    let mut expediteur_recu = false;
+    // Destinataires acceptes (RCPT TO), ce sont les destinataires du message
+    let mut destinataires: Vec<String> = Vec::new();
+    let mut nombre_rcpt = 0usize;
+    loop {
+        let ligne = match lire_ligne(&mut lecture).await? {
+            Some(inner) => String::from_utf8_lossy(inner.as_slice()).into_owned(),
+            None => return Ok(())
+        };
+        let commande = ligne.to_ascii_uppercase();
+
+        if commande.starts_with("EHLO") {
+            ecrire_ligne(&mut ecriture, format!("250-{}\r\n250-SIZE {}\r\n250 8BITMIME", domaine, taille_max).as_str()).await?;
+        } else if commande.starts_with("HELO") {
+            ecrire_ligne(&mut ecriture, format!("250 {}", domaine).as_str()).await?;
+        } else if commande.starts_with("MAIL FROM:") {
+            expediteur_recu = true;
+            destinataires.clear();
+            nombre_rcpt = 0;
+            ecrire_ligne(&mut ecriture, "250 2.1.0 Ok").await?;
+        } else if commande.starts_with("RCPT TO:") {
+            let adresse = ligne[8..].split_whitespace().next().unwrap_or("");
+            if !expediteur_recu {
+                ecrire_ligne(&mut ecriture, "503 5.5.1 MAIL FROM requis").await?;
+            } else if nombre_rcpt >= NOMBRE_MAX_RCPT {
+                ecrire_ligne(&mut ecriture, "452 4.5.3 Trop de destinataires").await?;
+            } else {
+                match nom_usager_local(adresse, domaine) {
+                    Some(nom_usager) => {
+                        nombre_rcpt += 1;
+                        if !destinataires.contains(&nom_usager) {
+                            destinataires.push(nom_usager);
+                        }
+                        ecrire_ligne(&mut ecriture, "250 2.1.5 Ok").await?;
+                    },
+                    // Ne pas relayer vers d'autres domaines
+                    None => ecrire_ligne(&mut ecriture, "550 5.7.1 Destinataire refuse").await?
+                }
+            }
+        } else if commande == "DATA" {
+            if destinataires.is_empty() {
+                ecrire_ligne(&mut ecriture, "503 5.5.1 RCPT TO requis").await?;
+                continue
+            }
+            ecrire_ligne(&mut ecriture, "354 Terminer avec <CRLF>.<CRLF>").await?;
+            let courriel = lire_data(&mut lecture, taille_max).await?;
+            let destinataires_courriel = std::mem::take(&mut destinataires);
+            let reponse = match courriel {
+                Some(courriel) => recevoir_courriel(gestionnaire, middleware, courriel, destinataires_courriel).await,
+                None => "552 5.3.4 Courriel trop volumineux"
+            };
+            expediteur_recu = false;
+            nombre_rcpt = 0;
+            ecrire_ligne(&mut ecriture, reponse).await?;
+        } else if commande == "RSET" {
+            expediteur_recu = false;
+            destinataires.clear();
+            nombre_rcpt = 0;
+            ecrire_ligne(&mut ecriture, "250 2.0.0 Ok").await?;
+        } else if commande == "NOOP" {
+            ecrire_ligne(&mut ecriture, "250 2.0.0 Ok").await?;
+        } else if commande == "QUIT" {
+            ecrire_ligne(&mut ecriture, "221 2.0.0 Bye").await?;
+            return Ok(())
+        } else {
+            ecrire_ligne(&mut ecriture, "502 5.5.2 Commande non supportee").await?;
+        }
+    }
+}
+
+/// Traite un courriel recu et retourne la reponse SMTP. Une erreur temporaire (4xx) permet au
+/// MTA de reessayer.
+async fn recevoir_courriel<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, courriel: Vec<u8>,
+                              destinataires: Vec<String>)
+    -> &'static str
+    where M: Middleware
+{
+    let _garde = match gestionnaire.arret.debuter_tache("reception courriel") {
+        Some(inner) => inner,
+        None => return "421 4.3.2 Arret en cours"
+    };
+    metriques().posts_recus.inc();
+
+    // Un courriel transfere qui revient est accepte puis ignore, un refus genererait un avis
+    // de non-livraison qui peut lui aussi boucler
+    if let Some(valeur) = valeur_x_loop(&gestionnaire.config) {
+        if boucle_transfert(courriel.as_slice(), valeur.as_str()) {
+            warn!("recevoir_courriel Courriel avec X-Loop {} ignore (boucle de transfert)", valeur);
+            return "250 2.0.0 Ok"
+        }
+    }
+
+    let mut message_post = match convertir_courriel(courriel.as_slice(), destinataires.clone()) {
+        Ok(inner) => inner,
+        Err(RefusCourriel::PieceJointe(nom)) => {
+            debug!("recevoir_courriel Courriel refuse, piece jointe {}", nom);
+            return "554 5.6.0 Pieces jointes non supportees"
+        },
+        Err(RefusCourriel::Invalide) => {
+            debug!("recevoir_courriel Courriel refuse, format invalide");
+            return "554 5.6.0 Courriel invalide"
+        }
+    };
+    if let Err(e) = valider_message_post(&gestionnaire.config.limites, &mut message_post) {
+        debug!("recevoir_courriel Courriel refuse : {}", e);
+        return "554 5.6.0 Contenu refuse"
+    }
+
+    match poster_message(gestionnaire, middleware, SourceLivraison::Courriel(courriel.as_slice(), &destinataires), &message_post, None).await {
+        Ok(resultat) if resultat.nombre_destinataires == 0 => "550 5.1.1 Destinataires inconnus",
+        Ok(resultat) => {
+            info!("recevoir_courriel Courriel livre a {} destinataires ({} en attente)",
+                resultat.nombre_destinataires, resultat.livraisons_en_attente);
+            "250 2.0.0 Ok"
+        },
+        Err(e) => {
+            error!("recevoir_courriel Erreur traitement courriel : {:?}", e);
+            "451 4.3.0 Erreur temporaire"
+        }
+    }
+}
+
+/// Lit une ligne sans le CRLF. La lecture est bornee a LONGUEUR_MAX_LIGNE_SMTP, une ligne plus
+/// longue termine la session. Le contenu 8BITMIME n'est pas necessairement UTF-8.
+async fn lire_ligne<R>(lecture: &mut R) -> Result<Option<Vec<u8>>, Error>
+    where R: AsyncBufRead + Unpin
+{
+    let mut ligne = Vec::new();
+    let duree = tokio::time::Duration::from_secs(TIMEOUT_LIGNE_SMTP_SECS);
+    let mut lecture_bornee = (&mut *lecture).take(LONGUEUR_MAX_LIGNE_SMTP as u64);
+    let taille = match tokio::time::timeout(duree, lecture_bornee.read_until(b'\n', &mut ligne)).await {
+        Ok(inner) => inner.map_err(|e| Error::String(format!("lire_ligne Erreur lecture : {:?}", e)))?,
+        Err(_) => Err(Error::Str("lire_ligne Timeout"))?
+    };
+    if taille == 0 {
+        return Ok(None)
+    }
+    if ligne.last() != Some(&b'\n') && taille >= LONGUEUR_MAX_LIGNE_SMTP {
+        Err(Error::Str("lire_ligne Ligne trop longue"))?
+    }
+    if ligne.last() == Some(&b'\n') {
+        ligne.pop();
+        if ligne.last() == Some(&b'\r') {
+            ligne.pop();
+        }
+    }
+    Ok(Some(ligne))
+}
+
+/// Lit le contenu DATA jusqu'a la ligne ".". Retourne None si la taille maximale est depassee
+/// (le contenu est lu au complet pour conserver la session).
+async fn lire_data<R>(lecture: &mut R, taille_max: usize) -> Result<Option<Vec<u8>>, Error>
+    where R: AsyncBufRead + Unpin
+{
+    let mut courriel = Vec::new();
+    let mut depasse = false;
+    loop {
+        let ligne = match lire_ligne(lecture).await? {
+            Some(inner) => inner,
+            None => Err(Error::Str("lire_data Connexion fermee durant DATA"))?
+        };
+        if ligne.as_slice() == b"." {
+            break
+        }
+        // Retirer le point ajoute par le client (dot-stuffing)
+        let ligne = ligne.strip_prefix(b".").unwrap_or(ligne.as_slice());
+        if courriel.len() + ligne.len() + 2 > taille_max {
+            depasse = true;
+        }
+        if !depasse {
+            courriel.extend_from_slice(ligne);
+            courriel.extend_from_slice(b"\r\n");
+        }
+    }
+    match depasse {
+        true => Ok(None),
+        false => Ok(Some(courriel))
+    }
+}
+
+async fn ecrire_ligne<W>(ecriture: &mut W, ligne: &str) -> Result<(), Error>
+    where W: AsyncWriteExt + Unpin
+{
+    ecriture.write_all(format!("{}\r\n", ligne).as_bytes()).await
+        .map_err(|e| Error::String(format!("ecrire_ligne Erreur ecriture : {:?}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lire_data_test(data: &[u8], taille_max: usize) -> Result<Option<Vec<u8>>, Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            let mut lecture = BufReader::new(data);
+            lire_data(&mut lecture, taille_max).await
+        })
+    }
+
+    #[test]
+    fn nom_usager_local_domaine() {
+        assert_eq!(Some("usager".to_string()), nom_usager_local("usager@exemple.com", "exemple.com"));
+        assert_eq!(Some("usager".to_string()), nom_usager_local("<usager@EXEMPLE.com>", "exemple.com"));
+        assert_eq!(Some("usager".to_string()), nom_usager_local(" usager@exemple.com ", "exemple.com"));
+        assert_eq!(None, nom_usager_local("usager@autre.com", "exemple.com"));
+        assert_eq!(None, nom_usager_local("usager@sous.exemple.com", "exemple.com"));
+        assert_eq!(None, nom_usager_local("@exemple.com", "exemple.com"));
+        assert_eq!(None, nom_usager_local("usager", "exemple.com"));
+    }
+
+    #[test]
+    fn lire_data_dot_stuffing() {
+        let courriel = lire_data_test(b"Subject: test\r\n\r\n..ligne\r\n.\r\nsuite\r\n", 1000).unwrap();
+        assert_eq!(Some(b"Subject: test\r\n\r\n.ligne\r\n".to_vec()), courriel);
+    }
+
+    #[test]
+    fn lire_data_octets_non_utf8() {
+        let courriel = lire_data_test(b"\xe9t\xe9\r\n.\r\n", 1000).unwrap();
+        assert_eq!(Some(b"\xe9t\xe9\r\n".to_vec()), courriel);
+    }
+
+    #[test]
+    fn lire_data_taille_depassee() {
+        assert_eq!(None, lire_data_test(b"0123456789\r\n0123456789\r\n.\r\n", 15).unwrap());
+    }
+
+    #[test]
+    fn lire_data_connexion_fermee() {
+        assert!(lire_data_test(b"ligne\r\n", 1000).is_err());
+    }
+
+    #[test]
+    fn lire_data_ligne_trop_longue() {
+        let mut data = vec![b'a'; LONGUEUR_MAX_LIGNE_SMTP + 10];
+        data.extend_from_slice(b"\r\n.\r\n");
+        assert!(lire_data_test(data.as_slice(), 10 * LONGUEUR_MAX_LIGNE_SMTP).is_err());
+    }
+
+    #[test]
+    fn convertir_courriel_destinataires_enveloppe() {
+        let courriel = b"From: Expediteur <exp@externe.com>\r\nTo: autre@exemple.com\r\nSubject: Test\r\n\r\nBonjour\r\n";
+        let message = convertir_courriel(courriel, vec!["usager".to_string()]).unwrap();
+        assert_eq!(vec!["usager".to_string()], message.destinataires);
+        assert_eq!(Some("mailto:exp@externe.com".to_string()), message.origine);
+    }
+
+    #[test]
+    fn source_autorisee_reseaux() {
+        let sources = vec![parser_reseau("127.0.0.1").unwrap(), parser_reseau("10.1.0.0/16").unwrap(), parser_reseau("fd00::/8").unwrap()];
+        assert!(source_autorisee(&sources, "127.0.0.1".parse().unwrap()));
+        assert!(source_autorisee(&sources, "::ffff:127.0.0.1".parse().unwrap()));
+        assert!(source_autorisee(&sources, "10.1.2.3".parse().unwrap()));
+        assert!(source_autorisee(&sources, "fd12::1".parse().unwrap()));
+        assert!(!source_autorisee(&sources, "127.0.0.2".parse().unwrap()));
+        assert!(!source_autorisee(&sources, "10.2.0.1".parse().unwrap()));
+        assert!(!source_autorisee(&sources, "::1".parse().unwrap()));
+        assert!(source_autorisee(&[parser_reseau("0.0.0.0/0").unwrap()], "192.0.2.1".parse().unwrap()));
+        assert!(!source_autorisee(&[], "127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn convertir_courriel_image_inline() {
+        let courriel = concat!(
+            "From: exp@externe.com\r\nMIME-Version: 1.0\r\n",
+            "Content-Type: multipart/related; boundary=\"b\"\r\n\r\n",
+            "--b\r\nContent-Type: text/html\r\n\r\n<img src=\"cid:img1\">\r\n",
+            "--b\r\nContent-Type: image/png\r\nContent-ID: <img1>\r\n",
+            "Content-Transfer-Encoding: base64\r\n\r\niVBORw==\r\n--b--\r\n");
+        let message = convertir_courriel(courriel.as_bytes(), vec!["usager".to_string()]).unwrap();
+        assert!(message.contenu.contains("data:image/png;base64,iVBORw=="), "{}", message.contenu);
+    }
+
+    #[test]
+    fn convertir_courriel_piece_jointe_refusee() {
+        let courriel = concat!(
+            "From: exp@externe.com\r\nSubject: Piece\r\nMIME-Version: 1.0\r\n",
+            "Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n",
+            "--b\r\nContent-Type: text/plain\r\n\r\nBonjour\r\n",
+            "--b\r\nContent-Type: application/pdf\r\nContent-Disposition: attachment; filename=\"doc.pdf\"\r\n",
+            "Content-Transfer-Encoding: base64\r\n\r\nJVBERi0=\r\n--b--\r\n");
+        assert_eq!(Err(RefusCourriel::PieceJointe("doc.pdf".to_string())),
+            convertir_courriel(courriel.as_bytes(), vec!["usager".to_string()]).map(|_| ()));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashSet;
+
+use log::{debug, info};
+use millegrilles_common_rust::bson::{doc, Bson, Document};
+use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::constantes::{DELEGATION_GLOBALE_PROPRIETAIRE, Securite, CHAMP_MODIFICATION};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+use serde::{Deserialize, Serialize};
+
+use crate::constantes::{COLLECTION_FICHIERS_NOM, COLLECTION_RECEPTION_NOM, COLLECTION_USAGERS_NOM};
+use crate::domaine_messages::GestionnaireDomaineMessages;
+use crate::erreurs::{ErreurMessages, reponse_erreur};
+
+/// Nombre maximal d'identifiants retournes pour chaque type de difference.
+const LIMITE_EXEMPLES_DIFFERENCES: usize = 100;
+
+/// Champs qui changent normalement lors d'une regeneration.
+const CHAMPS_IGNORES: [&str; 3] = ["_id", CHAMP_MODIFICATION, "dernier_reset"];
+
+/// Collections verifiees avec les champs qui identifient un document et le filtre des documents
+/// qui proviennent de transactions. Les profils sans cle_id sont une cache du maitre des comptes.
+fn collections_verifiees() -> Vec<(&'static str, Vec<&'static str>, Document)> {
+    vec![
+        (COLLECTION_RECEPTION_NOM, vec!["message_id"], doc! {}),
+        (COLLECTION_FICHIERS_NOM, vec!["message_id", "fuuid"], doc! {}),
+        (COLLECTION_USAGERS_NOM, vec!["user_id"], doc! {"cle_id": {"$exists": true}}),
+    ]
+}
+
+fn nom_collection_snapshot(nom_collection: &str) -> String {
+    format!("{}_snapshot", nom_collection)
+}
+
+#[derive(Deserialize)]
+struct CommandeVerifierRegeneration {
+    /// "snapshot" conserve une copie des collections avant la regeneration,
+    /// "comparer" compare les collections regenerees avec la copie.
+    etape: String,
+}
+
+#[derive(Serialize)]
+struct DifferencesCollection {
+    collection: String,
+    /// Documents presents avant la regeneration mais absents apres.
+    manquants: Vec<String>,
+    /// Documents crees par la regeneration qui n'existaient pas avant.
+    en_trop: Vec<String>,
+    /// Documents dont le contenu differe.
+    differents: Vec<String>,
+    nombre_manquants: usize,
+    nombre_en_trop: usize,
+    nombre_differents: usize,
+}
+
+#[derive(Serialize)]
+struct ReponseVerifierRegeneration {
+    ok: bool,
+    err: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    collections: Option<Vec<DifferencesCollection>>,
+}
+
+/// Verifie qu'une regeneration produit les memes collections que l'etat courant. La commande
+/// est appelee avec l'etape "snapshot", la regeneration est declenchee, puis la commande est
+/// appelee avec l'etape "comparer". Les collections snapshot sont supprimees apres la comparaison.
+pub async fn commande_verifier_regeneration<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    if !message.certificat.verifier_exchanges(vec![Securite::L3Protege, Securite::L4Secure])? &&
+        !message.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, None)?))
+    }
+
+    let commande: CommandeVerifierRegeneration = {
+        let message_ref = message.message.parse()?;
+        message_ref.contenu()?.deserialize()?
+    };
+
+    match commande.etape.as_str() {
+        "snapshot" => {
+            for (nom_collection, _, filtre) in collections_verifiees() {
+                let collection = middleware.get_collection(nom_collection)?;
+                let pipeline = vec![doc! {"$match": filtre}, doc! {"$out": nom_collection_snapshot(nom_collection)}];
+                collection.aggregate(pipeline, None).await?;
+            }
+            info!("commande_verifier_regeneration Snapshot des collections complete");
+            let reponse = ReponseVerifierRegeneration { ok: true, err: None, collections: None };
+            Ok(Some(middleware.build_reponse(reponse)?.0))
+        },
+        "comparer" => {
+            let mut collections = Vec::new();
+            for (nom_collection, champs_cle, filtre) in collections_verifiees() {
+                collections.push(comparer_collection(middleware, nom_collection, &champs_cle, filtre).await?);
+            }
+            // Les copies ne servent qu'a la comparaison
+            for (nom_collection, _, _) in collections_verifiees() {
+                middleware.get_collection(nom_collection_snapshot(nom_collection).as_str())?.drop(None).await?;
+            }
+            info!("commande_verifier_regeneration Comparaison complete, snapshots supprimes");
+            let reponse = ReponseVerifierRegeneration { ok: true, err: None, collections: Some(collections) };
+            Ok(Some(middleware.build_reponse(reponse)?.0))
+        },
+        _ => Ok(Some(reponse_erreur(middleware, ErreurMessages::RequeteInvalide, Some("Etape inconnue, doit etre snapshot ou comparer"))?))
+    }
+}
+
+async fn comparer_collection<M>(middleware: &M, nom_collection: &str, champs_cle: &Vec<&str>, filtre: Document)
+    -> Result<DifferencesCollection, Error>
+    where M: MongoDao
+{
+    let nom_snapshot = nom_collection_snapshot(nom_collection);
+    let collection_snapshot = middleware.get_collection(nom_snapshot.as_str())?;
+    let collection = middleware.get_collection(nom_collection)?;
+
+    let mut cles_snapshot = HashSet::new();
+    let mut manquants = Vec::new();
+    let mut differents = Vec::new();
+
+    let mut curseur = collection_snapshot.find(doc! {}, None).await?;
+    while curseur.advance().await? {
+        let doc_snapshot = curseur.deserialize_current()?;
+        let (filtre, cle) = filtre_cle(&doc_snapshot, champs_cle);
+        cles_snapshot.insert(cle.clone());
+        match collection.find_one(filtre, None).await? {
+            Some(doc_regenere) => {
+                if !documents_equivalents(&doc_snapshot, &doc_regenere) {
+                    differents.push(cle);
+                }
+            },
+            None => manquants.push(cle)
+        }
+    }
+
+    let mut en_trop = Vec::new();
+    let mut curseur = collection.find(filtre, None).await?;
+    while curseur.advance().await? {
+        let doc_regenere = curseur.deserialize_current()?;
+        let (_, cle) = filtre_cle(&doc_regenere, champs_cle);
+        if !cles_snapshot.contains(&cle) {
+            en_trop.push(cle);
+        }
+    }
+
+    debug!("comparer_collection {} : {} manquants, {} en trop, {} differents",
+        nom_collection, manquants.len(), en_trop.len(), differents.len());
+
+    let (nombre_manquants, nombre_en_trop, nombre_differents) = (manquants.len(), en_trop.len(), differents.len());
+    manquants.truncate(LIMITE_EXEMPLES_DIFFERENCES);
+    en_trop.truncate(LIMITE_EXEMPLES_DIFFERENCES);
+    differents.truncate(LIMITE_EXEMPLES_DIFFERENCES);
+
+    Ok(DifferencesCollection {
+        collection: nom_collection.to_string(),
+        manquants, en_trop, differents,
+        nombre_manquants, nombre_en_trop, nombre_differents,
+    })
+}
+
+/// Retourne le filtre pour trouver le document et une representation de la cle.
+fn filtre_cle(document: &Document, champs_cle: &Vec<&str>) -> (Document, String) {
+    let mut filtre = Document::new();
+    let mut valeurs = Vec::with_capacity(champs_cle.len());
+    for champ in champs_cle {
+        let valeur = document.get(*champ).cloned().unwrap_or(Bson::Null);
+        valeurs.push(match &valeur {
+            Bson::String(inner) => inner.to_owned(),
+            _ => valeur.to_string()
+        });
+        filtre.insert(*champ, valeur);
+    }
+    (filtre, valeurs.join("/"))
+}
+
+/// Compare deux documents sans tenir compte de l'ordre des champs ni des champs ignores.
+fn documents_equivalents(a: &Document, b: &Document) -> bool {
+    let champs_a: HashSet<&String> = a.keys().filter(|k| !CHAMPS_IGNORES.contains(&k.as_str())).collect();
+    let champs_b: HashSet<&String> = b.keys().filter(|k| !CHAMPS_IGNORES.contains(&k.as_str())).collect();
+    if champs_a != champs_b {
+        return false
+    }
+    champs_a.into_iter().all(|champ| a.get(champ) == b.get(champ))
+}
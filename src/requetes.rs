@@ -1,27 +1,35 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::str::from_utf8;
 use log::{debug, error};
-use millegrilles_common_rust::bson::{Bson, doc};
+use millegrilles_common_rust::bson;
+use millegrilles_common_rust::base64::{Engine as _, engine::general_purpose::STANDARD_NO_PAD as base64_nopad};
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::bson::oid::ObjectId;
 use millegrilles_common_rust::certificats::VerificateurPermissions;
+use millegrilles_common_rust::chrono;
 use millegrilles_common_rust::chrono::{DateTime, Utc};
 use millegrilles_common_rust::common_messages::RequeteDechiffrage;
-use millegrilles_common_rust::constantes::{DELEGATION_GLOBALE_PROPRIETAIRE, RolesCertificats, Securite, CHAMP_MODIFICATION, CHAMP_CREATION, DOMAINE_NOM_MAITREDESCLES, MAITREDESCLES_REQUETE_DECHIFFRAGE_V2};
+use millegrilles_common_rust::constantes::{DELEGATION_GLOBALE_PROPRIETAIRE, RolesCertificats, Securite, CHAMP_MODIFICATION, CHAMP_CREATION, DOMAINE_FICHIERS, DOMAINE_NOM_MAITREDESCLES, MAITREDESCLES_REQUETE_DECHIFFRAGE_V2};
 use millegrilles_common_rust::dechiffrage::DataChiffre;
 use millegrilles_common_rust::error::Error;
-use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction, RoutageMessageReponse};
 use millegrilles_common_rust::middleware::MiddlewareMessages;
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
-use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::serde_json;
 use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
 use millegrilles_common_rust::recepteur_messages::MessageValide;
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::epochseconds;
 use millegrilles_common_rust::mongodb::options::FindOptions;
 
 use serde::{Deserialize, Serialize};
+use crate::compteurs::{calculer_compteurs, CompteurBucket};
 use crate::constantes;
 use crate::constantes::{COLLECTION_FICHIERS_NOM, COLLECTION_RECEPTION_NOM, DOMAINE_NOM};
 use crate::domaine_messages::GestionnaireDomaineMessages;
-use crate::structures_messages::{MessageDb, MessageDbRef};
+use crate::erreurs::{ErreurMessages, reponse_erreur};
+use crate::metriques::metriques;
+use crate::structures_messages::{filtre_bucket, MessageDb, MessageDbRef};
 
 pub async fn consommer_requete<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -30,22 +38,39 @@ pub async fn consommer_requete<M>(gestionnaire: &GestionnaireDomaineMessages, mi
     debug!("consommer_requete : {:?}", &message.type_message);
     let (_user_id, _role_prive) = verifier_autorisation(&message)?;
 
-    let action = match &message.type_message {
-        TypeMessageOut::Requete(r) => r.action.clone(),
+    let (action, avec_reply_to) = match &message.type_message {
+        TypeMessageOut::Requete(r) => (r.action.clone(), r.reply_to.is_some()),
         _ => Err(Error::Str("grosfichiers.consommer_requete Mauvais type message, doit etre Requete"))?
     };
 
-    match action.as_str() {
+    let _garde = match gestionnaire.arret.debuter_tache(format!("requete {}", action)) {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::ArretEnCours, None)?))
+    };
+
+    let resultat = match action.as_str() {
         // Commandes standard
         constantes::REQUETE_SYNC_MESSAGES => requete_sync_messages(gestionnaire, middleware, message).await,
         constantes::REQUETE_MESSAGES_PAR_IDS => requete_messages_par_ids(gestionnaire, middleware, message).await,
         constantes::REQUETE_DECHIFFRER_CLES => requete_dechiffrer_cles(gestionnaire, middleware, message).await,
+        constantes::REQUETE_RECHERCHER_MESSAGES => requete_rechercher_messages(gestionnaire, middleware, message).await,
+        constantes::REQUETE_ACCES_FICHIERS => requete_acces_fichiers(gestionnaire, middleware, message).await,
+        constantes::REQUETE_COMPTEURS => requete_compteurs(gestionnaire, middleware, message).await,
 
         // Commande inconnue
         _ => Err(Error::String(format!("consommer_commande: Commande {} inconnue, **DROPPED**\n{}",
                                        action, from_utf8(message.message.buffer.as_slice())?)))?,
-    }
+    };
 
+    metriques().action("requete", action.as_str(), resultat.is_err());
+    match resultat {
+        Ok(inner) => Ok(inner),
+        Err(e) if avec_reply_to => {
+            error!("consommer_requete Erreur traitement requete {} : {:?}", action, e);
+            Ok(Some(reponse_erreur(middleware, ErreurMessages::ErreurInterne, None)?))
+        },
+        Err(e) => Err(e)
+    }
 }
 
 /// Verifier si le message est autorise a etre execute comme requete. Lance une erreur si le
@@ -101,17 +126,46 @@ struct ReponseSyncMessages {
     ok: bool,
     err: Option<String>,
     bucket: String,
-    messages: Vec<MessageSyncInfo>
+    messages: Vec<MessageSyncInfo>,
+    /// Curseur pour obtenir la page suivante. Absent lorsque la sync est completee.
+    #[serde(skip_serializing_if="Option::is_none")]
+    curseur: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct RequeteSyncMessages {
     bucket: String,
+    /// Obsolete, remplace par curseur.
     skip: Option<u64>,
     limit: Option<i64>,
+    /// Curseur opaque recu avec la page precedente.
+    curseur: Option<String>,
+}
+
+/// Encode la position (date de creation, _id) du dernier message d'une page.
+fn encoder_curseur(creation: &bson::DateTime, id: &ObjectId) -> String {
+    base64_nopad.encode(format!("{}:{}", creation.timestamp_millis(), id.to_hex()))
+}
+
+/// Decode un curseur et retourne le filtre des messages qui suivent la position.
+fn decoder_curseur(curseur: &str) -> Result<Document, Error> {
+    let valeur = String::from_utf8(base64_nopad.decode(curseur)?)
+        .map_err(|_| Error::Str("decoder_curseur Curseur invalide"))?;
+    let (creation, id) = match valeur.split_once(':') {
+        Some(inner) => inner,
+        None => Err(Error::Str("decoder_curseur Curseur invalide"))?
+    };
+    let creation = bson::DateTime::from_millis(creation.parse::<i64>()
+        .map_err(|_| Error::Str("decoder_curseur Date de curseur invalide"))?);
+    let id = ObjectId::parse_str(id)
+        .map_err(|_| Error::Str("decoder_curseur Id de curseur invalide"))?;
+    Ok(doc! {"$or": [
+        {CHAMP_CREATION: {"$gt": creation}},
+        {CHAMP_CREATION: creation, "_id": {"$gt": id}},
+    ]})
 }
 
-async fn requete_sync_messages<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+async fn requete_sync_messages<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao
 {
@@ -120,32 +174,46 @@ async fn requete_sync_messages<M>(_gestionnaire: &GestionnaireDomaineMessages, m
     let requete: RequeteSyncMessages = message_ref.contenu()?.deserialize()?;
 
     let skip = requete.skip.unwrap_or_else(|| 0);
-    let limit = requete.limit.unwrap_or_else(|| 1000);
+    let limite_sync = gestionnaire.config.limite_sync_messages;
+    let limit = match requete.limit {
+        Some(inner) => inner.clamp(1, limite_sync),
+        None => limite_sync
+    };
 
     let user_id = match message.certificat.get_user_id()? {
         Some(inner) => inner,
-        None => Err(Error::Str("requete_sync_messages Certificat sans user_id"))?
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
     };
 
-    let bucket: Bson = if requete.bucket.as_str() == "reception" {
-        let bucket = doc!{"$exists": false};
-        bucket.into()
-    } else {
-        Bson::String(requete.bucket.clone())
-    };
+    let bucket = filtre_bucket(requete.bucket.as_str());
 
-    let filtre = doc! {"user_id": &user_id, "bucket": bucket};
-    let options = FindOptions::builder()
-        .skip(skip)
+    let mut filtre = doc! {"user_id": &user_id, "bucket": bucket};
+    let mut options = FindOptions::builder()
         .limit(limit)
-        .projection(doc!{"message_id": 1, CHAMP_MODIFICATION: 1, "supprime": 1, "date_traitement": 1})
+        .projection(doc!{"message_id": 1, CHAMP_MODIFICATION: 1, CHAMP_CREATION: 1, "supprime": 1, "date_traitement": 1})
         .sort(doc!{CHAMP_CREATION: 1, "_id": 1})
         .build();
+    match requete.curseur.as_ref() {
+        Some(curseur) => {
+            let filtre_curseur = match decoder_curseur(curseur.as_str()) {
+                Ok(inner) => inner,
+                Err(e) => {
+                    debug!("requete_sync_messages Curseur invalide : {:?}", e);
+                    return Ok(Some(reponse_erreur(middleware, ErreurMessages::RequeteInvalide, Some("Curseur invalide"))?))
+                }
+            };
+            filtre.extend(filtre_curseur);
+        },
+        None => options.skip = Some(skip)
+    }
     let collection = middleware.get_collection_typed::<MessageDbRef>(COLLECTION_RECEPTION_NOM)?;
     let mut curseur = collection.find(filtre, options).await?;
     let mut resultat = Vec::with_capacity(limit as usize);
+    let mut position = None;
+    let mut nombre_rows = 0;
     while curseur.advance().await? {
-        let row = match curseur.deserialize_current() {
+        nombre_rows += 1;
+        let row: MessageDbRef = match curseur.deserialize_current() {
             Ok(inner) => inner,
             Err(e) => {
                 error!("requete_sync_messages Erreur mapping row message, skip : {:?}", e);
@@ -153,15 +221,25 @@ async fn requete_sync_messages<M>(_gestionnaire: &GestionnaireDomaineMessages, m
             }
         };
 
+        if let (Some(creation), Some(id)) = (row.creation.as_ref(), row.id.as_ref()) {
+            position = Some(encoder_curseur(creation, id));
+        }
         let message_sync = MessageSyncInfo::from(row);
         resultat.push(message_sync);
     }
 
+    // Une page incomplete indique la fin de la sync
+    let curseur = match nombre_rows < limit {
+        true => None,
+        false => position
+    };
+
     let reponse = ReponseSyncMessages {
         ok: true,
         err: None,
         bucket: requete.bucket,
         messages: resultat,
+        curseur,
     };
 
     Ok(Some(middleware.build_reponse(reponse)?.0))
@@ -169,7 +247,9 @@ async fn requete_sync_messages<M>(_gestionnaire: &GestionnaireDomaineMessages, m
 
 #[derive(Deserialize)]
 struct RequeteMessagesParIds {
-    message_ids: Vec<String>
+    message_ids: Vec<String>,
+    /// Si true, les messages sont transmis dans plusieurs reponses sur le meme correlation_id.
+    stream: Option<bool>,
 }
 
 #[derive(Serialize)]
@@ -202,6 +282,15 @@ struct ReponseMessagesParIds {
     ok: bool,
     err: Option<String>,
     messages: Vec<MessageReponse>,
+    /// Messages non inclus pour respecter la taille maximale de la reponse, a redemander.
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    message_ids_restants: Vec<String>,
+    /// Mode stream : numero de la reponse, en commencant a 0.
+    #[serde(skip_serializing_if="Option::is_none")]
+    sequence: Option<usize>,
+    /// Mode stream : true sur la derniere reponse.
+    #[serde(skip_serializing_if="Option::is_none")]
+    termine: Option<bool>,
 }
 
 async fn requete_messages_par_ids<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
@@ -214,20 +303,72 @@ async fn requete_messages_par_ids<M>(_gestionnaire: &GestionnaireDomaineMessages
 
     let user_id = match message.certificat.get_user_id()? {
         Some(inner) => inner,
-        None => Err(Error::Str("requete_sync_messages Certificat sans user_id"))?
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+
+    // Le mode stream requiert une adresse de reponse pour les reponses partielles
+    let routage_stream = match requete.stream.unwrap_or(false) {
+        true => match &message.type_message {
+            TypeMessageOut::Requete(r) => match (r.reply_to.as_ref(), r.correlation_id.as_ref()) {
+                (Some(reply_q), Some(correlation_id)) => Some((reply_q.to_owned(), correlation_id.to_owned())),
+                _ => None
+            },
+            _ => None
+        },
+        false => None
     };
 
+    let limite = match routage_stream {
+        Some(_) => constantes::LIMITE_MESSAGES_PAR_IDS_STREAM,
+        None => constantes::LIMITE_MESSAGES_PAR_IDS
+    };
+    if requete.message_ids.len() > limite {
+        let err = format!("Maximum de {} message_ids par requete", limite);
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::LimiteDepassee, Some(err.as_str()))?))
+    }
+
     let filtre = doc! {"user_id": &user_id, "message_id": {"$in": requete.message_ids}};
     let collection = middleware.get_collection_typed::<MessageDb>(COLLECTION_RECEPTION_NOM)?;
 
     let mut messages: Vec<MessageReponse> = Vec::new();
+    let mut message_ids_restants = Vec::new();
+    let mut taille_reponse = 0;
+    let mut sequence = 0;
     let mut curseur = collection.find(filtre, None).await?;
     while curseur.advance().await? {
-        let row = curseur.deserialize_current()?;
+        let row: MessageDb = curseur.deserialize_current()?;
+        // Taille approximative, le contenu chiffre domine la taille du message
+        let taille_message = row.message.data_chiffre.len() + 512;
+        if messages.len() > 0 && taille_reponse + taille_message > constantes::TAILLE_MAX_REPONSE {
+            match routage_stream.as_ref() {
+                Some((reply_q, correlation_id)) => {
+                    // Transmettre une reponse partielle et continuer avec une nouvelle batch
+                    let reponse = ReponseMessagesParIds {
+                        ok: true, err: None, messages, message_ids_restants: Vec::new(),
+                        sequence: Some(sequence), termine: Some(false),
+                    };
+                    let routage = RoutageMessageReponse::new(reply_q, correlation_id);
+                    middleware.repondre(routage, reponse).await?;
+                    debug!("requete_messages_par_ids Reponse partielle {} transmise", sequence);
+                    sequence += 1;
+                    messages = Vec::new();
+                    taille_reponse = 0;
+                },
+                None => {
+                    message_ids_restants.push(row.message_id);
+                    continue
+                }
+            }
+        }
+        taille_reponse += taille_message;
         messages.push(row.into());
     }
 
-    let reponse = ReponseMessagesParIds { ok: true, err: None, messages };
+    let (sequence, termine) = match routage_stream {
+        Some(_) => (Some(sequence), Some(true)),
+        None => (None, None)
+    };
+    let reponse = ReponseMessagesParIds { ok: true, err: None, messages, message_ids_restants, sequence, termine };
 
     Ok(Some(middleware.build_reponse(reponse)?.0))
 }
@@ -257,6 +398,19 @@ struct FichierCle<'a> {
     cle_id: &'a str,
 }
 
+#[derive(Serialize)]
+struct ReponseDechiffrerCles {
+    ok: bool,
+    code: Option<usize>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    raison: Option<&'static str>,
+    err: Option<String>,
+    cles: Option<Vec<serde_json::Value>>,
+    /// Cle_ids demandees qui n'appartiennent pas a l'usager ou qui n'existent pas. Transmis
+    /// uniquement lorsque toutes les cles sont refusees, sinon le maitre des cles repond.
+    refuses: Vec<String>,
+}
+
 async fn requete_dechiffrer_cles<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: GenerateurMessages + MongoDao
@@ -265,15 +419,20 @@ async fn requete_dechiffrer_cles<M>(_gestionnaire: &GestionnaireDomaineMessages,
     let message_ref = message.message.parse()?;
     let requete: RequeteDechiffrerCles = message_ref.contenu()?.deserialize()?;
 
-    let (reply_to, correlation_id) = match message.type_message {
+    if requete.cle_ids.len() > constantes::LIMITE_CLE_IDS_DECHIFFRAGE {
+        let err = format!("Maximum de {} cle_ids par requete", constantes::LIMITE_CLE_IDS_DECHIFFRAGE);
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::LimiteDepassee, Some(err.as_str()))?))
+    }
+
+    let (reply_to, correlation_id) = match &message.type_message {
         TypeMessageOut::Requete(r) => {
-            let reply_to = match r.reply_to {
-                Some(inner) => inner,
-                None => Err("requete_dechiffrer_cles Requete sans reply_to")?
+            let reply_to = match r.reply_to.as_ref() {
+                Some(inner) => inner.to_owned(),
+                None => Err(Error::Str("requete_dechiffrer_cles Requete sans reply_to"))?
             };
-            let correlation_id = match r.correlation_id {
-                Some(inner) => inner,
-                None => Err("requete_dechiffrer_cles Requete sans correlation_id")?
+            let correlation_id = match r.correlation_id.as_ref() {
+                Some(inner) => inner.to_owned(),
+                None => Err(Error::Str("requete_dechiffrer_cles Requete sans correlation_id"))?
             };
             (reply_to, correlation_id)
         },
@@ -282,14 +441,14 @@ async fn requete_dechiffrer_cles<M>(_gestionnaire: &GestionnaireDomaineMessages,
 
     let user_id = match message.certificat.get_user_id()? {
         Some(inner) => inner,
-        None => Err(Error::Str("requete_dechiffrer_cles Certificat sans user_id"))?
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
     };
 
     // S'assurer que l'usager a acces au cles demandees
     let mut cles = HashSet::new();
 
     if let Some(true) = requete.cles_fichiers {
-        let filtre = doc!{"user_id": &user_id, "cle_id": {"$in": &requete.cle_ids}};
+        let filtre = doc!{"user_id": &user_id, "cle_id": {"$in": &requete.cle_ids}, "supprime": {"$ne": true}};
         let collection = middleware.get_collection_typed::<FichierCle>(COLLECTION_FICHIERS_NOM)?;
         let options = FindOptions::builder().projection(doc!("message_id": 1, "fuuid": 1, "cle_id": 1)).build();
         let mut curseur = collection.find(filtre, options).await?;
@@ -308,10 +467,17 @@ async fn requete_dechiffrer_cles<M>(_gestionnaire: &GestionnaireDomaineMessages,
         }
     }
 
+    let refuses: Vec<String> = requete.cle_ids.iter()
+        .filter(|c| !cles.contains(c.as_str()))
+        .map(|c| c.to_owned())
+        .collect();
+
     if cles.len() > 0 {
-        debug!("requete_dechiffrer_cles Requete pour dechiffrer {} cles", cles.len());
+        debug!("requete_dechiffrer_cles Requete pour dechiffrer {} cles ({} refusees)", cles.len(), refuses.len());
         let cles: Vec<String> = cles.into_iter().collect();
 
+        // Une seule reponse par correlation_id : celle du maitre des cles. Les cles refusees
+        // sont absentes de sa reponse.
         let routage = RoutageMessageAction::builder(
             DOMAINE_NOM_MAITREDESCLES, MAITREDESCLES_REQUETE_DECHIFFRAGE_V2, vec![Securite::L3Protege])
             .reply_to(reply_to)
@@ -331,6 +497,248 @@ async fn requete_dechiffrer_cles<M>(_gestionnaire: &GestionnaireDomaineMessages,
         Ok(None)
     } else {
         // Refuse, les cles n'appartiennent pas a l'usager ou n'existent pas
-        Ok(Some(middleware.reponse_err(1, None, Some("Acces refuse"))?))
+        let reponse = ReponseDechiffrerCles {
+            ok: false,
+            code: Some(ErreurMessages::AccesRefuse.code()),
+            raison: Some(ErreurMessages::AccesRefuse.raison()),
+            err: Some("Acces refuse".to_string()),
+            cles: None,
+            refuses,
+        };
+        Ok(Some(middleware.build_reponse(reponse)?.0))
+    }
+}
+
+#[derive(Deserialize)]
+struct RequeteRechercherMessages {
+    /// Tokens HMAC des termes recherches.
+    tokens: Vec<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResultatRechercheMessage {
+    message_id: String,
+    /// Nombre de tokens de la requete presents dans l'index du message.
+    hits: i32,
+    #[serde(serialize_with="epochseconds::serialize", deserialize_with="bson::serde_helpers::chrono_datetime_as_bson_datetime::deserialize")]
+    date_traitement: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ReponseRechercherMessages {
+    ok: bool,
+    err: Option<String>,
+    resultats: Vec<ResultatRechercheMessage>,
+}
+
+async fn requete_rechercher_messages<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_rechercher_messages Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    let message_ref = message.message.parse()?;
+    let requete: RequeteRechercherMessages = message_ref.contenu()?.deserialize()?;
+
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+
+    if requete.tokens.is_empty() || requete.tokens.len() > constantes::LIMITE_TOKENS_RECHERCHE {
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::RequeteInvalide, Some("Nombre de tokens de recherche invalide"))?))
     }
-}
\ No newline at end of file
+
+    let limit = match requete.limit {
+        Some(inner) => inner.clamp(1, constantes::LIMITE_RESULTATS_RECHERCHE),
+        None => constantes::LIMITE_RESULTATS_RECHERCHE
+    };
+
+    // Le filtre user_id garantit que seuls les messages de l'usager sont consideres
+    let pipeline = vec![
+        doc! {"$match": {
+            constantes::CHAMP_USER_ID: &user_id,
+            constantes::CHAMP_INDEX_RECHERCHE: {"$in": &requete.tokens},
+        }},
+        doc! {"$project": {
+            "_id": 0,
+            "message_id": 1,
+            "date_traitement": 1,
+            "hits": {"$size": {"$setIntersection": [format!("${}", constantes::CHAMP_INDEX_RECHERCHE), &requete.tokens]}},
+        }},
+        doc! {"$sort": {"hits": -1, "date_traitement": -1}},
+        doc! {"$limit": limit},
+    ];
+    let collection = middleware.get_collection(COLLECTION_RECEPTION_NOM)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    let mut resultats = Vec::new();
+    while curseur.advance().await? {
+        let row = curseur.deserialize_current()?;
+        let resultat: ResultatRechercheMessage = convertir_bson_deserializable(row)?;
+        resultats.push(resultat);
+    }
+
+    let reponse = ReponseRechercherMessages { ok: true, err: None, resultats };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteAccesFichiers {
+    fuuids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct FichierAccesDb {
+    message_id: String,
+    fuuid: String,
+    cle_id: String,
+    format: String,
+    nonce: Option<String>,
+    verification: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FichierAcces {
+    fuuid: String,
+    cle_id: String,
+    format: String,
+    #[serde(skip_serializing_if="Option::is_none")]
+    nonce: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    verification: Option<String>,
+}
+
+impl From<FichierAccesDb> for FichierAcces {
+    fn from(value: FichierAccesDb) -> Self {
+        Self {
+            fuuid: value.fuuid,
+            cle_id: value.cle_id,
+            format: value.format,
+            nonce: value.nonce,
+            verification: value.verification,
+        }
+    }
+}
+
+/// Autorisation signee par le domaine, verifiee par le service de fichiers.
+/// Le type et l'audience empechent de reutiliser l'autorisation avec un autre service.
+#[derive(Serialize)]
+struct AutorisationFichiers<'a> {
+    #[serde(rename="type")]
+    type_autorisation: &'a str,
+    audience: &'a str,
+    user_id: &'a str,
+    fuuids: Vec<&'a str>,
+    #[serde(with="epochseconds")]
+    expiration: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ReponseAccesFichiers {
+    ok: bool,
+    err: Option<String>,
+    fichiers: Vec<FichierAcces>,
+    /// Fuuids inconnus ou qui ne sont pas references par un message de l'usager.
+    refuses: Vec<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    autorisation: Option<serde_json::Value>,
+}
+
+async fn requete_acces_fichiers<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_acces_fichiers Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    let message_ref = message.message.parse()?;
+    let requete: RequeteAccesFichiers = message_ref.contenu()?.deserialize()?;
+
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+
+    if requete.fuuids.is_empty() || requete.fuuids.len() > constantes::LIMITE_FUUIDS_ACCES {
+        return Ok(Some(reponse_erreur(middleware, ErreurMessages::RequeteInvalide, Some("Nombre de fuuids invalide"))?))
+    }
+
+    // Charger les fichiers de l'usager pour les fuuids demandes
+    let filtre = doc!{"user_id": &user_id, "fuuid": {"$in": &requete.fuuids}, "supprime": {"$ne": true}};
+    let collection = middleware.get_collection_typed::<FichierAccesDb>(COLLECTION_FICHIERS_NOM)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    let mut fichiers_db = Vec::new();
+    while curseur.advance().await? {
+        fichiers_db.push(curseur.deserialize_current()?);
+    }
+
+    // Verifier que les messages qui referencent les fichiers existent toujours pour l'usager
+    let message_ids: Vec<&str> = fichiers_db.iter().map(|f| f.message_id.as_str()).collect();
+    let filtre = doc!{"user_id": &user_id, "message_id": {"$in": message_ids}, "supprime": {"$ne": true}};
+    let collection = middleware.get_collection_typed::<MessageDbRef>(COLLECTION_RECEPTION_NOM)?;
+    let options = FindOptions::builder()
+        .projection(doc!{"message_id": 1, CHAMP_MODIFICATION: 1, "date_traitement": 1})
+        .build();
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut messages_actifs = HashSet::new();
+    while curseur.advance().await? {
+        let row = curseur.deserialize_current()?;
+        messages_actifs.insert(row.message_id.to_string());
+    }
+
+    let mut fichiers: HashMap<String, FichierAcces> = HashMap::new();
+    for fichier in fichiers_db {
+        if messages_actifs.contains(&fichier.message_id) {
+            fichiers.insert(fichier.fuuid.clone(), fichier.into());
+        }
+    }
+
+    let refuses: Vec<String> = requete.fuuids.iter()
+        .filter(|f| !fichiers.contains_key(f.as_str()))
+        .map(|f| f.to_owned())
+        .collect();
+
+    let autorisation = if fichiers.len() > 0 {
+        let autorisation = AutorisationFichiers {
+            type_autorisation: constantes::TYPE_AUTORISATION_FICHIERS,
+            audience: DOMAINE_FICHIERS,
+            user_id: user_id.as_str(),
+            fuuids: fichiers.keys().map(|f| f.as_str()).collect(),
+            expiration: Utc::now() + chrono::Duration::seconds(constantes::DUREE_AUTORISATION_FICHIERS),
+        };
+        let autorisation = middleware.build_reponse(&autorisation)?.0;
+        Some(serde_json::from_slice(autorisation.buffer.as_slice())?)
+    } else {
+        None
+    };
+
+    let reponse = ReponseAccesFichiers {
+        ok: true,
+        err: None,
+        fichiers: fichiers.into_values().collect(),
+        refuses,
+        autorisation,
+    };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Serialize)]
+struct ReponseCompteurs {
+    ok: bool,
+    err: Option<String>,
+    compteurs: Vec<CompteurBucket>,
+}
+
+async fn requete_compteurs<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_compteurs Message recu {:?}", message.type_message);
+
+    let user_id = match message.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => return Ok(Some(reponse_erreur(middleware, ErreurMessages::AccesRefuse, Some("Certificat sans user_id"))?))
+    };
+
+    let compteurs = calculer_compteurs(middleware, user_id.as_str()).await?;
+    let reponse = ReponseCompteurs { ok: true, err: None, compteurs };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
@@ -0,0 +1,196 @@
+use std::sync::Mutex;
+
+use log::{debug, error, info, warn};
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::middleware::Middleware;
+use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::tokio;
+use millegrilles_common_rust::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use millegrilles_common_rust::tokio::net::{TcpListener, TcpStream};
+use serde::Serialize;
+
+use crate::constantes::COLLECTION_USAGERS_NOM;
+use crate::domaine_messages::GestionnaireDomaineMessages;
+use crate::metriques::exporter_metriques;
+
+/// Delai maximal (secondes) d'une verification de MongoDB.
+const TIMEOUT_PING_MONGO_SECS: u64 = 2;
+/// Delai maximal (secondes) de lecture de la requete HTTP.
+const TIMEOUT_LECTURE_SECS: u64 = 5;
+
+/// Etat de sante du service mis a jour par les threads du domaine.
+#[derive(Default)]
+pub struct EtatSante {
+    inner: Mutex<EtatSanteInner>,
+}
+
+#[derive(Clone, Default)]
+struct EtatSanteInner {
+    /// Resultat de la derniere verification de redis. None si redis n'est pas configure.
+    redis_ok: Option<bool>,
+    /// Resultat du dernier chargement des certificats du maitre des cles.
+    certificats_maitredescles: bool,
+    /// Date de la derniere passe complete de thread_entretien.
+    dernier_entretien: Option<DateTime<Utc>>,
+}
+
+impl EtatSante {
+    pub fn set_chargement_certificats(&self, ok: bool) {
+        self.inner.lock().expect("lock sante").certificats_maitredescles = ok;
+    }
+
+    pub fn set_redis(&self, ok: bool) {
+        self.inner.lock().expect("lock sante").redis_ok = Some(ok);
+    }
+
+    pub fn set_entretien(&self) {
+        self.inner.lock().expect("lock sante").dernier_entretien = Some(Utc::now());
+    }
+
+    fn get(&self) -> EtatSanteInner {
+        self.inner.lock().expect("lock sante").clone()
+    }
+}
+
+#[derive(Serialize)]
+struct ReponseSante {
+    ok: bool,
+    /// Etat de la connexion MQ du middleware.
+    mq: bool,
+    /// Verifie seulement avec /ready.
+    #[serde(skip_serializing_if="Option::is_none")]
+    mongo: Option<bool>,
+    redis: Option<bool>,
+    certificats_maitredescles: bool,
+    /// Age (secondes) de la derniere passe d'entretien.
+    age_entretien: Option<i64>,
+}
+
+/// Serveur HTTP local de sante. GET /health verifie que le thread d'entretien est actif,
+/// GET /ready verifie aussi les connexions MQ, MongoDB et redis. GET /metrics expose les
+/// metriques Prometheus.
+pub async fn thread_sante<M>(gestionnaire: &'static GestionnaireDomaineMessages, middleware: &'static M)
+    where M: Middleware + 'static
+{
+    let adresse = gestionnaire.config.adresse_sante.as_str();
+    let listener = match TcpListener::bind(adresse).await {
+        Ok(inner) => inner,
+        Err(e) => {
+            // Le domaine fonctionne sans le serveur de sante
+            error!("thread_sante Erreur ouverture {} : {:?}, serveur de sante desactive", adresse, e);
+            return std::future::pending().await
+        }
+    };
+    info!("thread_sante Serveur de sante sur {}", adresse);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("thread_sante Erreur accept : {:?}", e);
+                continue
+            }
+        };
+        // Une connexion lente ne bloque pas les autres verifications
+        tokio::spawn(async move {
+            if let Err(e) = traiter_connexion(gestionnaire, middleware, stream).await {
+                debug!("thread_sante Erreur connexion : {:?}", e);
+            }
+        });
+    }
+}
+
+async fn traiter_connexion<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, mut stream: TcpStream)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let mut buffer = [0u8; 1024];
+    let duree_lecture = tokio::time::Duration::from_secs(TIMEOUT_LECTURE_SECS);
+    let taille = match tokio::time::timeout(duree_lecture, stream.read(&mut buffer)).await {
+        Ok(inner) => inner.map_err(|e| Error::String(format!("traiter_connexion Erreur lecture : {:?}", e)))?,
+        Err(_) => Err(Error::Str("traiter_connexion Timeout lecture requete"))?
+    };
+    let requete = String::from_utf8_lossy(&buffer[..taille]);
+    let chemin = requete.lines().next().unwrap_or("").split_whitespace().nth(1).unwrap_or("");
+
+    if chemin == "/metrics" {
+        let contenu = exporter_metriques()?;
+        return ecrire_reponse(&mut stream, 200, "text/plain; version=0.0.4", contenu.as_str()).await
+    }
+
+    let etat = gestionnaire.sante.get();
+    let mq_ok = middleware.mq_disponible();
+    let age_entretien = etat.dernier_entretien.map(|d| (Utc::now() - d).num_seconds());
+    // Le thread d'entretien est considere bloque apres 3 intervalles sans passe complete
+    let entretien_ok = match age_entretien {
+        Some(age) => age <= 3 * gestionnaire.config.intervalle_entretien_secs as i64,
+        None => false
+    };
+
+    let (statut, reponse) = match chemin {
+        "/health" => {
+            let reponse = ReponseSante {
+                ok: entretien_ok, mq: mq_ok, mongo: None, redis: etat.redis_ok,
+                certificats_maitredescles: etat.certificats_maitredescles, age_entretien,
+            };
+            (if entretien_ok { 200 } else { 503 }, Some(reponse))
+        },
+        "/ready" => {
+            let mongo = ping_mongo(middleware).await;
+            let ok = entretien_ok && mongo && mq_ok &&
+                etat.redis_ok != Some(false) && etat.certificats_maitredescles;
+            let reponse = ReponseSante {
+                ok, mq: mq_ok, mongo: Some(mongo), redis: etat.redis_ok,
+                certificats_maitredescles: etat.certificats_maitredescles, age_entretien,
+            };
+            (if ok { 200 } else { 503 }, Some(reponse))
+        },
+        _ => (404, None)
+    };
+
+    let contenu = match reponse {
+        Some(inner) => serde_json::to_string(&inner)?,
+        None => String::new()
+    };
+    ecrire_reponse(&mut stream, statut, "application/json", contenu.as_str()).await
+}
+
+async fn ecrire_reponse(stream: &mut TcpStream, statut: u16, content_type: &str, contenu: &str)
+    -> Result<(), Error>
+{
+    let texte_statut = match statut {
+        200 => "OK",
+        404 => "Not Found",
+        _ => "Service Unavailable",
+    };
+    let reponse_http = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        statut, texte_statut, content_type, contenu.len(), contenu);
+    stream.write_all(reponse_http.as_bytes()).await
+        .map_err(|e| Error::String(format!("ecrire_reponse Erreur ecriture : {:?}", e)))?;
+    Ok(())
+}
+
+async fn ping_mongo<M>(middleware: &M) -> bool
+    where M: MongoDao
+{
+    let collection = match middleware.get_collection(COLLECTION_USAGERS_NOM) {
+        Ok(inner) => inner,
+        Err(_) => return false
+    };
+    let duree = tokio::time::Duration::from_secs(TIMEOUT_PING_MONGO_SECS);
+    match tokio::time::timeout(duree, collection.estimated_document_count(None)).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            warn!("ping_mongo Erreur MongoDB : {:?}", e);
+            false
+        },
+        Err(_) => {
+            warn!("ping_mongo Timeout MongoDB");
+            false
+        }
+    }
+}
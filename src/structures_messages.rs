@@ -2,14 +2,34 @@ use millegrilles_common_rust::chrono::{DateTime, Utc};
 use millegrilles_common_rust::dechiffrage::{DataChiffre, DataChiffreBorrow};
 use serde::Deserialize;
 use millegrilles_common_rust::bson;
+use millegrilles_common_rust::bson::{doc, Bson};
+use millegrilles_common_rust::bson::oid::ObjectId;
+
+/// Bucket des messages recus, correspond aux messages sans champ bucket.
+pub const BUCKET_RECEPTION: &str = "reception";
+
+/// Retourne la valeur de filtre mongo pour un bucket.
+pub fn filtre_bucket(bucket: &str) -> Bson {
+    if bucket == BUCKET_RECEPTION {
+        let bucket = doc!{"$exists": false};
+        bucket.into()
+    } else {
+        Bson::String(bucket.to_string())
+    }
+}
 
 #[derive(Deserialize)]
 pub struct MessageDbRef<'a> {
+    #[serde(rename="_id")]
+    pub id: Option<ObjectId>,
     pub message_id: &'a str,
     #[serde(rename="_mg-derniere-modification", with="bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub derniere_modification: DateTime<Utc>,
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub date_traitement: DateTime<Utc>,
+    /// Date de creation, utilisee avec _id pour la pagination.
+    #[serde(rename="_mg-creation")]
+    pub creation: Option<bson::DateTime>,
 
     /// Champs optionnels pour permettre projection reduite (e.g. pour sync)
     pub lu: Option<bool>,
@@ -1,11 +1,11 @@
 use millegrilles_common_rust::bson::doc;
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
-use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::chrono::{DateTime, Utc};
 use millegrilles_common_rust::db_structs::TransactionValide;
 use millegrilles_common_rust::dechiffrage::DataChiffre;
 use millegrilles_common_rust::error::Error;
 use millegrilles_common_rust::generateur_messages::GenerateurMessages;
-use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{MessageMilleGrillesBufferDefault, optionepochseconds};
 use millegrilles_common_rust::mongo_dao::{convertir_to_bson, MongoDao};
 use millegrilles_common_rust::serde_json;
 use millegrilles_common_rust::constantes as CommonConstantes;
@@ -13,10 +13,12 @@ use millegrilles_common_rust::mongodb::options::UpdateOptions;
 
 use serde::{Deserialize, Serialize};
 use crate::commandes::MessageFichierV1;
+use crate::courriel::TransfertCourriel;
 use crate::constantes;
-use crate::constantes::{COLLECTION_FICHIERS_NOM, COLLECTION_RECEPTION_NOM};
+use crate::constantes::{COLLECTION_FICHIERS_NOM, COLLECTION_RECEPTION_NOM, COLLECTION_USAGERS_NOM, DOMAINE_NOM};
 
 use crate::domaine_messages::GestionnaireDomaineMessages;
+use crate::envoi_evenements::{ajouter_evenement, evenement_id, EvenementMessages, EvenementNouveauMessage};
 
 pub async fn aiguillage_transaction<M, T>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, transaction: T)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -37,10 +39,16 @@ pub async fn aiguillage_transaction<M, T>(gestionnaire: &GestionnaireDomaineMess
         None => Err(format!("transactions.aiguillage_transaction Transaction sans routage : {}", transaction.transaction.id))?
     };
 
+    // La transaction est deja sauvegardee, son traitement complete meme durant l'arret
+    let _garde = gestionnaire.arret.suivre_tache(format!("transaction {}", action));
+
     match action.as_str() {
         constantes::COMMANDE_POSTER_V1 => transaction_poster_v1(gestionnaire, middleware, transaction).await,
         constantes::COMMANDE_MARQUER_LU => transaction_marquer_lu(gestionnaire, middleware, transaction).await,
         constantes::COMMANDE_SUPPRIMER_MESSAGE => transaction_supprimer_message(gestionnaire, middleware, transaction).await,
+        constantes::COMMANDE_INDEXER_MESSAGE => transaction_indexer_message(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_ASSIGNER_CLE_USAGER => transaction_assigner_cle_usager(gestionnaire, middleware, transaction).await,
+        constantes::COMMANDE_CONFIGURER_TRANSFERT_COURRIEL => transaction_configurer_transfert_courriel(gestionnaire, middleware, transaction).await,
         _ => Err(format!("transactions.aiguillage_transaction: Transaction {} est de type non gere : {}", transaction.transaction.id, action))?
     }
 }
@@ -70,42 +78,127 @@ impl From<&MessageFichierV1> for FichierMessage {
     }
 }
 
+/// Information non chiffree sur le message, ajoutee avec la version 2 des transactions.
+#[derive(Serialize, Deserialize)]
+pub struct MetadataMessageV2 {
+    /// User_id du certificat de l'expediteur lorsque le message provient d'un usager local.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub expediteur: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub conversation_id: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none", with="optionepochseconds")]
+    pub expiration: Option<DateTime<Utc>>,
+    /// Taille du contenu dechiffre du message en bytes.
+    pub taille: i64,
+    pub nombre_fichiers: i64,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TransactionRecevoirMessage {
+    /// Message_id deterministe (diffusion, livraison). L'id de la transaction est utilise si absent.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    message_id: Option<String>,
     user_id: String,
     message: DataChiffre,
     #[serde(skip_serializing_if="Option::is_none")]
     fichiers: Option<Vec<FichierMessage>>,
     version: u16,
+    /// Present a partir de la version 2.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    metadata: Option<MetadataMessageV2>,
 }
 
 impl TransactionRecevoirMessage {
-    pub fn new<S>(user_id: S, message: DataChiffre, fichiers: Option<Vec<FichierMessage>>) -> Self
+    pub fn new<S>(message_id: Option<String>, user_id: S, message: DataChiffre, fichiers: Option<Vec<FichierMessage>>, metadata: MetadataMessageV2) -> Self
         where S: ToString
     {
-        Self { user_id: user_id.to_string(), message, fichiers, version: constantes::VERSION_TRANSACTION_MESSAGE_1 }
+        Self {
+            message_id,
+            user_id: user_id.to_string(),
+            message,
+            fichiers,
+            version: constantes::VERSION_TRANSACTION_MESSAGE_2,
+            metadata: Some(metadata),
+        }
     }
 }
 
-async fn transaction_poster_v1<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, transaction: TransactionValide)
+/// Conserve un message recu et son evenement nouveauMessage.
+///
+/// Les ecritures (reception, fichiers, evenement) ne sont pas dans une transaction mongo. Une
+/// transaction n'est marquee completee qu'apres le retour de cette fonction : une interruption
+/// entre deux ecritures fait rejouer la transaction au complet. Chaque ecriture est un upsert
+/// `$setOnInsert` sur une cle deterministe (message_id, fuuid, evenement_id), le traitement
+/// repete ne modifie donc pas les documents deja inseres et complete ceux qui manquent. Un
+/// evenement rejoue apres DUREE_CONSERVATION_EVENEMENTS_HEURES est ignore, le client se
+/// resynchronise alors avec la requete de messages.
+async fn transaction_poster_v1<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, transaction: TransactionValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: MongoDao
 {
-    let message_id = transaction.transaction.id;
     let message_recu: TransactionRecevoirMessage = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+    let message_id = match message_recu.message_id.as_ref() {
+        Some(inner) => inner.to_owned(),
+        None => transaction.transaction.id
+    };
     let user_id = message_recu.user_id;
     let estampille = transaction.transaction.estampille;
 
+    // Les transactions historiques (v1) doivent toujours pouvoir etre rejouees
+    let metadata = match message_recu.version {
+        constantes::VERSION_TRANSACTION_MESSAGE_1 => None,
+        constantes::VERSION_TRANSACTION_MESSAGE_2 => match message_recu.metadata {
+            Some(inner) => Some(inner),
+            None => Err(format!("transaction_poster_v1 Transaction v2 sans metadata : {}", message_id))?
+        },
+        version => Err(format!("transaction_poster_v1 Version de transaction non supportee {} : {}", version, message_id))?
+    };
+
     let filtre = doc! {"message_id": &message_id};
+    let taille_chiffre = message_recu.message.data_chiffre.len();
     let datachiffre_value = convertir_to_bson(message_recu.message)?;
+
+    // Inclure le contenu chiffre dans l'evenement s'il est sous le seuil
+    let taille_max_evenement = gestionnaire.config.taille_max_message_evenement;
+    let message_evenement = match taille_max_evenement > 0 && taille_chiffre <= taille_max_evenement {
+        true => datachiffre_value.as_document().cloned(),
+        false => None
+    };
+    let mut evenement = EvenementNouveauMessage {
+        evenement_id: evenement_id(message_id.as_str(), constantes::EVENEMENT_NOUVEAU_MESSAGE),
+        message_id: message_id.to_string(),
+        user_id: user_id.clone(),
+        date_traitement: estampille.clone(),
+        conversation_id: None, nombre_fichiers: None, taille: None,
+        message: message_evenement,
+    };
+
+    let mut set_on_insert = doc! {
+        "user_id": &user_id,
+        "message": datachiffre_value,
+        "date_traitement": &estampille,
+        "lu": false,
+        // Utiliser l'estampille de la transaction pour conserver l'ordre lors d'une regeneration
+        CommonConstantes::CHAMP_CREATION: &estampille,
+    };
+    if let Some(metadata) = metadata {
+        evenement.conversation_id = metadata.conversation_id.clone();
+        evenement.nombre_fichiers = Some(metadata.nombre_fichiers);
+        evenement.taille = Some(metadata.taille);
+        set_on_insert.insert("taille", metadata.taille);
+        set_on_insert.insert("nombre_fichiers", metadata.nombre_fichiers);
+        if let Some(inner) = metadata.expediteur {
+            set_on_insert.insert("expediteur", inner);
+        }
+        if let Some(inner) = metadata.conversation_id {
+            set_on_insert.insert("conversation_id", inner);
+        }
+        if let Some(inner) = metadata.expiration {
+            set_on_insert.insert("expiration", inner);
+        }
+    }
     let ops = doc!{
-        "$setOnInsert": {
-            "user_id": &user_id,
-            "message": datachiffre_value,
-            "date_traitement": &estampille,
-            "lu": false,
-            CommonConstantes::CHAMP_CREATION: Utc::now(),
-        },
+        "$setOnInsert": set_on_insert,
         "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
     };
     let collection = middleware.get_collection(COLLECTION_RECEPTION_NOM)?;
@@ -121,7 +214,7 @@ async fn transaction_poster_v1<M>(_gestionnaire: &GestionnaireDomaineMessages, m
                     "format": fichier.format,
                     "verification": fichier.verification,
                     "taille_chiffre": fichier.taille_chiffre,
-                    CommonConstantes::CHAMP_CREATION: Utc::now()
+                    CommonConstantes::CHAMP_CREATION: &estampille
                 },
                 "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
             };
@@ -131,21 +224,52 @@ async fn transaction_poster_v1<M>(_gestionnaire: &GestionnaireDomaineMessages, m
         }
     }
 
+    ajouter_evenement(gestionnaire, middleware, evenement.evenement_id.clone(), constantes::EVENEMENT_NOUVEAU_MESSAGE,
+                      user_id.as_str(), &estampille, evenement).await?;
+
     Ok(None)
 }
 
-#[derive(Deserialize)]
+/// Filtre utilise pour une operation sur plusieurs messages. Conserve dans la transaction pour
+/// reference, les message_ids resolus sont appliques lors du traitement.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FiltreMessages {
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub bucket: Option<String>,
+    /// Messages traites avant cette date.
+    #[serde(default, skip_serializing_if="Option::is_none", with="optionepochseconds")]
+    pub date_avant: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct TransactionMarquerLu {
+    /// Usager proprietaire des messages. Absent des transactions signees par l'usager (certificat).
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub user_id: Option<String>,
     pub message_ids: Vec<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub filtre: Option<FiltreMessages>,
+}
+
+/// Retourne l'usager vise par la transaction. Le user_id du contenu est accepte uniquement
+/// lorsque la transaction est signee par le domaine (operations par filtre), sinon le user_id
+/// provient du certificat de l'usager.
+fn user_id_transaction(transaction: &TransactionValide, user_id_contenu: Option<String>) -> Result<Option<String>, Error> {
+    if let Some(user_id) = user_id_contenu {
+        if transaction.certificat.verifier_domaines(vec![DOMAINE_NOM.to_string()])? {
+            return Ok(Some(user_id))
+        }
+    }
+    transaction.certificat.get_user_id()
 }
 
-async fn transaction_marquer_lu<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, transaction: TransactionValide)
+async fn transaction_marquer_lu<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, transaction: TransactionValide)
                                   -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: MongoDao
 {
     let message_recu: TransactionMarquerLu = serde_json::from_str(transaction.transaction.contenu.as_str())?;
 
-    let user_id = match transaction.certificat.get_user_id()? {
+    let user_id = match user_id_transaction(&transaction, message_recu.user_id)? {
         Some(inner) => inner,
         None => Err(Error::Str("transaction_marquer_lu Certificat sans user_id"))?
     };
@@ -161,21 +285,31 @@ async fn transaction_marquer_lu<M>(_gestionnaire: &GestionnaireDomaineMessages,
     let collection = middleware.get_collection(COLLECTION_RECEPTION_NOM)?;
     collection.update_many(filtre, ops, None).await?;
 
+    let evenement_id = evenement_id(transaction.transaction.id.as_str(), constantes::EVENEMENT_MESSAGE_LU);
+    let evenement = EvenementMessages { evenement_id: evenement_id.clone(), message_ids, user_id: user_id.clone() };
+    ajouter_evenement(gestionnaire, middleware, evenement_id, constantes::EVENEMENT_MESSAGE_LU,
+                      user_id.as_str(), &transaction.transaction.estampille, evenement).await?;
+
     Ok(None)
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TransactionSupprimerMessage {
+    /// Usager proprietaire des messages. Absent des transactions signees par l'usager (certificat).
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub user_id: Option<String>,
     pub message_ids: Vec<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub filtre: Option<FiltreMessages>,
 }
 
-async fn transaction_supprimer_message<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, transaction: TransactionValide)
+async fn transaction_supprimer_message<M>(gestionnaire: &GestionnaireDomaineMessages, middleware: &M, transaction: TransactionValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
     where M: MongoDao
 {
     let message_recu: TransactionSupprimerMessage = serde_json::from_str(transaction.transaction.contenu.as_str())?;
 
-    let user_id = match transaction.certificat.get_user_id()? {
+    let user_id = match user_id_transaction(&transaction, message_recu.user_id)? {
         Some(inner) => inner,
         None => Err(Error::Str("transaction_supprimer_message Certificat sans user_id"))?
     };
@@ -187,9 +321,125 @@ async fn transaction_supprimer_message<M>(_gestionnaire: &GestionnaireDomaineMes
     let collection = middleware.get_collection(COLLECTION_RECEPTION_NOM)?;
     collection.delete_many(filtre.clone(), None).await?;
 
-    // Supprimer les fichiers associes au message
+    // Marquer les fichiers associes au message comme supprimes. Ils sont transmis avec le flag
+    // archive lors de la reclamation des fuuids.
+    let ops = doc! {
+        "$set": {"supprime": true},
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true},
+    };
     let collection_fichiers = middleware.get_collection(COLLECTION_FICHIERS_NOM)?;
-    collection_fichiers.delete_many(filtre.clone(), None).await?;
+    collection_fichiers.update_many(filtre.clone(), ops, None).await?;
+
+    let evenement_id = evenement_id(transaction.transaction.id.as_str(), constantes::EVENEMENT_MESSAGE_SUPPRIME);
+    let evenement = EvenementMessages { evenement_id: evenement_id.clone(), message_ids, user_id: user_id.clone() };
+    ajouter_evenement(gestionnaire, middleware, evenement_id, constantes::EVENEMENT_MESSAGE_SUPPRIME,
+                      user_id.as_str(), &transaction.transaction.estampille, evenement).await?;
+
+    Ok(None)
+}
+
+#[derive(Deserialize)]
+pub struct TransactionIndexerMessage {
+    pub message_id: String,
+    /// Tokens HMAC (cle connue des clients seulement) des mots, expediteur et sujet du message.
+    pub tokens: Vec<String>,
+}
+
+async fn transaction_indexer_message<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionIndexerMessage = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let user_id = match transaction.certificat.get_user_id()? {
+        Some(inner) => inner,
+        None => Err(Error::Str("transaction_indexer_message Certificat sans user_id"))?
+    };
+
+    // Retirer les doublons, l'ordre des tokens n'a pas d'importance
+    let mut tokens = message_recu.tokens;
+    tokens.sort();
+    tokens.dedup();
+    tokens.truncate(constantes::LIMITE_TOKENS_INDEX_MESSAGE);
+
+    let filtre = doc!{constantes::CHAMP_USER_ID: &user_id, constantes::CHAMP_MESSAGE_ID: &message_recu.message_id};
+    let ops = doc! {
+        "$set": {constantes::CHAMP_INDEX_RECHERCHE: tokens},
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true},
+    };
+    let collection = middleware.get_collection(COLLECTION_RECEPTION_NOM)?;
+    collection.update_one(filtre, ops, None).await?;
+
+    Ok(None)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionAssignerCleUsager {
+    pub user_id: String,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub nom_usager: Option<String>,
+    pub cle_id: String,
+}
+
+async fn transaction_assigner_cle_usager<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let transaction_cle: TransactionAssignerCleUsager = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+    let estampille = transaction.transaction.estampille;
+
+    // La date de la cle provient de la transaction, utilisee pour la rotation des cles
+    let filtre = doc! {constantes::CHAMP_USER_ID: &transaction_cle.user_id};
+    let mut set_ops = doc! {"cle_id": &transaction_cle.cle_id, "date_cle": &estampille};
+    if let Some(nom_usager) = transaction_cle.nom_usager.as_ref() {
+        set_ops.insert("nom_usager", nom_usager);
+    }
+    let ops = doc! {
+        "$set": set_ops,
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true},
+    };
+    let collection = middleware.get_collection(COLLECTION_USAGERS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, options).await?;
+
+    Ok(None)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionConfigurerTransfertCourriel {
+    /// Usager configure, utilise lorsque la transaction est signee par le domaine. Absent des
+    /// transactions signees par l'usager (certificat).
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub user_id: Option<String>,
+    /// Configuration du transfert, None retire le transfert.
+    pub transfert: Option<TransfertCourriel>,
+}
+
+async fn transaction_configurer_transfert_courriel<M>(_gestionnaire: &GestionnaireDomaineMessages, middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let transaction_transfert: TransactionConfigurerTransfertCourriel = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let user_id = match user_id_transaction(&transaction, transaction_transfert.user_id)? {
+        Some(inner) => inner,
+        None => Err(Error::Str("transaction_configurer_transfert_courriel Certificat sans user_id"))?
+    };
+
+    let filtre = doc! {constantes::CHAMP_USER_ID: &user_id};
+    let ops = match transaction_transfert.transfert {
+        Some(transfert) => doc! {
+            "$set": {"transfert_courriel": convertir_to_bson(transfert)?},
+            "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true},
+        },
+        None => doc! {
+            "$unset": {"transfert_courriel": true},
+            "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true},
+        }
+    };
+    let collection = middleware.get_collection(COLLECTION_USAGERS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, options).await?;
 
     Ok(None)
 }
@@ -0,0 +1,148 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+use crate::commandes::MessagePostV1;
+use crate::constantes;
+
+/// Limites appliquees aux messages recus avec posterV1 et diffuserMessage.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LimitesMessages {
+    /// Taille maximale du contenu HTML (bytes).
+    pub taille_max_contenu: usize,
+    /// Nombre maximal de fichiers attaches.
+    pub nombre_max_fichiers: usize,
+    /// Taille chiffree totale maximale des fichiers attaches (bytes).
+    pub taille_max_fichiers: i64,
+}
+
+impl Default for LimitesMessages {
+    fn default() -> Self {
+        Self {
+            taille_max_contenu: constantes::TAILLE_MAX_CONTENU_MESSAGE,
+            nombre_max_fichiers: constantes::NOMBRE_MAX_FICHIERS_MESSAGE,
+            taille_max_fichiers: constantes::TAILLE_MAX_FICHIERS_MESSAGE,
+        }
+    }
+}
+
+/// Verifie les limites du message et nettoie le contenu HTML. Retourne la raison du refus
+/// lorsque le message depasse une limite.
+pub fn valider_message_post(limites: &LimitesMessages, message: &mut MessagePostV1) -> Result<(), String> {
+    if message.contenu.len() > limites.taille_max_contenu {
+        Err(format!("Contenu de {} bytes, maximum {}", message.contenu.len(), limites.taille_max_contenu))?
+    }
+
+    if let Some(fichiers) = message.fichiers.as_ref() {
+        if fichiers.len() > limites.nombre_max_fichiers {
+            Err(format!("{} fichiers attaches, maximum {}", fichiers.len(), limites.nombre_max_fichiers))?
+        }
+        if let Some(fichier) = fichiers.iter().find(|f| f.taille_chiffre < 0) {
+            Err(format!("Taille chiffree invalide pour le fichier {}", fichier.fuuid))?
+        }
+        let taille_fichiers: i64 = fichiers.iter().map(|f| f.taille_chiffre).sum();
+        if taille_fichiers > limites.taille_max_fichiers {
+            Err(format!("Fichiers de {} bytes, maximum {}", taille_fichiers, limites.taille_max_fichiers))?
+        }
+    }
+
+    message.contenu = nettoyer_html(message.contenu.as_str());
+
+    Ok(())
+}
+
+/// Retire les elements et attributs non permis du contenu HTML. Les scripts, styles et
+/// ressources distantes (images, media) sont retires, seules les images data:image/ et les
+/// ressources cid: (fichiers attaches) sont conservees. Les liens data: sont retires.
+pub fn nettoyer_html(contenu: &str) -> String {
+    let balises_retirees: HashSet<&str> = ["script", "style", "iframe", "object", "embed"].into();
+    ammonia::Builder::default()
+        .clean_content_tags(balises_retirees)
+        .add_url_schemes(["data", "cid"])
+        .attribute_filter(|element, attribut, valeur| {
+            let valeur_minuscules = valeur.trim_start().to_ascii_lowercase();
+            if valeur_minuscules.starts_with("data:") {
+                return match (element, attribut) {
+                    ("img", "src") if valeur_minuscules.starts_with("data:image/") => Some(Cow::Borrowed(valeur)),
+                    _ => None
+                }
+            }
+            match attribut {
+                "src" if valeur_minuscules.starts_with("cid:") => Some(Cow::Borrowed(valeur)),
+                "src" => None,
+                _ => Some(Cow::Borrowed(valeur))
+            }
+        })
+        .clean(contenu)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use millegrilles_common_rust::serde_json::{self, json};
+
+    use super::*;
+
+    fn message_test(contenu: &str, tailles_fichiers: &[i64]) -> MessagePostV1 {
+        let fichiers: Vec<serde_json::Value> = tailles_fichiers.iter().enumerate().map(|(i, taille)| json!({
+            "fuuid": format!("fuuid{}", i), "nom": "fichier.txt", "mimetype": "text/plain",
+            "taille_chiffre": taille, "cle_id": "cle", "format": "mgs4",
+        })).collect();
+        serde_json::from_value(json!({
+            "contenu": contenu,
+            "destinataires": ["usager"],
+            "fichiers": fichiers,
+        })).unwrap()
+    }
+
+    #[test]
+    fn valider_message_post_limites() {
+        let limites = LimitesMessages { taille_max_contenu: 10, nombre_max_fichiers: 2, taille_max_fichiers: 100 };
+
+        assert!(valider_message_post(&limites, &mut message_test("<p>ok</p>", &[50, 50])).is_ok());
+        assert!(valider_message_post(&limites, &mut message_test("<p>trop long</p>", &[])).is_err());
+        assert!(valider_message_post(&limites, &mut message_test("", &[1, 1, 1])).is_err());
+        assert!(valider_message_post(&limites, &mut message_test("", &[60, 60])).is_err());
+    }
+
+    #[test]
+    fn valider_message_post_taille_negative() {
+        let limites = LimitesMessages::default();
+        // Une taille negative ne doit pas compenser la taille des autres fichiers
+        let mut message = message_test("", &[limites.taille_max_fichiers, -1]);
+        assert!(valider_message_post(&limites, &mut message).is_err());
+    }
+
+    #[test]
+    fn valider_message_post_nettoie_contenu() {
+        let mut message = message_test("<p>texte</p><script>alert(1)</script>", &[]);
+        valider_message_post(&LimitesMessages::default(), &mut message).unwrap();
+        assert_eq!("<p>texte</p>", message.contenu);
+    }
+
+    #[test]
+    fn nettoyer_html_images() {
+        let resultat = nettoyer_html(r#"<img src="data:image/png;base64,AAAA"><img src="cid:image1"><img src="https://exemple.com/a.png">"#);
+        assert!(resultat.contains(r#"src="data:image/png;base64,AAAA""#));
+        assert!(resultat.contains(r#"src="cid:image1""#));
+        assert!(!resultat.contains("exemple.com"));
+    }
+
+    #[test]
+    fn nettoyer_html_data_hors_img() {
+        let resultat = nettoyer_html(r#"<a href="data:text/html;base64,PHNjcmlwdD4=">lien</a>"#);
+        assert!(!resultat.contains("data:"));
+        let resultat = nettoyer_html(r#"<video src="data:video/mp4;base64,AAAA"></video>"#);
+        assert!(!resultat.contains("data:"));
+        let resultat = nettoyer_html(r#"<img src="data:text/html;base64,PHNjcmlwdD4=">"#);
+        assert!(!resultat.contains("data:"));
+    }
+
+    #[test]
+    fn nettoyer_html_liens() {
+        let resultat = nettoyer_html(r#"<a href="https://exemple.com">lien</a>"#);
+        assert!(resultat.contains(r#"href="https://exemple.com""#));
+    }
+}